@@ -1,30 +1,51 @@
 use axum::{
-    extract::State,
-    http::StatusCode,
-    response::IntoResponse,
-    routing::{get, post},
+    body::{Body, Bytes},
+    extract::{FromRequest, Path as UrlPath, Query, Request, State},
+    http::{header, Extensions, HeaderMap, HeaderName, HeaderValue, Method, StatusCode},
+    middleware::{self, Next},
+    response::{Html, IntoResponse, Response},
+    routing::{get, head, patch, post, put, MethodRouter},
     Json, Router,
 };
-use metrics::{counter, gauge, histogram};
+use futures_util::{
+    future::{BoxFuture, Shared},
+    stream::{self, FuturesUnordered},
+    FutureExt, StreamExt,
+};
+use http_body::Frame;
+use http_body_util::StreamBody;
+use metrics::{counter, gauge, histogram, Key, KeyName, Metadata, Recorder, SharedString, Unit};
 use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
 use rand::Rng;
-use serde::{Deserialize, Serialize};
+use regex::Regex;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::{
-    env,
+    any::Any,
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    env, fs,
+    io::{self, Write},
     net::SocketAddr,
+    path::{Path, PathBuf},
+    pin::Pin,
     sync::{
-        atomic::{AtomicI32, AtomicI64, Ordering},
+        atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering},
         Arc,
     },
+    task::{Context, Poll},
     time::{Duration, Instant},
 };
 use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpListener, TcpStream},
     signal,
-    sync::Semaphore,
+    sync::{Notify, OwnedSemaphorePermit, Semaphore, SemaphorePermit},
     time::sleep,
 };
-use tower_http::cors::{Any, CorsLayer};
+use tower_http::{
+    catch_panic::CatchPanicLayer,
+    cors::{self, CorsLayer},
+};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct Configuration {
@@ -32,12 +53,379 @@ struct Configuration {
     response_delay_ms: i32,
     failure_rate: f64,
     queue_size: i32,
+    #[serde(default)]
+    canary_fraction: f64,
+    #[serde(default)]
+    base_jitter_ms: i32,
+    #[serde(default)]
+    echo_config: bool,
+    #[serde(default)]
+    min_inter_response_ms: i32,
+    #[serde(default)]
+    shadow_enabled: bool,
+    #[serde(default)]
+    shadow_response_delay_ms: i32,
+    #[serde(default)]
+    shadow_failure_rate: f64,
+    #[serde(default)]
+    rate_limit_rps: f64,
+    #[serde(default)]
+    rate_limit_burst: i32,
+    #[serde(default)]
+    latency_budget_ms: i32,
+    #[serde(default)]
+    trickle_bytes_per_sec: i32,
+    #[serde(default)]
+    stale_timestamp_rate: f64,
+    #[serde(default)]
+    accept_id_pattern: String,
+    #[serde(default)]
+    admission_strategy: String,
+    #[serde(default)]
+    problem_json: bool,
+    #[serde(default)]
+    downstream_url: String,
+    #[serde(default)]
+    downstream_connect_delay_ms: i32,
+    #[serde(default)]
+    success_color: String,
+    #[serde(default)]
+    degraded_color: String,
+    #[serde(default)]
+    failure_color: String,
+    #[serde(default)]
+    outage_probability: f64,
+    #[serde(default)]
+    outage_duration_ms: i32,
+    #[serde(default)]
+    failure_scales_with_weight: bool,
+    #[serde(default)]
+    unfair_prefix: String,
+    #[serde(default)]
+    internal_retries: i32,
+    #[serde(default)]
+    broken_content_length_rate: f64,
+    #[serde(default)]
+    leak_rate_rps: f64,
+    #[serde(default)]
+    health_load_weight: f64,
+    #[serde(default)]
+    health_queue_weight: f64,
+    #[serde(default)]
+    health_score_mode: String,
+    #[serde(default)]
+    replica_lag_ms: i32,
+    #[serde(default)]
+    replica_lag_jitter_ms: i32,
+    #[serde(default)]
+    per_id_metrics: bool,
+    #[serde(default)]
+    failure_takes_precedence: bool,
+    #[serde(default)]
+    success_status_code: i32,
+    #[serde(default)]
+    accepted_location: bool,
+    #[serde(default)]
+    leak_bytes_per_request: i32,
+    #[serde(default)]
+    accept_timeout_ms: i32,
+    #[serde(default)]
+    dependency_timeout_ms: i32,
+    #[serde(default)]
+    truncate_response_rate: f64,
+    #[serde(default)]
+    diurnal_period_ms: i32,
+    #[serde(default)]
+    diurnal_amplitude: f64,
+    #[serde(default)]
+    include_queue_position: bool,
+    #[serde(default)]
+    single_flight: bool,
+    #[serde(default)]
+    latency_table: Vec<LatencyEntry>,
+    #[serde(default)]
+    task_steps: bool,
+    #[serde(default)]
+    processing_timeout_ms: i32,
+    #[serde(default)]
+    stream_trailers: bool,
+    #[serde(default)]
+    auto_id: bool,
+    #[serde(default)]
+    health_flap_rate: f64,
+    #[serde(default)]
+    trace_task_ids: Vec<String>,
+    #[serde(default)]
+    payload_size_bytes: i32,
+    #[serde(default)]
+    response_size_alignment: i32,
+    #[serde(default)]
+    config_read_delay_ms: i32,
+    #[serde(default)]
+    downstream_targets: Vec<DownstreamTarget>,
+    #[serde(default = "default_downstream_fanout")]
+    downstream_fanout: i32,
+    #[serde(default)]
+    fanout_quorum: i32,
+    #[serde(default)]
+    health_cache_ms: i32,
+    #[serde(default)]
+    schema_drift_rate: f64,
+    #[serde(default = "default_max_batch_size")]
+    max_batch_size: i32,
 }
 
-#[derive(Debug, Deserialize)]
+/// `latency_table` の 1 エントリ。`weight` の比率でこの遅延が選ばれる。
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct LatencyEntry {
+    delay_ms: i32,
+    weight: f64,
+}
+
+fn default_downstream_fanout() -> i32 {
+    1
+}
+
+/// `downstream_targets` の 1 エントリ。`weight` の比率でこの下流が呼び出し先に選ばれる。
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct DownstreamTarget {
+    url: String,
+    weight: f64,
+}
+
+fn default_max_batch_size() -> i32 {
+    1000
+}
+
+impl Configuration {
+    /// このリクエストで呼び出す下流の URL。`downstream_targets` があれば重みに従って選び、
+    /// なければ `downstream_url` を使う。どちらもなければ `None`。
+    fn pick_downstream_url(&self) -> Option<&str> {
+        let total: f64 = self.downstream_targets.iter().map(|t| t.weight).sum();
+        if total <= 0.0 {
+            return Some(self.downstream_url.as_str()).filter(|url| !url.is_empty());
+        }
+        let mut point = rand::thread_rng().gen_range(0.0..total);
+        for target in &self.downstream_targets {
+            if point < target.weight {
+                return Some(&target.url);
+            }
+            point -= target.weight;
+        }
+        self.downstream_targets.last().map(|t| t.url.as_str())
+    }
+
+    /// `latency_table` が空でなければ、重みに従って選んだエントリの遅延で `response_delay_ms` を置き換える。
+    fn apply_latency_table(&mut self) {
+        let total: f64 = self.latency_table.iter().map(|e| e.weight).sum();
+        if total <= 0.0 {
+            return;
+        }
+        let mut point = rand::thread_rng().gen_range(0.0..total);
+        for entry in &self.latency_table {
+            if point < entry.weight {
+                self.response_delay_ms = entry.delay_ms;
+                return;
+            }
+            point -= entry.weight;
+        }
+        if let Some(last) = self.latency_table.last() {
+            self.response_delay_ms = last.delay_ms;
+        }
+    }
+
+    /// トークンバケットの容量。`rate_limit_burst` が 0 なら `rate_limit_rps` を切り上げた値（最低 1）。
+    fn effective_rate_limit_burst(&self) -> u64 {
+        if self.rate_limit_burst > 0 {
+            self.rate_limit_burst as u64
+        } else {
+            self.rate_limit_rps.ceil().max(1.0) as u64
+        }
+    }
+
+    /// ヘルス状態の判定に使うスコア。同時実行とキューの使用率にそれぞれ
+    /// `health_load_weight`・`health_queue_weight` を掛け、`health_score_mode` が `sum` なら和を、
+    /// それ以外なら大きい方をとる。既定（重み 1.0、`max`）では両者を同等に扱う。
+    fn health_score(&self, load_ratio: f64, queue_ratio: f64) -> f64 {
+        let load = load_ratio * self.health_load_weight;
+        let queue = queue_ratio * self.health_queue_weight;
+        if self.health_score_mode == "sum" {
+            load + queue
+        } else {
+            load.max(queue)
+        }
+    }
+
+    /// 成功レスポンスの `replicaLagMs` に含める擬似的なレプリカ遅延。
+    ///
+    /// `replica_lag_ms` に `0..=replica_lag_jitter_ms` の一様乱数を加えたもの。どちらも 0 なら `None`。
+    fn sample_replica_lag(&self) -> Option<i64> {
+        if self.replica_lag_ms == 0 && self.replica_lag_jitter_ms == 0 {
+            return None;
+        }
+        let jitter = rand::thread_rng().gen_range(0..=self.replica_lag_jitter_ms);
+        Some(self.replica_lag_ms as i64 + jitter as i64)
+    }
+
+    /// 日周変動の波形 `wave`（-1.0..=1.0）に合わせて `response_delay_ms` と `failure_rate` を
+    /// `1 + diurnal_amplitude × wave` 倍にする（失敗率は 1.0 で頭打ち）。`diurnal_amplitude` が 0 なら何もしない。
+    fn apply_diurnal(&mut self, wave: f64) {
+        if self.diurnal_amplitude <= 0.0 {
+            return;
+        }
+        let factor = 1.0 + self.diurnal_amplitude * wave;
+        self.response_delay_ms = (self.response_delay_ms as f64 * factor).round() as i32;
+        self.failure_rate = (self.failure_rate * factor).clamp(0.0, 1.0);
+    }
+
+    /// 重み `weight` のタスクに適用する失敗確率。
+    ///
+    /// `failure_scales_with_weight` が有効なら `failure_rate × weight` を 1.0 で頭打ちにした値、
+    /// 無効なら重みに関わらず `failure_rate`。例えば `failure_rate` が 0.3 なら重み 4 以上のタスクは必ず失敗する。
+    fn effective_failure_rate(&self, weight: f64) -> f64 {
+        if self.failure_scales_with_weight {
+            (self.failure_rate * weight).min(1.0)
+        } else {
+            self.failure_rate
+        }
+    }
+}
+
+/// `/config` の更新リクエスト。指定されたフィールドのみが現在の設定へ反映される。
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct ConfigUpdate {
+    max_concurrent_requests: Option<i32>,
+    response_delay_ms: Option<i32>,
+    failure_rate: Option<f64>,
+    queue_size: Option<i32>,
+    canary_fraction: Option<f64>,
+    base_jitter_ms: Option<i32>,
+    echo_config: Option<bool>,
+    min_inter_response_ms: Option<i32>,
+    shadow_enabled: Option<bool>,
+    shadow_response_delay_ms: Option<i32>,
+    shadow_failure_rate: Option<f64>,
+    rate_limit_rps: Option<f64>,
+    rate_limit_burst: Option<i32>,
+    latency_budget_ms: Option<i32>,
+    trickle_bytes_per_sec: Option<i32>,
+    stale_timestamp_rate: Option<f64>,
+    accept_id_pattern: Option<String>,
+    admission_strategy: Option<String>,
+    problem_json: Option<bool>,
+    downstream_url: Option<String>,
+    downstream_connect_delay_ms: Option<i32>,
+    success_color: Option<String>,
+    degraded_color: Option<String>,
+    failure_color: Option<String>,
+    outage_probability: Option<f64>,
+    outage_duration_ms: Option<i32>,
+    failure_scales_with_weight: Option<bool>,
+    unfair_prefix: Option<String>,
+    internal_retries: Option<i32>,
+    broken_content_length_rate: Option<f64>,
+    leak_rate_rps: Option<f64>,
+    health_load_weight: Option<f64>,
+    health_queue_weight: Option<f64>,
+    health_score_mode: Option<String>,
+    replica_lag_ms: Option<i32>,
+    replica_lag_jitter_ms: Option<i32>,
+    per_id_metrics: Option<bool>,
+    failure_takes_precedence: Option<bool>,
+    success_status_code: Option<i32>,
+    accepted_location: Option<bool>,
+    leak_bytes_per_request: Option<i32>,
+    accept_timeout_ms: Option<i32>,
+    dependency_timeout_ms: Option<i32>,
+    truncate_response_rate: Option<f64>,
+    diurnal_period_ms: Option<i32>,
+    diurnal_amplitude: Option<f64>,
+    include_queue_position: Option<bool>,
+    single_flight: Option<bool>,
+    latency_table: Option<Vec<LatencyEntry>>,
+    task_steps: Option<bool>,
+    processing_timeout_ms: Option<i32>,
+    stream_trailers: Option<bool>,
+    auto_id: Option<bool>,
+    health_flap_rate: Option<f64>,
+    trace_task_ids: Option<Vec<String>>,
+    payload_size_bytes: Option<i32>,
+    response_size_alignment: Option<i32>,
+    config_read_delay_ms: Option<i32>,
+    downstream_targets: Option<Vec<DownstreamTarget>>,
+    downstream_fanout: Option<i32>,
+    fanout_quorum: Option<i32>,
+    health_cache_ms: Option<i32>,
+    schema_drift_rate: Option<f64>,
+    max_batch_size: Option<i32>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
 struct TaskRequest {
     id: String,
     weight: Option<f64>,
+    #[serde(default)]
+    profile: Option<String>,
+    /// デバッグ用。`DEBUG_ENDPOINTS` が有効な場合のみ、指定したメッセージで 500 を返させる。
+    #[serde(default)]
+    force_error: Option<String>,
+    /// 同じセッションでこの id のタスクが成功するまで処理を待つ。
+    #[serde(default)]
+    depends_on: Option<String>,
+    /// 通常の遅延と失敗判定の代わりに順に実行する手順（`TaskStep` の配列）。`task_steps` が有効な場合のみ解釈する。
+    #[serde(default)]
+    steps: Option<serde_json::Value>,
+}
+
+/// `TaskRequest.steps` の 1 手順。`{"sleep": 50}` のように種類をキーにして書く。
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase", deny_unknown_fields)]
+enum TaskStep {
+    /// 指定したミリ秒だけ待つ。
+    Sleep(u64),
+    /// 指定したミリ秒だけブロッキングスレッドで CPU を使い続ける。
+    Cpu(u64),
+    /// 指定した確率で失敗とする。一度失敗したタスクは以降の手順を実行しない。
+    Fail(f64),
+}
+
+impl TaskStep {
+    fn is_valid(&self) -> bool {
+        match self {
+            TaskStep::Fail(rate) => (0.0..=1.0).contains(rate),
+            TaskStep::Sleep(ms) | TaskStep::Cpu(ms) => *ms <= MAX_TASK_STEP_MS,
+        }
+    }
+}
+
+/// `TaskRequest.steps` を解釈して検証する。不正な場合は 400 で返すエラーメッセージを返す。
+fn parse_task_steps(raw: serde_json::Value) -> Result<Vec<TaskStep>, String> {
+    let steps: Vec<TaskStep> =
+        serde_json::from_value(raw).map_err(|err| format!("Invalid steps: {}", err))?;
+    if steps.len() > MAX_TASK_STEPS {
+        return Err(format!("Too many steps (max {})", MAX_TASK_STEPS));
+    }
+    match steps.iter().find(|step| !step.is_valid()) {
+        Some(invalid) => Err(format!("Invalid step: {:?}", invalid)),
+        None => Ok(steps),
+    }
+}
+
+/// `/task` のクエリパラメータ。
+///
+/// `force` と `delay` は curl での手動確認向けのデバッグ用で、`DEBUG_ENDPOINTS` が無効な場合は無視される。
+#[derive(Debug, Default, Deserialize)]
+struct TaskQuery {
+    echo_config: Option<bool>,
+    force: Option<ForcedOutcome>,
+    delay: Option<i32>,
+}
+
+/// `/metrics` のクエリパラメータ。
+#[derive(Debug, Default, Deserialize)]
+struct MetricsQuery {
+    /// 指定した場合、名前がこの文字列で始まるメトリクスだけを返す。
+    prefix: Option<String>,
 }
 
 #[derive(Debug, Serialize)]
@@ -48,12 +436,77 @@ struct TaskResponse {
     #[serde(rename = "processingTimeMs")]
     processing_time_ms: i64,
     timestamp: String,
+    version: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    config: Option<Configuration>,
+    /// 内部再試行を含めた処理の試行回数。`internal_retries` が設定されている場合のみ含める。
+    #[serde(skip_serializing_if = "Option::is_none")]
+    attempts: Option<i32>,
+    /// 読み取りレプリカの擬似的な遅延。`replica_lag_ms` か `replica_lag_jitter_ms` が設定されている場合のみ含める。
+    #[serde(rename = "replicaLagMs", skip_serializing_if = "Option::is_none")]
+    replica_lag_ms: Option<i64>,
+    /// 許可を要求した時点でキューにいた先行リクエストの数。`include_queue_position` が有効な場合のみ含める。
+    #[serde(rename = "queuePosition", skip_serializing_if = "Option::is_none")]
+    queue_position: Option<i32>,
 }
 
+/// `schema_drift_rate` で返す、互換性のない別バージョンのデプロイを模した `TaskResponse`。
+///
+/// フィールド名の変更（`id` → `taskId`、`worker` → `workerName`）、単位と型の変更（`processingTimeMs` の代わりに
+/// 秒単位の `processingTime`）、追加（`schemaVersion`）、欠落（`color`・`timestamp`）をまとめて含む。
 #[derive(Debug, Serialize)]
+struct DriftedTaskResponse {
+    #[serde(rename = "taskId")]
+    task_id: String,
+    #[serde(rename = "workerName")]
+    worker_name: String,
+    #[serde(rename = "processingTime")]
+    processing_time_secs: f64,
+    version: String,
+    #[serde(rename = "schemaVersion")]
+    schema_version: u32,
+}
+
+impl From<&TaskResponse> for DriftedTaskResponse {
+    fn from(response: &TaskResponse) -> Self {
+        Self {
+            task_id: response.id.clone(),
+            worker_name: response.worker.clone(),
+            processing_time_secs: response.processing_time_ms as f64 / 1000.0,
+            version: response.version.clone(),
+            schema_version: DRIFTED_SCHEMA_VERSION,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
 struct ErrorResponse {
     error: String,
     worker: String,
+    /// `failure_color` が設定されている場合のみ含める。
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    color: Option<String>,
+}
+
+/// RFC 7807 形式のエラー本文。`problem_json` が有効か、クライアントが要求した場合に `ErrorResponse` の代わりに返す。
+#[derive(Debug, Serialize)]
+struct ProblemDetails {
+    #[serde(rename = "type")]
+    problem_type: &'static str,
+    title: String,
+    status: u16,
+    detail: String,
+    worker: String,
+}
+
+/// `handle_health` が返す内容のうち、設定と負荷から求める部分。`health_cache_ms` が正ならこれをキャッシュする。
+#[derive(Debug, Clone, Copy)]
+struct HealthSnapshot {
+    status: &'static str,
+    current_load: i32,
+    queue_depth: i32,
+    /// キャッシュから返す場合も設定ロックを取らずに済むよう、求めた時点の `health_flap_rate` を持っておく。
+    flap_rate: f64,
 }
 
 #[derive(Debug, Serialize)]
@@ -63,494 +516,8963 @@ struct HealthResponse {
     current_load: i32,
     #[serde(rename = "queueDepth")]
     queue_depth: i32,
+    paused: bool,
 }
 
-struct AppState {
-    config: RwLock<Configuration>,
-    worker_name: String,
-    worker_color: String,
-    active_requests: AtomicI32,
-    queue_semaphore: Semaphore,
-    queue_size: AtomicI64,
-    prometheus_handle: PrometheusHandle,
+#[derive(Debug, Serialize)]
+struct IndexResponse {
+    worker: String,
+    color: String,
+    version: String,
+    endpoints: Vec<&'static str>,
 }
 
-/// 環境変数からi32値を取得し、存在しないか整数に変換できない場合はデフォルト値を返す。
-///
-/// 指定したキーの環境変数を読み取り、UTF-8文字列をi32として解析して返します。環境変数が未設定または解析に失敗した場合は `default` を返します。
-///
-/// # Examples
-///
-/// ```
-/// use std::env;
-/// // 値が設定されている場合はその値を返す
-/// env::set_var("TEST_INT", "42");
-/// assert_eq!(get_env_i32("TEST_INT", 10), 42);
-///
-/// // 不正な値の場合はデフォルトを返す
-/// env::set_var("TEST_INT", "not_an_int");
-/// assert_eq!(get_env_i32("TEST_INT", 10), 10);
-///
-/// // 未設定の場合はデフォルトを返す
-/// env::remove_var("TEST_INT");
-/// assert_eq!(get_env_i32("TEST_INT", 10), 10);
-/// ```
-fn get_env_i32(key: &str, default: i32) -> i32 {
-    env::var(key)
-        .ok()
-        .and_then(|v| v.parse().ok())
-        .unwrap_or(default)
+/// `POST /debug/force` で事前に指定できる次回以降のレスポンス結果。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ForcedOutcome {
+    Success,
+    Fail,
+    Overload,
 }
 
-/// 環境変数を読み取り、f64 に変換して返す。
-///
-/// 指定した `key` の環境変数を読み取り、`f64` にパースできればその値を返します。環境変数が未設定またはパースに失敗した場合は `default` を返します。
-///
-/// # Examples
-///
-/// ```
-/// use std::env;
-///
-/// // 環境変数が数値の場合はその値を返す
-/// env::set_var("TEST_F64", "3.14");
-/// assert_eq!(get_env_f64("TEST_F64", 1.0), 3.14);
-///
-/// // 未設定または無効な値の場合はデフォルトを返す
-/// env::remove_var("TEST_F64");
-/// assert_eq!(get_env_f64("TEST_F64", 2.5), 2.5);
-/// env::set_var("TEST_F64", "not_a_number");
-/// assert_eq!(get_env_f64("TEST_F64", 4.2), 4.2);
-/// ```
-fn get_env_f64(key: &str, default: f64) -> f64 {
-    env::var(key)
-        .ok()
-        .and_then(|v| v.parse().ok())
-        .unwrap_or(default)
+#[derive(Debug, Deserialize)]
+struct ForceRequest {
+    outcome: ForcedOutcome,
+    #[serde(default = "default_force_count")]
+    count: u32,
 }
 
-/// 環境変数からランタイム設定を読み取り、Configuration構造体を生成する。
-///
-/// 環境変数が存在しないか解析できない場合は既定値を使用する：
-/// - `MAX_CONCURRENT_REQUESTS` → 10
-/// - `RESPONSE_DELAY_MS` → 100
-/// - `FAILURE_RATE` → 0.0
-/// - `QUEUE_SIZE` → 50
-///
-/// # Examples
-///
-/// ```
-/// use std::env;
-/// // 環境変数が未設定の場合はデフォルトが使われる
-/// env::remove_var("MAX_CONCURRENT_REQUESTS");
-/// env::remove_var("RESPONSE_DELAY_MS");
-/// env::remove_var("FAILURE_RATE");
-/// env::remove_var("QUEUE_SIZE");
-///
-/// let cfg = load_config();
-/// assert_eq!(cfg.max_concurrent_requests, 10);
-/// assert_eq!(cfg.response_delay_ms, 100);
-/// assert_eq!(cfg.failure_rate, 0.0);
-/// assert_eq!(cfg.queue_size, 50);
-/// ```
-fn load_config() -> Configuration {
-    let max_concurrent = get_env_i32("MAX_CONCURRENT_REQUESTS", 10).max(1);
-    let response_delay = get_env_i32("RESPONSE_DELAY_MS", 100).max(0);
-    let failure_rate = get_env_f64("FAILURE_RATE", 0.0).clamp(0.0, 1.0);
-    let queue_size = get_env_i32("QUEUE_SIZE", 50).max(1);
-
-    Configuration {
-        max_concurrent_requests: max_concurrent,
-        response_delay_ms: response_delay,
-        failure_rate,
-        queue_size,
-    }
+fn default_force_count() -> u32 {
+    1
 }
 
-/// Prometheus メトリクスを初期化してカスタムヒストグラムバケットを設定し、レンダリング用のハンドルを返す。
-///
-/// この関数はサービスで使用するメトリクスレコーダーをインストールし、
-/// リクエスト処理時間を収集する `worker_request_duration_ms` メトリクスに対して
-/// カスタムバケットを設定してからハンドルを返します。
-///
-/// # Returns
-///
-/// `PrometheusHandle` — メトリクスをレンダリング・取得するためのハンドル。
-///
-/// # Examples
-///
-/// ```
-/// let handle = setup_metrics();
-/// let output = handle.render();
-/// assert!(output.contains("worker_request_duration_ms"));
-/// ```
-fn setup_metrics() -> PrometheusHandle {
-    PrometheusBuilder::new()
-        .set_buckets_for_metric(
-            Matcher::Full("worker_request_duration_ms".to_string()),
-            &[1.0, 2.0, 4.0, 8.0, 16.0, 32.0, 64.0, 128.0, 256.0, 512.0],
-        )
-        .unwrap()
-        .install_recorder()
-        .unwrap()
+#[derive(Debug, Serialize)]
+struct ForceResponse {
+    outcome: Option<ForcedOutcome>,
+    remaining: u32,
 }
 
-/// タスク要求を処理し、成功時は TaskResponse を、失敗時は ErrorResponse を返すハンドラ。
-///
-/// 必要に応じてキュー許可を取得して同時実行数を管理し、構成に基づく遅延をシミュレートし、
-/// プロセッシング時間やステータス（success/failed/rejected/overloaded）をプロメテウス用メトリクスに記録する。
-/// - キューが満杯の場合は 503 を返す（エラー "Queue full - service overloaded"）。
-/// - 同時実行上限を超えた場合は 503 を返す（エラーに現在数と上限を含む）。
-/// - 設定された failure_rate によっては 500 を返す（エラー "Simulated failure"）。
-/// - 成功時は TaskResponse を JSON で返す。
-///
-/// 注意: 関数は State と Json の抽出済みパラメータを受け取り、内部でアトミックカウンタとセマフォを更新する。
-///
-/// # Examples
-///
-/// ```
-/// use axum::Json;
-/// use axum::extract::State;
-/// use std::sync::Arc;
-///
-/// // テスト用に AppState を構築し、ルート経由で handle_task を呼び出すのが最も簡単な利用方法です。
-/// // ここでは概念例として、実際の構築手順は省略しています。
-///
-/// // let app_state = Arc::new(AppState::new_for_test());
-/// // let req = TaskRequest { id: "1".into(), weight: Some(1.0) };
-/// // let resp = handle_task(State(app_state), Json(req)).await;
-/// ```
-async fn handle_task(
-    State(state): State<Arc<AppState>>,
-    Json(task): Json<TaskRequest>,
-) -> impl IntoResponse {
-    let config = state.config.read().clone();
+/// ルートページに掲載するエンドポイント一覧。
+const ENDPOINTS: &[&str] = &[
+    "POST /task",
+    "HEAD /task",
+    "POST /task/batch",
+    "GET /task/{id}/progress",
+    "GET /health",
+    "GET /health/history",
+    "GET /events",
+    "GET /ready",
+    "GET /config",
+    "POST /config",
+    "PUT /config",
+    "PATCH /config",
+    "GET /config/history",
+    "GET /errors/recent",
+    "GET /metrics",
+    "POST /reset",
+    "GET /report",
+    "GET /selftest",
+    "POST /flush",
+    "POST /pause",
+    "POST /resume",
+];
+
+/// `DEBUG_ENDPOINTS` が有効なときのみ登録されるエンドポイント一覧。
+const DEBUG_ENDPOINT_ROUTES: &[&str] = &["POST /debug/force", "GET /debug/env"];
 
-    // Try to acquire queue slot
-    let permit = match state.queue_semaphore.try_acquire() {
-        Ok(p) => {
-            state.queue_size.fetch_add(1, Ordering::SeqCst);
-            p
+/// `success_status_code` に指定できるステータスコード。
+const SUCCESS_STATUS_CODES: &[i32] = &[200, 201, 202];
+
+/// `health_score_mode` に指定できる値。
+const HEALTH_SCORE_MODES: &[&str] = &["max", "sum"];
+
+/// `DISABLED_ENDPOINTS` をカンマ区切りのエンドポイント名（`GET /metrics` のようなメソッドとパス）として解釈する。
+///
+/// `ENDPOINTS`・`DEBUG_ENDPOINT_ROUTES`・`GET /` に含まれる名前を返し、それ以外の名前はエラーとして返す。
+fn parse_disabled_endpoints(raw: &str) -> (Vec<&'static str>, Vec<String>) {
+    let known = || {
+        std::iter::once("GET /")
+            .chain(ENDPOINTS.iter().copied())
+            .chain(DEBUG_ENDPOINT_ROUTES.iter().copied())
+    };
+    let mut disabled = Vec::new();
+    let mut unknown = Vec::new();
+    for name in raw
+        .split(',')
+        .map(|n| n.split_whitespace().collect::<Vec<_>>().join(" "))
+    {
+        if name.is_empty() {
+            continue;
         }
-        Err(_) => {
-            counter!("worker_requests_total", "worker" => state.worker_name.clone(), "status" => "rejected").increment(1);
-            return (
-                StatusCode::SERVICE_UNAVAILABLE,
-                Json(ErrorResponse {
-                    error: "Queue full - service overloaded".to_string(),
-                    worker: state.worker_name.clone(),
-                }),
-            )
-                .into_response();
+        match known().find(|endpoint| endpoint.eq_ignore_ascii_case(&name)) {
+            Some(endpoint) => disabled.push(endpoint),
+            None => unknown.push(name),
         }
-    };
+    }
+    (disabled, unknown)
+}
 
-    // Check concurrent request limit
-    let current = state.active_requests.fetch_add(1, Ordering::SeqCst) + 1;
-    gauge!("worker_current_load", "worker" => state.worker_name.clone()).set(current as f64);
+/// メモリ上に保持する設定履歴の最大件数。
+const CONFIG_HISTORY_LIMIT: usize = 50;
 
-    if current > config.max_concurrent_requests {
-        state.active_requests.fetch_sub(1, Ordering::SeqCst);
-        state.queue_size.fetch_sub(1, Ordering::SeqCst);
-        drop(permit);
-        counter!("worker_requests_total", "worker" => state.worker_name.clone(), "status" => "overloaded").increment(1);
-        return (
-            StatusCode::SERVICE_UNAVAILABLE,
-            Json(ErrorResponse {
-                error: format!(
-                    "Max concurrent requests exceeded ({}/{})",
-                    current, config.max_concurrent_requests
-                ),
-                worker: state.worker_name.clone(),
-            }),
-        )
-            .into_response();
-    }
+/// `stream_trailers` で送る、処理時間（ミリ秒）のトレーラー名。
+const PROCESSING_TIME_TRAILER: HeaderName = HeaderName::from_static("x-processing-time-ms");
 
-    let start = Instant::now();
+/// `stream_trailers` で送る、ステータスコードのトレーラー名。
+const STATUS_TRAILER: HeaderName = HeaderName::from_static("x-status");
 
-    // Simulate processing with delay
-    let weight = task.weight.unwrap_or(1.0).max(0.1);
-    let delay = Duration::from_millis((config.response_delay_ms as f64 * weight) as u64);
-    sleep(delay).await;
+/// `METRIC_PREFIX` の既定値。メトリクス名はこれに `_` と各メトリクスの名前を続けたものになる。
+const DEFAULT_METRIC_PREFIX: &str = "worker";
 
-    let processing_time = start.elapsed().as_millis() as i64;
-    histogram!("worker_request_duration_ms", "worker" => state.worker_name.clone()).record(processing_time as f64);
+/// `/metrics` の本文を送り出すチャンクの大きさ（バイト）。
+const METRICS_CHUNK_BYTES: usize = 64 * 1024;
 
-    // Cleanup
-    state.active_requests.fetch_sub(1, Ordering::SeqCst);
-    state.queue_size.fetch_sub(1, Ordering::SeqCst);
-    gauge!("worker_current_load", "worker" => state.worker_name.clone())
-        .set(state.active_requests.load(Ordering::SeqCst) as f64);
-    drop(permit);
+/// `per_id_metrics` で個別のラベルを付けるタスク id の上限。以降の id は `other` にまとめる。
+const PER_ID_METRICS_LIMIT: usize = 50;
 
-    // Simulate failure based on failure rate
-    let mut rng = rand::thread_rng();
-    if rng.gen::<f64>() < config.failure_rate {
-        counter!("worker_requests_total", "worker" => state.worker_name.clone(), "status" => "failed").increment(1);
-        return (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Json(ErrorResponse {
-                error: "Simulated failure".to_string(),
-                worker: state.worker_name.clone(),
-            }),
-        )
-            .into_response();
-    }
+/// `per_id_metrics` を有効にしたときの警告。
+const PER_ID_METRICS_WARNING: &str =
+    "per_id_metrics enabled; worker_request_duration_ms is labelled by task id (high cardinality)";
 
-    // Success response
-    counter!("worker_requests_total", "worker" => state.worker_name.clone(), "status" => "success").increment(1);
+/// 依存関係の解決のために覚えておく、完了したタスクの最大件数（全セッション合計）。
+const COMPLETED_TASKS_LIMIT: usize = 10_000;
 
-    let response = TaskResponse {
-        id: task.id,
-        worker: state.worker_name.clone(),
-        color: state.worker_color.clone(),
-        processing_time_ms: processing_time,
-        timestamp: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Nanos, true),
-    };
+/// `depends_on` のセッションを区別するリクエストヘッダー。指定がなければすべて同じセッションとみなす。
+const SESSION_HEADER: &str = "x-session-id";
+
+/// `TENANT_QUOTAS` でテナントを区別するリクエストヘッダー。
+const TENANT_HEADER: &str = "x-tenant";
+
+/// `HEAD /task` で返す、キューの空き枠の数。
+const QUEUE_AVAILABLE_HEADER: &str = "x-queue-available";
+
+/// `HEAD /task` で返す、同時実行の空き枠の数。
+const CONCURRENCY_AVAILABLE_HEADER: &str = "x-concurrency-available";
+
+/// `GET /errors/recent` のために保持するエラーレスポンスの最大件数。
+const RECENT_ERRORS_LIMIT: usize = 100;
+
+/// `GET /health/history` のために保持するヘルス状態の遷移の最大件数。
+const HEALTH_HISTORY_LIMIT: usize = 100;
+
+/// `health_cache_ms` が 0 の間、キャッシュを更新するタスクが設定を確認し直す間隔。
+const HEALTH_CACHE_IDLE_INTERVAL: Duration = Duration::from_secs(1);
 
-    Json(response).into_response()
+/// `RECORD_EVENTS` が有効な場合に `GET /events` のために保持するイベントの最大件数。
+const TASK_EVENTS_LIMIT: usize = 10_000;
+
+tokio::task_local! {
+    /// 処理中の `/task` の id。`error_response` が直近のエラーに id を記録するために使う。
+    static CURRENT_TASK_ID: String;
 }
 
-/// ヘルスチェックを作成し、現在の負荷とキュー深度に基づいてサービスの状態を返すハンドラ。
-///
-/// 現在の同時処理数とキュー深度を取得し、構成の最大値に対する比率から状態を決定する：
-/// - 比率が 0.9 以上なら `unhealthy`
-/// - 比率が 0.7 以上なら `degraded`
-/// - それ以外は `healthy`
-///
-/// 返却される JSON ペイロードは `HealthResponse` で、状態文字列、現在の負荷（in-flight リクエスト数）、キュー深度を含む。
-///
-/// # Examples
-///
-/// ```no_run
-/// use std::sync::Arc;
-/// use axum::extract::State;
-///
-/// #[tokio::main]
-/// async fn main() {
-///     // `state` は実際のアプリケーションの共有状態を指す Arc<AppState> とする
-///     let state: Arc<AppState> = /* 既存の AppState を構築 */ unimplemented!();
-///     let response = handle_health(State(state)).await;
-///     // `response` は `HealthResponse` を含む JSON レスポンスになる
-/// }
-/// ```
-async fn handle_health(State(state): State<Arc<AppState>>) -> impl IntoResponse {
-    let config = state.config.read();
-    let load = state.active_requests.load(Ordering::SeqCst);
-    let queue_depth = state.queue_size.load(Ordering::SeqCst) as i32;
+/// `queue_size` の上限。誤設定でセマフォが巨大化したり 32bit 環境で許可数が溢れたりするのを防ぐ。
+const MAX_QUEUE_SIZE: i32 = 100_000;
 
-    let load_ratio = load as f64 / config.max_concurrent_requests as f64;
-    let queue_ratio = queue_depth as f64 / config.queue_size as f64;
+/// 設定変更 Webhook への通知のタイムアウト。
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(2);
 
-    let status = if load_ratio >= 0.9 || queue_ratio >= 0.9 {
-        "unhealthy"
-    } else if load_ratio >= 0.7 || queue_ratio >= 0.7 {
-        "degraded"
-    } else {
-        "healthy"
-    };
+/// `broken_content_length_rate` で宣言する `Content-Length` の、実際の本文に対する超過分。
+const MISFRAMED_EXTRA_BYTES: usize = 16;
 
-    Json(HealthResponse {
-        status: status.to_string(),
-        current_load: load,
-        queue_depth,
-    })
+/// 不正な `Content-Length` のレスポンスで、本文を送ってから接続を切るまでの猶予。
+const MISFRAMED_FLUSH_DELAY: Duration = Duration::from_millis(50);
+
+/// `MAX_PARSE_MS` の検査で読み込む本文の上限。axum の `Json` 抽出器の既定の上限と同じ。
+const PARSE_GUARD_BODY_LIMIT: usize = 2 * 1024 * 1024;
+
+/// `MAX_PARSE_MS` の検査で、解析中に時刻を確かめる間隔（読み込みの回数）。
+const DEADLINE_CHECK_INTERVAL: u32 = 4096;
+
+/// `/report` が集計する期間。
+const REPORT_WINDOW: Duration = Duration::from_secs(60);
+
+/// `/report` 用に保持する結果の最大件数。
+const REPORT_SAMPLE_LIMIT: usize = 100_000;
+
+/// 擬似障害の発生を抽選する間隔。`outage_probability` はこの間隔ごとの確率。
+const OUTAGE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// リーキーバケットの受付間隔の上限。極端に小さい `leak_rate_rps` でも `Duration` が溢れないようにする。
+const MAX_LEAK_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// `TaskRequest.steps` に書ける手順の数の上限。
+const MAX_TASK_STEPS: usize = 64;
+
+/// `TaskStep` の `sleep`・`cpu` 1 手順に指定できるミリ秒の上限。
+const MAX_TASK_STEP_MS: u64 = 60_000;
+
+/// 下流呼び出し 1 回あたりのタイムアウト。
+const DOWNSTREAM_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// `worker_permit_utilization` ゲージを更新する間隔。
+const PERMIT_UTILIZATION_INTERVAL: Duration = Duration::from_secs(5);
+
+/// `DriftedTaskResponse` が名乗るスキーマのバージョン。
+const DRIFTED_SCHEMA_VERSION: u32 = 2;
+
+/// `downstream_fanout` に指定できる上限。
+const MAX_DOWNSTREAM_FANOUT: i32 = 64;
+
+/// `payload_size_bytes` と `response_size_alignment` に指定できる上限。
+const MAX_RESPONSE_SIZE_BYTES: i32 = 16 * 1024 * 1024;
+
+/// `pad_response_body` が本文の末尾に足す空の `padding` フィールドの長さ。
+const PADDING_FIELD_BYTES: usize = br#","padding":"""#.len();
+
+/// `leak_bytes_per_request` で保持し続けるメモリの上限。ホストを実際に OOM にしないためのもの。
+const LEAK_CAP_BYTES: u64 = 256 * 1024 * 1024;
+
+/// 日周変動の波形を更新する間隔。
+const DIURNAL_UPDATE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// `STATE_FILE` に累積のカウンタを書き出す間隔。
+const STATE_CHECKPOINT_INTERVAL: Duration = Duration::from_secs(15);
+
+/// `PUSHGATEWAY_URL` へメトリクスを送る間隔。
+const PUSHGATEWAY_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Pushgateway への 1 回の送信のタイムアウト。
+const PUSHGATEWAY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// 再起動をまたいで引き継ぐ状態。`STATE_FILE` に JSON で保存する。
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct PersistedState {
+    /// `/task` のステータスコードごとの累積の応答数。
+    #[serde(default)]
+    task_responses: BTreeMap<u16, u64>,
 }
 
-/// 設定（Configuration）の現在値をJSONで返すエンドポイントハンドラ。
-///
-/// レスポンスとして現在の `Configuration` クローンをJSON形式で返します。
-async fn handle_config_get(State(state): State<Arc<AppState>>) -> impl IntoResponse {
-    let config = state.config.read().clone();
-    Json(config)
+impl PersistedState {
+    /// `path` から読み込む。ファイルがない場合や内容が不正な場合は空の状態から始める。
+    fn load(path: &Path) -> Self {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Self::default(),
+            Err(err) => {
+                tracing::warn!("Failed to read state file {}: {}", path.display(), err);
+                return Self::default();
+            }
+        };
+        serde_json::from_str(&contents).unwrap_or_else(|err| {
+            tracing::warn!("Ignoring unreadable state file {}: {}", path.display(), err);
+            Self::default()
+        })
+    }
+
+    /// 一時ファイルに書いてから置き換えることで、途中で落ちても壊れたファイルを残さない。
+    fn save(&self, path: &Path) -> io::Result<()> {
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec(self).unwrap_or_default())?;
+        fs::rename(tmp, path)
+    }
 }
 
-/// 設定値を受け取り、妥当なフィールドのみアプリケーションのランタイム設定に反映して更新済みの設定を返すハンドラー。
-///
-/// 与えられた `Configuration` の各フィールドは次の条件を満たす場合にのみ現在の設定へ適用される:
-/// - `max_concurrent_requests > 0`
-/// - `response_delay_ms >= 0`
-/// - `0.0 <= failure_rate <= 1.0`
-/// - `queue_size > 0`
-///
-/// 更新後の設定はログに記録され、クライアントへ JSON として返される。
-///
-/// # Returns
-///
-/// 更新後の `Configuration` を含む JSON レスポンス。
+/// `GET /task/{id}/progress` が送る進捗の段階数。0% から 100% まで `PROGRESS_STEPS + 1` 行になる。
+const PROGRESS_STEPS: u32 = 10;
+
+/// `max_batch_size` に指定できる上限。
+const MAX_BATCH_SIZE_LIMIT: i32 = 1_000_000;
+
+/// `POST /task/batch` の配列の要素 1 つに許す大きさ。デコーダが保持するのはこの大きさまでの要素 1 つ分だけ。
+const MAX_BATCH_ELEMENT_BYTES: usize = 64 * 1024;
+
+/// レート制限用のトークンバケット。
 ///
-/// # Examples
+/// 取り出し時に経過時間に応じてトークンを補充する。レートと容量は呼び出しごとに渡すため、
+/// `/config` で変更された値が即座に反映される。
+struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new() -> Self {
+        Self {
+            tokens: f64::INFINITY,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// トークンを 1 つ取り出す。成功時は残りトークン数を、いずれの場合も
+    /// バケットが満杯に戻るまでの時間を返す。
+    fn take(&mut self, rate: f64, burst: f64, now: Instant) -> (Option<f64>, Duration) {
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(burst);
+        self.last_refill = now;
+        let remaining = if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Some(self.tokens)
+        } else {
+            None
+        };
+        let reset = Duration::from_secs_f64((burst - self.tokens) / rate);
+        (remaining, reset)
+    }
+}
+
+/// トークンバケットによる受付判定の結果。
+enum RateLimitDecision {
+    Unlimited,
+    Allowed,
+    Limited { limit: u64, reset: Duration },
+}
+
+/// 設定履歴の 1 件分。適用された時刻とその時点の設定を持つ。
+#[derive(Debug, Clone, Serialize)]
+struct ConfigHistoryEntry {
+    timestamp: String,
+    config: Configuration,
+}
+
+/// `RECORD_EVENTS` が有効な場合に記録する、`/task` 1 件の受け付け・拒否・完了のいずれか。
+#[derive(Debug, Clone, Serialize)]
+struct TaskEvent {
+    /// 記録した順に 1 から振る連番。同じ時刻のイベントも区別できる。
+    seq: u64,
+    /// ナノ秒までの RFC 3339（UTC）。桁数が固定なので文字列の比較で前後を判定できる。
+    timestamp: String,
+    /// `admitted`・`rejected`・`completed` のいずれか。`completed` は拒否を含めてすべての応答で記録する。
+    event: &'static str,
+    id: Option<String>,
+    /// `rejected` の理由（`TaskRejected` の値）。
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<&'static str>,
+    /// `completed` のステータスコード。
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<u16>,
+}
+
+/// `GET /events` のクエリパラメータ。
+#[derive(Debug, Default, Deserialize)]
+struct EventsQuery {
+    /// 指定した場合、この時刻（RFC 3339）より後のイベントだけを返す。
+    since: Option<String>,
+    /// 指定した場合、`seq` がこの値より大きいイベントだけを返す。
+    since_seq: Option<u64>,
+}
+
+/// `/task` を受け付けずに断ったレスポンスに付ける拡張。値は `worker_requests_total` の `status` と同じ理由。
 ///
-/// ```ignore
-/// // ハンドラーの使い方（概念例）
-/// // 実際の呼び出しは Axum のルーティング経由で行われる。
-/// let new_cfg = Configuration {
-///     max_concurrent_requests: 5,
-///     response_delay_ms: 100,
-///     failure_rate: 0.1,
-///     queue_size: 50,
-/// };
-/// // POST /config に new_cfg を送ると、更新後の設定が JSON で返る
-/// ```
-async fn handle_config_update(
-    State(state): State<Arc<AppState>>,
-    Json(new_config): Json<Configuration>,
-) -> impl IntoResponse {
-    let mut config = state.config.write();
-    if new_config.max_concurrent_requests > 0 {
-        config.max_concurrent_requests = new_config.max_concurrent_requests;
+/// `handle_task` はこれを見て `rejected` イベントを記録する。
+#[derive(Debug, Clone, Copy)]
+struct TaskRejected(&'static str);
+
+/// `handle_health` が前回と異なる状態を返した時点の記録。
+#[derive(Debug, Clone, Serialize)]
+struct HealthTransition {
+    timestamp: String,
+    status: &'static str,
+}
+
+/// 直近に返したエラーレスポンスの 1 件分。
+#[derive(Debug, Clone, Serialize)]
+struct RecentError {
+    timestamp: String,
+    /// `/task` のエラーの場合のみ、そのタスクの id。
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
+    status: u16,
+    error: String,
+}
+
+/// `single_flight` で同じ id のリクエスト間で共有する、本文まで読み終えたレスポンス。トレーラーも保持する。
+#[derive(Clone)]
+struct BufferedResponse {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+    trailers: Option<HeaderMap>,
+    extensions: Extensions,
+}
+
+impl BufferedResponse {
+    async fn buffer(response: Response) -> Option<Self> {
+        use http_body_util::BodyExt;
+
+        let (parts, body) = response.into_parts();
+        let collected = body.collect().await.ok()?;
+        Some(Self {
+            status: parts.status,
+            headers: parts.headers,
+            extensions: parts.extensions,
+            trailers: collected.trailers().cloned(),
+            body: collected.to_bytes(),
+        })
+    }
+}
+
+impl IntoResponse for BufferedResponse {
+    fn into_response(self) -> Response {
+        let body = match self.trailers {
+            Some(trailers) => {
+                let frames = [Frame::data(self.body), Frame::trailers(trailers)];
+                Body::new(StreamBody::new(stream::iter(
+                    frames.map(Ok::<_, io::Error>),
+                )))
+            }
+            None => Body::from(self.body),
+        };
+        let mut response = Response::new(body);
+        *response.status_mut() = self.status;
+        *response.headers_mut() = self.headers;
+        *response.extensions_mut() = self.extensions;
+        response
     }
-    if new_config.response_delay_ms >= 0 {
-        config.response_delay_ms = new_config.response_delay_ms;
+}
+
+/// `AppState::enter_leak_buffer` で数えたリーキーバケットの待機 1 件。
+///
+/// 受付時刻より前に捨てられた（タイムアウトやキャンセルで待つのをやめた）場合、それが最後の予約なら
+/// `last_leak_slot` を予約前に戻し、来なかった分だけ後続のリクエストを遅らせないようにする。
+struct LeakBufferEntry<'a> {
+    state: &'a AppState,
+    /// この待機に予約した受付時刻。
+    slot: Instant,
+    /// 予約したときの受付間隔。
+    gap: Duration,
+}
+
+impl Drop for LeakBufferEntry<'_> {
+    fn drop(&mut self) {
+        if Instant::now() < self.slot {
+            let mut last = self.state.last_leak_slot.lock();
+            // Only the latest reservation can be given back; later ones are already spaced after it
+            if *last == Some(self.slot) {
+                *last = self.slot.checked_sub(self.gap);
+            }
+        }
+        self.state.leak_buffered.fetch_sub(1, Ordering::SeqCst);
+        self.state.publish_leak_depth();
     }
-    if new_config.failure_rate >= 0.0 && new_config.failure_rate <= 1.0 {
-        config.failure_rate = new_config.failure_rate;
+}
+
+/// 処理中のタスクの結果。同じ id で後から来たリクエストはこれを待つ。
+type InFlightTask = Shared<BoxFuture<'static, Option<BufferedResponse>>>;
+
+/// 先頭のリクエストが持つ `in_flight_tasks` の登録。処理が終わっても panic しても、捨てた時点で登録を消す。
+struct InFlightEntry {
+    state: Arc<AppState>,
+    id: String,
+}
+
+impl Drop for InFlightEntry {
+    fn drop(&mut self) {
+        self.state.in_flight_tasks.lock().remove(&self.id);
     }
-    // Handle queue_size change with semaphore adjustment
-    if new_config.queue_size > 0 && new_config.queue_size != config.queue_size {
-        let delta = new_config.queue_size - config.queue_size;
-        if delta > 0 {
-            // Increase capacity by adding permits
-            state.queue_semaphore.add_permits(delta as usize);
+}
+
+/// セッションごとの完了済みタスク id。古いものから `COMPLETED_TASKS_LIMIT` 件を超えた分を忘れる。
+#[derive(Default)]
+struct CompletedTasks {
+    order: VecDeque<(String, String)>,
+    ids: HashSet<(String, String)>,
+}
+
+impl CompletedTasks {
+    fn insert(&mut self, session: &str, id: &str) {
+        let key = (session.to_string(), id.to_string());
+        if !self.ids.insert(key.clone()) {
+            return;
         }
-        // Note: Decreasing semaphore permits atomically is complex in Tokio;
-        // for simplicity, we only support increasing. Decreasing requires
-        // acquiring permits which may block. Log a warning if decrease attempted.
-        if delta < 0 {
-            tracing::warn!(
-                "Cannot decrease queue_size from {} to {} at runtime; only increases are supported",
-                config.queue_size, new_config.queue_size
-            );
+        self.order.push_back(key);
+        if self.order.len() > COMPLETED_TASKS_LIMIT {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+    }
+
+    fn contains(&self, session: &str, id: &str) -> bool {
+        self.ids.contains(&(session.to_string(), id.to_string()))
+    }
+}
+
+/// `STICKY_SESSIONS` が有効な場合のアフィニティ Cookie の設定。
+struct StickySessions {
+    cookie_name: String,
+    ttl_secs: u64,
+}
+
+/// 名前付きタスクプロファイル。プロファイルごとに独立した同時実行上限を持つ。
+struct TaskProfile {
+    max_concurrent: usize,
+    semaphore: Semaphore,
+}
+
+impl TaskProfile {
+    fn in_flight(&self) -> usize {
+        self.max_concurrent - self.semaphore.available_permits()
+    }
+}
+
+/// `HARDWARE_CLASSES` で定義するハードウェアクラス。リクエストごとに重みに従って選ばれる。
+struct HardwareClass {
+    color: String,
+    weight: f64,
+    latency_multiplier: f64,
+}
+
+/// `TENANT_QUOTAS` によるテナントごとの同時実行上限。`X-Tenant` ヘッダーでテナントを区別する。
+struct TenantQuotas {
+    /// `TENANT_QUOTAS` に載っていないテナント全体（`other`）の上限。0 なら無制限。
+    default_quota: usize,
+    quotas: HashMap<String, usize>,
+    /// 枠ごとのセマフォ。`TENANT_QUOTAS` のテナントと `other` の分だけ、初めて使う時点で作る。
+    semaphores: Mutex<HashMap<String, Arc<Semaphore>>>,
+}
+
+impl TenantQuotas {
+    /// `tenant` が属する枠の名前。`TENANT_QUOTAS` にないテナントはヘッダーの値に関係なく `other` にまとめる。
+    fn bucket<'a>(&self, tenant: &'a str) -> &'a str {
+        if self.quotas.contains_key(tenant) {
+            tenant
         } else {
-            config.queue_size = new_config.queue_size;
+            "other"
         }
     }
-    tracing::info!("Config updated: {:?}", *config);
-    Json(config.clone())
+
+    fn quota(&self, bucket: &str) -> usize {
+        self.quotas
+            .get(bucket)
+            .copied()
+            .unwrap_or(self.default_quota)
+    }
+
+    /// `bucket` のセマフォ。上限がなければ `None`。
+    fn semaphore(&self, bucket: &str) -> Option<Arc<Semaphore>> {
+        let quota = self.quota(bucket);
+        (quota > 0).then(|| {
+            Arc::clone(
+                self.semaphores
+                    .lock()
+                    .entry(bucket.to_string())
+                    .or_insert_with(|| Arc::new(Semaphore::new(quota))),
+            )
+        })
+    }
 }
 
-/// Prometheus のメトリクスをレンダリングして HTTP レスポンスの本文を生成するハンドラ。
-///
-/// 返り値は Prometheus ハンドラがレンダリングしたメトリクス本文（テキスト）で、HTTP のレスポンス本文として返却されます。
-///
-/// # Examples
-///
-/// ```no_run
-/// use std::sync::Arc;
-/// use axum::extract::State;
-///
-/// // `state` はサーバの共有状態で、内部に `prometheus_handle` を保持している想定です。
-/// # async fn example(state: Arc<AppState>) {
-/// let resp = handle_metrics(State(state)).await;
-/// // `resp` はレンダリング済みメトリクスを含む HTTP レスポンスとなります。
-/// # }
-/// ```
-async fn handle_metrics(State(state): State<Arc<AppState>>) -> impl IntoResponse {
-    state.prometheus_handle.render()
+/// `TENANT_QUOTAS` のテナントごとの上限を保持している間の許可。捨てると枠を返して `worker_tenant_in_flight` を更新する。
+struct TenantSlot {
+    worker_name: String,
+    tenant: String,
+    quota: usize,
+    semaphore: Arc<Semaphore>,
+    permit: Option<OwnedSemaphorePermit>,
 }
 
-/// Ctrl+C またはプロセス終了シグナルを待機し、受信したらシャットダウンをログに記録する。
-///
-/// UNIX プラットフォームでは terminate シグナルも監視する。
-///
-/// # Examples
-///
-/// ```
-/// #[tokio::main]
-/// async fn main() {
-///     // Ctrl+C またはプロセス終了シグナルを待ち、受信時にシャットダウンを記録する。
-///     shutdown_signal().await;
-/// }
-/// ```
-async fn shutdown_signal() {
-    let ctrl_c = async {
-        signal::ctrl_c()
-            .await
-            .expect("failed to install Ctrl+C handler");
-    };
+impl TenantSlot {
+    fn publish_in_flight(&self) {
+        gauge!("worker_tenant_in_flight", "worker" => self.worker_name.clone(), "tenant" => self.tenant.clone())
+            .set((self.quota - self.semaphore.available_permits()) as f64);
+    }
+}
 
-    #[cfg(unix)]
-    let terminate = async {
-        signal::unix::signal(signal::unix::SignalKind::terminate())
-            .expect("failed to install signal handler")
-            .recv()
-            .await;
+impl Drop for TenantSlot {
+    fn drop(&mut self) {
+        drop(self.permit.take());
+        self.publish_in_flight();
+    }
+}
+
+/// 直前に予約した時刻から `gap` 以上後（ただし現在時刻より前にはしない）の時刻を予約して返す。
+fn reserve_slot(last: &Mutex<Option<Instant>>, gap: Duration) -> Instant {
+    let now = Instant::now();
+    let mut last = last.lock();
+    let slot = match *last {
+        Some(prev) => (prev + gap).max(now),
+        None => now,
     };
+    *last = Some(slot);
+    slot
+}
 
-    #[cfg(not(unix))]
-    let terminate = std::future::pending::<()>();
+/// `/task` 1 件分の結果。`/report` の集計に使う。
+struct RequestSample {
+    at: Instant,
+    status: StatusCode,
+    elapsed_ms: f64,
+}
+
+/// `GET /selftest` のレスポンス。
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SelfTestReport {
+    ok: bool,
+    /// 内部で処理したタスクのステータスコード。
+    status: u16,
+    latency_ms: f64,
+    /// 失敗した場合のみ、`ErrorResponse` の `error`。
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// `GET /selftest` が内部で処理するタスクの id。
+const SELFTEST_TASK_ID: &str = "selftest";
+
+/// `GET /report` のレスポンス。直近 `REPORT_WINDOW` の `/task` の結果から求めた容量計画用の要約。
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct CapacityReport {
+    /// 集計対象の期間（秒）。起動直後や `/reset` 直後は `REPORT_WINDOW` より短い。
+    window_secs: f64,
+    requests: usize,
+    /// 期間全体の平均スループット。
+    throughput_rps: f64,
+    /// 期間内で最も多かった 1 秒間のリクエスト数。
+    max_throughput_rps: f64,
+    current_load: i32,
+    max_concurrent_requests: i32,
+    /// `worker_permit_utilization` と同じ許可の利用率。
+    permit_utilization: f64,
+    /// 429 と 503 の割合。
+    rejection_rate: f64,
+    /// 成功したリクエストのレイテンシ。成功が 1 件もなければ `null`。
+    latency_p50_ms: Option<f64>,
+    latency_p99_ms: Option<f64>,
+    /// 成功したリクエストのレイテンシ分布。
+    success_latency: LatencyPercentiles,
+    /// 失敗したリクエスト（429・503 の拒否を除く 2xx 以外）のレイテンシ分布。速く失敗しているか遅く失敗しているかを示す。
+    failure_latency: LatencyPercentiles,
+}
+
+/// `CapacityReport` に含めるレイテンシのパーセンタイル。該当するリクエストがなければ各値は `null`。
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct LatencyPercentiles {
+    p50_ms: Option<f64>,
+    p90_ms: Option<f64>,
+    p99_ms: Option<f64>,
+}
+
+impl LatencyPercentiles {
+    fn from_unsorted(mut latencies: Vec<f64>) -> Self {
+        latencies.sort_by(f64::total_cmp);
+        Self {
+            p50_ms: percentile(&latencies, 50.0),
+            p90_ms: percentile(&latencies, 90.0),
+            p99_ms: percentile(&latencies, 99.0),
+        }
+    }
+}
 
-    tokio::select! {
-        _ = ctrl_c => {},
-        _ = terminate => {},
+/// 昇順に並んだ `sorted` の `p` パーセンタイル（最近傍順位法）。
+fn percentile(sorted: &[f64], p: f64) -> Option<f64> {
+    if sorted.is_empty() {
+        return None;
     }
+    let rank = ((p / 100.0) * sorted.len() as f64).ceil() as usize;
+    Some(sorted[rank.clamp(1, sorted.len()) - 1])
+}
 
-    tracing::info!("Shutdown signal received");
+/// 受付に成功したリクエストが処理の間保持する許可。ドロップすると解放される。
+struct Admission<'a> {
+    queue_permit: SemaphorePermit<'a>,
+    concurrency_permit: SemaphorePermit<'a>,
+    /// `profile` で指定されたタスクプロファイルの名前・定義と、その許可。
+    profile: Option<(&'a String, &'a TaskProfile, SemaphorePermit<'a>)>,
 }
 
-/// アプリケーションのHTTPサーバーを初期化し、ルーティング・メトリクス・共有状態を構成して起動する。
+/// 受付を拒否した理由。`AppState::rejection_response` でメトリクスとレスポンスに変換される。
+#[derive(Debug)]
+enum Rejection {
+    /// トークンバケットが空。`limit` はバケット容量、`reset` は満杯に戻るまでの時間。
+    RateLimited {
+        limit: u64,
+        reset: Duration,
+    },
+    QueueFull,
+    /// 受付前の待機（一時停止・リーキーバケット）が `accept_timeout_ms` を超えた。
+    AcceptTimeout,
+    /// 同時実行上限を超えた。エラーメッセージをそのまま返す。
+    Overloaded(String),
+}
+
+/// リクエストを受け付けるかどうか（レート制限・キュー・同時実行数）を決める受付制御。
 ///
-/// 初期設定を環境変数から読み込み、Prometheus メトリクスをセットアップし、セマフォやアトミックカウンタを含む共有 AppState を作成します。CORS を有効にした Axum ルーターを構築し、/task、/health、/config、/metrics のエンドポイントを登録した後、指定ポートでリッスンしてグレースフルシャットダウンを待機します。
+/// `admission_strategy` で選ばれた実装が `process_task` から呼ばれる。実装は必要な許可を
+/// すべて取得して `Admission` として返すか、途中で取得した許可を解放した上で `Rejection` を返す。
+/// 一時停止中やリーキーバケットで待っていたリクエストは、待つ間に確保したキューの許可を `queued` として渡す。
+/// 実装はそれを新たに取得する代わりに使い、待っていたリクエストが後から来たリクエストに枠を奪われないようにする。
+/// 新しい負荷制御の方式は、この trait を実装して `ADMISSION_CONTROLLERS` に登録する。
+trait AdmissionController: Send + Sync {
+    fn admit<'a>(
+        &self,
+        state: &'a AppState,
+        config: &Configuration,
+        task: &TaskRequest,
+        queued: Option<SemaphorePermit<'a>>,
+    ) -> Result<Admission<'a>, Rejection>;
+}
+
+/// 既定の受付制御。レート制限、キュー、ワーカー全体の同時実行数、タスクプロファイルの順に判定する。
 ///
-/// # Examples
+/// どの段階でも待機せず、空きがなければ即座に拒否する。
 ///
-/// ```no_run
-/// // 簡易的な起動例（環境変数で設定を与えてから実行）
-/// // WORKER_NAME=demo WORKER_COLOR="#000000" PORT=8080 cargo run
-/// ```
-#[tokio::main]
-async fn main() {
-    tracing_subscriber::fmt::init();
+/// `unfair_prefix` が設定されている場合は、不公平なスケジューリングを再現する診断用モードになる。
+/// 同時実行枠の半分（切り上げ）をその接頭辞を持つ id のために確保し、それ以外のタスクは残りの枠でしか
+/// 実行できない。受け付けた件数は `worker_unfair_acquisitions_total` に接頭辞（それ以外は `other`）別に記録する。
+struct DefaultAdmission;
 
-    let config = load_config();
-    let worker_name = env::var("WORKER_NAME").unwrap_or_else(|_| "rust-worker-1".to_string());
-    let worker_color = env::var("WORKER_COLOR").unwrap_or_else(|_| "#F97316".to_string());
-    let port = env::var("PORT").unwrap_or_else(|_| "8080".to_string());
+impl AdmissionController for DefaultAdmission {
+    fn admit<'a>(
+        &self,
+        state: &'a AppState,
+        config: &Configuration,
+        task: &TaskRequest,
+        queued: Option<SemaphorePermit<'a>>,
+    ) -> Result<Admission<'a>, Rejection> {
+        if let RateLimitDecision::Limited { limit, reset } = state.take_rate_limit_token(config) {
+            return Err(Rejection::RateLimited { limit, reset });
+        }
 
-    let prometheus_handle = setup_metrics();
+        let queue_permit = match queued {
+            Some(permit) => permit,
+            None => state
+                .queue_semaphore
+                .try_acquire()
+                .map_err(|_| Rejection::QueueFull)?,
+        };
+        state.record_queue_depth(config);
 
-    let queue_size = config.queue_size as usize;
-    let state = Arc::new(AppState {
-        config: RwLock::new(config.clone()),
-        worker_name: worker_name.clone(),
-        worker_color: worker_color.clone(),
-        active_requests: AtomicI32::new(0),
-        queue_semaphore: Semaphore::new(queue_size),
-        queue_size: AtomicI64::new(0),
-        prometheus_handle,
-    });
+        let Ok(concurrency_permit) = state.concurrency_semaphore.try_acquire() else {
+            return Err(Rejection::Overloaded(format!(
+                "Max concurrent requests exceeded ({}/{})",
+                state.current_load(config) + 1,
+                config.max_concurrent_requests
+            )));
+        };
 
-    let cors = CorsLayer::new()
-        .allow_origin(Any)
-        .allow_methods(Any)
-        .allow_headers(Any);
-
-    let app = Router::new()
-        .route("/task", post(handle_task))
-        .route("/health", get(handle_health))
-        .route("/config", get(handle_config_get).post(handle_config_update).put(handle_config_update))
-        .route("/metrics", get(handle_metrics))
-        .layer(cors)
-        .with_state(state);
+        // Diagnostic unfairness: the upper half of the slots is kept for the favored prefix
+        if !config.unfair_prefix.is_empty() {
+            let favored = task.id.starts_with(&config.unfair_prefix);
+            let reserved = (config.max_concurrent_requests as usize).div_ceil(2);
+            if !favored && state.concurrency_semaphore.available_permits() < reserved {
+                return Err(Rejection::Overloaded(
+                    "Capacity reserved for prioritized tasks".to_string(),
+                ));
+            }
+            let class = if favored {
+                config.unfair_prefix.clone()
+            } else {
+                "other".to_string()
+            };
+            counter!("worker_unfair_acquisitions_total", "worker" => state.worker_name.clone(), "prefix" => class)
+                .increment(1);
+        }
 
-    let addr: SocketAddr = format!("0.0.0.0:{}", port).parse().unwrap();
-    tracing::info!(
-        "Starting {} on port {} (color: {})",
-        worker_name,
-        port,
-        worker_color
-    );
-    tracing::info!(
-        "Config: max_concurrent={}, delay={}ms, failure_rate={:.2}, queue_size={}",
-        config.max_concurrent_requests,
-        config.response_delay_ms,
-        config.failure_rate,
-        config.queue_size
-    );
+        // The task profile's own limit applies on top of the worker-wide one
+        let profile = task
+            .profile
+            .as_deref()
+            .and_then(|name| state.task_profiles.get_key_value(name));
+        let profile = match profile {
+            Some((name, profile)) => match profile.semaphore.try_acquire() {
+                Ok(p) => {
+                    gauge!("worker_profile_in_flight", "worker" => state.worker_name.clone(), "profile" => name.clone())
+                        .set(profile.in_flight() as f64);
+                    Some((name, profile, p))
+                }
+                Err(_) => {
+                    return Err(Rejection::Overloaded(format!(
+                        "Profile concurrency exceeded ({}: {}/{})",
+                        name,
+                        profile.in_flight() + 1,
+                        profile.max_concurrent
+                    )))
+                }
+            },
+            None => None,
+        };
 
-    let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
-    axum::serve(listener, app)
-        .with_graceful_shutdown(shutdown_signal())
-        .await
-        .unwrap();
-}
\ No newline at end of file
+        Ok(Admission {
+            queue_permit,
+            concurrency_permit,
+            profile,
+        })
+    }
+}
+
+/// `admission_strategy` に指定できる名前と、対応する受付制御の実装。
+const ADMISSION_CONTROLLERS: &[(&str, &dyn AdmissionController)] =
+    &[("default", &DefaultAdmission)];
+
+/// `admission_strategy` に対応する受付制御を返す。未登録の名前は既定の実装になる。
+fn admission_controller(strategy: &str) -> &'static dyn AdmissionController {
+    ADMISSION_CONTROLLERS
+        .iter()
+        .find(|(name, _)| *name == strategy)
+        .map_or(&DefaultAdmission, |(_, controller)| *controller)
+}
+
+/// `admission_strategy` として登録済みの名前かどうか。
+fn is_admission_strategy(strategy: &str) -> bool {
+    ADMISSION_CONTROLLERS
+        .iter()
+        .any(|(name, _)| *name == strategy)
+}
+
+struct AppState {
+    config: RwLock<Configuration>,
+    worker_name: String,
+    worker_color: String,
+    worker_version: String,
+    canary_version: String,
+    queue_semaphore: Semaphore,
+    concurrency_semaphore: Semaphore,
+    prometheus_handle: PrometheusHandle,
+    http_client: reqwest::Client,
+    /// 下流呼び出し用のクライアント。`DOWNSTREAM_POOLING` が無効なら接続を再利用しない。
+    downstream_client: reqwest::Client,
+    config_change_webhook: Option<String>,
+    /// `PUSHGATEWAY_URL` から組み立てた送信先（`…/metrics/job/{worker_name}`）。未設定なら送らない。
+    pushgateway_url: Option<reqwest::Url>,
+    draining: AtomicBool,
+    /// シャットダウンのシグナルを受けた後に立つ。`/ready` だけを 503 にする。
+    shutting_down: AtomicBool,
+    /// `POST /pause` で立ち、`POST /resume` で下りる。立っている間はタスクをキューに留める。
+    paused: AtomicBool,
+    /// 一時停止の解除を待っているリクエストを起こす。
+    resumed: Notify,
+    /// `depends_on` の解決に使う、セッションごとの成功したタスク。
+    completed_tasks: Mutex<CompletedTasks>,
+    /// タスクが成功するたびに、依存先を待っているリクエストを起こす。
+    task_completed: Notify,
+    /// `MAX_LIFETIME_REQUESTS`。この件数のタスク（`/task` とバッチの各要素）を処理し終えたらシャットダウンする。0 なら `None`（無制限）。
+    max_lifetime_requests: Option<u64>,
+    /// 起動から処理し終えた `/task` の件数。
+    lifetime_requests: AtomicU64,
+    /// `max_lifetime_requests` に達したときに `shutdown_signal` を起こす。
+    lifetime_exhausted: Notify,
+    /// `single_flight` で処理中のタスク。id ごとに 1 件だけ実際に処理する。
+    in_flight_tasks: Mutex<HashMap<String, InFlightTask>>,
+    /// 擬似障害の終了時刻。障害中でなければ `None`。
+    outage_until: Mutex<Option<Instant>>,
+    task_profiles: HashMap<String, TaskProfile>,
+    /// `TENANT_QUOTAS`・`TENANT_DEFAULT_QUOTA` のどちらも未設定なら `None`。
+    tenant_quotas: Option<TenantQuotas>,
+    /// `HARDWARE_CLASSES` で定義したクラス。空なら単一クラスとして振る舞う。
+    hardware_classes: Vec<HardwareClass>,
+    debug_endpoints: bool,
+    /// `DISABLED_ENDPOINTS` で指定され、ルーターに登録しなかったエンドポイント。
+    disabled_endpoints: Vec<&'static str>,
+    admin_token: Option<String>,
+    /// `JWT_SECRET` から作った `/task` の JWT 検証鍵。未設定なら認証しない。
+    jwt_key: Option<jsonwebtoken::DecodingKey>,
+    /// テナントとして扱う JWT のクレーム名（`JWT_TENANT_CLAIM`）。
+    jwt_tenant_claim: String,
+    forced_outcome: Mutex<Option<(ForcedOutcome, u32)>>,
+    last_response_slot: Mutex<Option<Instant>>,
+    /// リーキーバケットで最後に予約した受付時刻。
+    last_leak_slot: Mutex<Option<Instant>>,
+    /// リーキーバケットで受付時刻を待っているリクエスト数。
+    leak_buffered: AtomicI64,
+    queue_depth_max: AtomicI64,
+    config_history: Mutex<VecDeque<ConfigHistoryEntry>>,
+    /// 日周変動の現在の波形（-1.0..=1.0）を `f64::to_bits` で保持する。`simulate_diurnal` が更新する。
+    diurnal_wave: AtomicU64,
+    /// `SIMULATE_LEAK`。`leak_bytes_per_request` によるメモリリークはこれが有効な場合のみ作用する。
+    simulate_leak: bool,
+    /// `leak_bytes_per_request` で確保し、`/reset` まで解放しないバッファ。
+    leaked: Mutex<Vec<Vec<u8>>>,
+    leaked_bytes: AtomicU64,
+    /// `STATE_FILE`。設定されている場合のみ累積のカウンタを保存・復元する。
+    state_file: Option<PathBuf>,
+    /// `/task` のステータスコードごとの累積の応答数。`STATE_FILE` から復元した値を含む。
+    task_responses: Mutex<BTreeMap<u16, u64>>,
+    /// `per_id_metrics` でラベルに使ったタスク id。最大 `PER_ID_METRICS_LIMIT` 件。
+    metric_ids: Mutex<HashSet<String>>,
+    /// `error_response` で組み立てた直近のエラー。最大 `RECENT_ERRORS_LIMIT` 件。
+    recent_errors: Mutex<VecDeque<RecentError>>,
+    /// `handle_health` で観測したヘルス状態の遷移。最大 `HEALTH_HISTORY_LIMIT` 件。
+    health_history: Mutex<VecDeque<HealthTransition>>,
+    /// `health_cache_ms` が正の場合に `refresh_health_cache` が更新する、有効期限付きのヘルス状態。
+    health_cache: Mutex<Option<(Instant, HealthSnapshot)>>,
+    /// `RECORD_EVENTS` が有効な場合のみ `Some`。最大 `TASK_EVENTS_LIMIT` 件の `/task` のイベント。
+    task_events: Option<Mutex<VecDeque<TaskEvent>>>,
+    /// 最後に記録した `TaskEvent` の `seq`。
+    task_event_seq: AtomicU64,
+    /// 起動時を 0 として、設定が更新されるたびに 1 つ増える。
+    config_version: AtomicU64,
+    rate_limiter: Mutex<TokenBucket>,
+    log_sample_rate: f64,
+    /// `CHAOS_TRANSPORT`。HTTP のフレーミングや本文の形を壊す設定はこれが有効な場合のみ作用する。
+    chaos_transport: bool,
+    /// `CHAOS_HEALTH`。`health_flap_rate` による偽のヘルス状態はこれが有効な場合のみ返す。
+    chaos_health: bool,
+    /// `STICKY_SESSIONS` が有効な場合のみ `Some`。`/task` の応答にアフィニティ Cookie を付ける。
+    sticky_sessions: Option<StickySessions>,
+    /// `MAX_PARSE_MS`。`/task` と `/config` の本文の解析にかけてよい時間。0 なら `None`（無制限）。
+    max_parse: Option<Duration>,
+    warmup_task_id: Option<String>,
+    /// `RESPONSE_HEADERS` から読み込んだ、すべてのレスポンスに付与するヘッダー。
+    response_headers: HeaderMap,
+    /// 古いキャッシュとして返すタイムスタンプ（起動時刻）。
+    stale_timestamp: String,
+    /// `accept_id_pattern` をコンパイルしたもの。設定の変更時にのみ再コンパイルする。
+    accept_id_filter: RwLock<Option<Regex>>,
+    /// 同時実行許可を保持していた時間の累計（マイクロ秒）。
+    permit_held_micros: AtomicU64,
+    /// `permit_held_micros` の集計開始時刻。
+    utilization_since: Mutex<Instant>,
+    /// 直近の `/task` の結果。`/report` で集計する。
+    request_samples: Mutex<VecDeque<RequestSample>>,
+}
+
+impl AppState {
+    /// 環境変数に依存する起動オプションをすべて既定値にした状態を作る。
+    ///
+    /// メトリクスはグローバルなレコーダーに登録しないハンドルを使う。`main` は環境変数から読んだ値で必要なフィールドを上書きする。
+    fn new(config: Configuration, worker_name: String, worker_color: String) -> Self {
+        let queue_size = config.queue_size as usize;
+        let max_concurrent = config.max_concurrent_requests as usize;
+        let worker_version = env!("CARGO_PKG_VERSION").to_string();
+        Self {
+            config: RwLock::new(config),
+            worker_name,
+            worker_color,
+            canary_version: format!("{}-canary", worker_version),
+            worker_version,
+            queue_semaphore: Semaphore::new(queue_size),
+            concurrency_semaphore: Semaphore::new(max_concurrent),
+            prometheus_handle: PrometheusBuilder::new().build_recorder().handle(),
+            http_client: reqwest::Client::new(),
+            downstream_client: reqwest::Client::new(),
+            config_change_webhook: None,
+            pushgateway_url: None,
+            draining: AtomicBool::new(false),
+            shutting_down: AtomicBool::new(false),
+            paused: AtomicBool::new(false),
+            resumed: Notify::new(),
+            completed_tasks: Mutex::new(CompletedTasks::default()),
+            task_completed: Notify::new(),
+            max_lifetime_requests: None,
+            lifetime_requests: AtomicU64::new(0),
+            lifetime_exhausted: Notify::new(),
+            in_flight_tasks: Mutex::new(HashMap::new()),
+            outage_until: Mutex::new(None),
+            task_profiles: HashMap::new(),
+            tenant_quotas: None,
+            hardware_classes: Vec::new(),
+            debug_endpoints: false,
+            disabled_endpoints: Vec::new(),
+            admin_token: None,
+            jwt_key: None,
+            jwt_tenant_claim: "tenant".to_string(),
+            forced_outcome: Mutex::new(None),
+            last_response_slot: Mutex::new(None),
+            last_leak_slot: Mutex::new(None),
+            leak_buffered: AtomicI64::new(0),
+            queue_depth_max: AtomicI64::new(0),
+            config_history: Mutex::new(VecDeque::new()),
+            recent_errors: Mutex::new(VecDeque::new()),
+            health_history: Mutex::new(VecDeque::new()),
+            health_cache: Mutex::new(None),
+            task_events: None,
+            task_event_seq: AtomicU64::new(0),
+            metric_ids: Mutex::new(HashSet::new()),
+            diurnal_wave: AtomicU64::new(0.0f64.to_bits()),
+            simulate_leak: false,
+            leaked: Mutex::new(Vec::new()),
+            leaked_bytes: AtomicU64::new(0),
+            state_file: None,
+            task_responses: Mutex::new(BTreeMap::new()),
+            config_version: AtomicU64::new(0),
+            rate_limiter: Mutex::new(TokenBucket::new()),
+            log_sample_rate: 1.0,
+            chaos_transport: false,
+            chaos_health: false,
+            sticky_sessions: None,
+            max_parse: None,
+            warmup_task_id: None,
+            response_headers: HeaderMap::new(),
+            stale_timestamp: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Nanos, true),
+            accept_id_filter: RwLock::new(None),
+            permit_held_micros: AtomicU64::new(0),
+            utilization_since: Mutex::new(Instant::now()),
+            request_samples: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// このレスポンスが報告するバージョンを選ぶ。
+    ///
+    /// `canary_fraction` の確率で `canary_version` を、それ以外は `worker_version` を返し、
+    /// 1プロセス内でローリングデプロイ中の新旧バージョン混在をシミュレートする。
+    fn pick_version(&self, canary_fraction: f64) -> &str {
+        if canary_fraction > 0.0 && rand::thread_rng().gen::<f64>() < canary_fraction {
+            &self.canary_version
+        } else {
+            &self.worker_version
+        }
+    }
+
+    /// このリクエストを処理するハードウェアクラスを重みに従って選ぶ。クラスが未定義なら `None`。
+    fn pick_hardware_class(&self) -> Option<&HardwareClass> {
+        let total: f64 = self.hardware_classes.iter().map(|c| c.weight).sum();
+        let mut point = rand::thread_rng().gen_range(0.0..total.max(f64::MIN_POSITIVE));
+        for class in &self.hardware_classes {
+            if point < class.weight {
+                return Some(class);
+            }
+            point -= class.weight;
+        }
+        self.hardware_classes.last()
+    }
+
+    /// `X-Tenant` ヘッダー（なければ `default`）のテナントの上限の枠を 1 つ確保する。
+    ///
+    /// テナントの上限がなければ `Ok(None)`、枠が埋まっていれば `Err(())` を返す。
+    fn acquire_tenant_slot(&self, headers: &HeaderMap) -> Result<Option<TenantSlot>, ()> {
+        let Some(quotas) = &self.tenant_quotas else {
+            return Ok(None);
+        };
+        let tenant = headers
+            .get(TENANT_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .filter(|v| !v.is_empty())
+            .unwrap_or("default");
+        let bucket = quotas.bucket(tenant);
+        let Some(semaphore) = quotas.semaphore(bucket) else {
+            return Ok(None);
+        };
+        let permit = Arc::clone(&semaphore).try_acquire_owned().map_err(|_| ())?;
+        let slot = TenantSlot {
+            worker_name: self.worker_name.clone(),
+            tenant: bucket.to_string(),
+            quota: quotas.quota(bucket),
+            semaphore,
+            permit: Some(permit),
+        };
+        slot.publish_in_flight();
+        Ok(Some(slot))
+    }
+
+    /// 同時実行セマフォの払い出し済み許可数から現在の処理中リクエスト数を求める。
+    ///
+    /// 別途カウンタを持たず、セマフォを唯一の情報源とする。`max_concurrent_requests`
+    /// の縮小直後は旧上限分の許可が回収されるまで一時的に少なく見えることがある。
+    fn current_load(&self, config: &Configuration) -> i32 {
+        let available = self.concurrency_semaphore.available_permits() as i32;
+        (config.max_concurrent_requests - available).max(0)
+    }
+
+    /// キューセマフォの払い出し済み許可数から現在のキュー深度を求める。
+    fn queue_depth(&self, config: &Configuration) -> i32 {
+        let available = self.queue_semaphore.available_permits() as i32;
+        (config.queue_size - available).max(0)
+    }
+
+    /// `accept_id_pattern` をコンパイルして id フィルタを差し替える。
+    ///
+    /// 空文字列ならフィルタを外す。正規表現として不正な場合はエラーを記録し、フィルタを無効にする。
+    fn set_accept_id_pattern(&self, pattern: &str) {
+        let filter = if pattern.is_empty() {
+            None
+        } else {
+            match Regex::new(pattern) {
+                Ok(regex) => Some(regex),
+                Err(err) => {
+                    tracing::error!(
+                        "Invalid accept_id_pattern {:?}, accepting all ids: {}",
+                        pattern,
+                        err
+                    );
+                    None
+                }
+            }
+        };
+        *self.accept_id_filter.write() = filter;
+    }
+
+    /// id が `accept_id_pattern` に一致するか（フィルタ無効時は常に true）。
+    fn accepts_task_id(&self, id: &str) -> bool {
+        self.accept_id_filter
+            .read()
+            .as_ref()
+            .is_none_or(|regex| regex.is_match(id))
+    }
+
+    /// 同時実行許可を保持していた時間を累計に加える。
+    fn record_permit_held(&self, held: Duration) {
+        self.permit_held_micros
+            .fetch_add(held.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// 集計開始（起動時または `/reset`）以降の許可の利用率。
+    ///
+    /// 許可の保持時間の合計を、経過時間と `max_concurrent_requests` の積で割った値で、
+    /// 0 ならまったく使われておらず、1 なら常にすべての許可が埋まっていたことを表す。
+    fn permit_utilization(&self, max_concurrent_requests: i32) -> f64 {
+        let wall = self.utilization_since.lock().elapsed().as_secs_f64();
+        let capacity = wall * max_concurrent_requests.max(1) as f64;
+        if capacity <= 0.0 {
+            return 0.0;
+        }
+        let held = self.permit_held_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        held / capacity
+    }
+
+    /// 現在の許可の利用率を `worker_permit_utilization` ゲージへ反映する。
+    fn publish_permit_utilization(&self) {
+        let max_concurrent = self.config.read().max_concurrent_requests;
+        gauge!("worker_permit_utilization", "worker" => self.worker_name.clone())
+            .set(self.permit_utilization(max_concurrent));
+    }
+
+    /// 許可の利用率の集計を今の時点からやり直す。
+    fn reset_permit_utilization(&self) {
+        let mut since = self.utilization_since.lock();
+        self.permit_held_micros.store(0, Ordering::Relaxed);
+        *since = Instant::now();
+    }
+
+    /// 現在時刻のタイムスタンプを持つ成功レスポンスを組み立てる。任意フィールドは空のまま。
+    fn task_response(&self, id: String, processing_time_ms: i64, version: String) -> TaskResponse {
+        TaskResponse {
+            id,
+            worker: self.worker_name.clone(),
+            color: self.worker_color.clone(),
+            processing_time_ms,
+            timestamp: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Nanos, true),
+            version,
+            config: None,
+            attempts: None,
+            replica_lag_ms: None,
+            queue_position: None,
+        }
+    }
+
+    /// 受付制御の拒否理由をメトリクスに記録し、対応するエラーレスポンスに変換する。
+    ///
+    /// レート制限は 429 と `RateLimit-*`・`Retry-After` ヘッダー、受付待ちの時間切れは 408、それ以外は 503 になる。
+    /// 拒否した時点のキュー使用率を、拒否理由の `reason` ラベル付きで `worker_rejection_queue_ratio` に記録する。
+    fn rejection_response(&self, rejection: Rejection, version: &str) -> Response {
+        let version = version.to_string();
+        let reason = match rejection {
+            Rejection::RateLimited { .. } => "rate_limited",
+            Rejection::QueueFull => "rejected",
+            Rejection::AcceptTimeout => "accept_timeout",
+            Rejection::Overloaded(_) => "overloaded",
+        };
+        let queue_ratio = {
+            let config = self.config.read();
+            self.queue_depth(&config) as f64 / config.queue_size as f64
+        };
+        histogram!("worker_rejection_queue_ratio", "worker" => self.worker_name.clone(), "reason" => reason)
+            .record(queue_ratio);
+        match rejection {
+            Rejection::RateLimited { limit, reset } => {
+                let reset_secs = reset.as_secs_f64().ceil().max(1.0) as u64;
+                let mut resp = self.refuse_task(
+                    reason,
+                    &version,
+                    StatusCode::TOO_MANY_REQUESTS,
+                    "Rate limit exceeded",
+                );
+                let headers = resp.headers_mut();
+                headers.insert("ratelimit-limit", limit.into());
+                headers.insert("ratelimit-remaining", 0.into());
+                headers.insert("ratelimit-reset", reset_secs.into());
+                headers.insert(header::RETRY_AFTER, reset_secs.into());
+                resp
+            }
+            Rejection::QueueFull => self.refuse_task(
+                reason,
+                &version,
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Queue full - service overloaded",
+            ),
+            Rejection::AcceptTimeout => self.refuse_task(
+                reason,
+                &version,
+                StatusCode::REQUEST_TIMEOUT,
+                "Timed out waiting for admission",
+            ),
+            Rejection::Overloaded(message) => {
+                self.refuse_task(reason, &version, StatusCode::SERVICE_UNAVAILABLE, message)
+            }
+        }
+    }
+
+    /// `/task` を受け付けずに断るレスポンス。`worker_requests_total` を `reason` で数え、`TaskRejected` を付ける。
+    fn refuse_task(
+        &self,
+        reason: &'static str,
+        version: &str,
+        status: StatusCode,
+        error: impl Into<String>,
+    ) -> Response {
+        counter!("worker_requests_total", "worker" => self.worker_name.clone(), "status" => reason, "version" => version.to_string()).increment(1);
+        let mut resp = self.error_response(status, error);
+        resp.extensions_mut().insert(TaskRejected(reason));
+        resp
+    }
+
+    /// 失敗判定による 500 を記録して返す。`message` がなければ "Simulated failure" とする。
+    fn failure_response(&self, version: &str, message: Option<String>) -> Response {
+        counter!("worker_requests_total", "worker" => self.worker_name.clone(), "status" => "failed", "version" => version.to_string()).increment(1);
+        let message = message.unwrap_or_else(|| "Simulated failure".to_string());
+        self.error_response(StatusCode::INTERNAL_SERVER_ERROR, message)
+    }
+
+    /// 負荷とキュー深度から求めたヘルス状態（`draining`/`unhealthy`/`degraded`/`healthy`）。
+    ///
+    /// 両者の使用率は `Configuration::health_score` で 1 つのスコアにまとめてから閾値と比べる。
+    fn health_status(&self, config: &Configuration) -> &'static str {
+        let load_ratio = self.current_load(config) as f64 / config.max_concurrent_requests as f64;
+        let queue_ratio = self.queue_depth(config) as f64 / config.queue_size as f64;
+        let score = config.health_score(load_ratio, queue_ratio);
+
+        if self.draining.load(Ordering::SeqCst) {
+            "draining"
+        } else if score >= 0.9 {
+            "unhealthy"
+        } else if score >= 0.7 {
+            "degraded"
+        } else {
+            "healthy"
+        }
+    }
+
+    /// 成功レスポンスの色。劣化状態で処理した場合は `degraded_color` を、それ以外は `success_color` を使い、
+    /// 未設定なら `WORKER_COLOR` に戻す。
+    fn outcome_color(&self, config: &Configuration, degraded: bool) -> String {
+        let color = if degraded {
+            &config.degraded_color
+        } else {
+            &config.success_color
+        };
+        if color.is_empty() {
+            self.worker_color.clone()
+        } else {
+            color.clone()
+        }
+    }
+
+    /// `ErrorResponse` を JSON 本文とする指定ステータスのレスポンスを組み立てる。
+    ///
+    /// `failure_color` が設定されていれば `color` として含める。組み立てたエラーは `GET /errors/recent` 用に記録する。
+    fn error_response(&self, status: StatusCode, error: impl Into<String>) -> Response {
+        let error = error.into();
+        self.record_recent_error(status, &error);
+        (
+            status,
+            Json(ErrorResponse {
+                error,
+                worker: self.worker_name.clone(),
+                color: Some(self.config.read().failure_color.clone()).filter(|c| !c.is_empty()),
+            }),
+        )
+            .into_response()
+    }
+
+    /// `DEBUG_ENDPOINTS` が有効なら、設定を読む前に `config_read_delay_ms` だけ待つ。
+    ///
+    /// 負荷が高いときの設定ロックの競合を誇張して再現するためのもので、ロック自体は保持せずに待つ。
+    async fn contend_config_read(&self) {
+        if !self.debug_endpoints {
+            return;
+        }
+        let delay = self.config.read().config_read_delay_ms;
+        if delay > 0 {
+            counter!("worker_config_read_delays_total", "worker" => self.worker_name.clone())
+                .increment(1);
+            sleep(Duration::from_millis(delay as u64)).await;
+        }
+    }
+
+    /// ルートページに掲載する、このワーカーで有効なエンドポイント一覧。`DISABLED_ENDPOINTS` のものは含めない。
+    fn endpoints(&self) -> Vec<&'static str> {
+        let mut endpoints = ENDPOINTS.to_vec();
+        if self.debug_endpoints {
+            endpoints.extend_from_slice(DEBUG_ENDPOINT_ROUTES);
+        }
+        endpoints.retain(|endpoint| !self.disabled_endpoints.contains(endpoint));
+        endpoints
+    }
+
+    /// `STICKY_SESSIONS` が有効な場合に、応答へアフィニティ Cookie と `X-Worker-Affinity` ヘッダーを付ける。
+    ///
+    /// リクエストの Cookie がこのワーカーを指していれば `hit`、それ以外（Cookie なし・別のワーカー）は `miss` とし、
+    /// どちらの場合も Cookie を発行し直して有効期限を延ばす。結果は `worker_affinity_requests_total` に計上する。
+    fn apply_affinity(&self, headers: &HeaderMap, response: &mut Response) {
+        let Some(sticky) = &self.sticky_sessions else {
+            return;
+        };
+        let pinned = headers
+            .get_all(header::COOKIE)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(';'))
+            .filter_map(|pair| pair.trim().split_once('='))
+            .any(|(name, value)| name == sticky.cookie_name && value == self.worker_name);
+        let result = if pinned { "hit" } else { "miss" };
+        counter!("worker_affinity_requests_total", "worker" => self.worker_name.clone(), "result" => result)
+            .increment(1);
+
+        let cookie = format!(
+            "{}={}; Max-Age={}; Path=/; HttpOnly",
+            sticky.cookie_name, self.worker_name, sticky.ttl_secs
+        );
+        let headers = response.headers_mut();
+        if let Ok(cookie) = HeaderValue::from_str(&cookie) {
+            headers.append(header::SET_COOKIE, cookie);
+        }
+        headers.insert("x-worker-affinity", HeaderValue::from_static(result));
+    }
+
+    /// `/task` の JWT を検証し、テナントのクレームを返す。
+    ///
+    /// `JWT_SECRET` が未設定なら常に `Ok(None)`。トークンが欠落・不正・期限切れの場合はエラーメッセージを返す。
+    /// テナントのクレームがないトークンは `unknown` として扱う。
+    fn authenticate_task(&self, headers: &HeaderMap) -> Result<Option<String>, &'static str> {
+        let Some(key) = &self.jwt_key else {
+            return Ok(None);
+        };
+        let token = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .ok_or("Missing bearer token")?;
+        let claims = jsonwebtoken::decode::<serde_json::Map<String, serde_json::Value>>(
+            token,
+            key,
+            &jsonwebtoken::Validation::new(jsonwebtoken::Algorithm::HS256),
+        )
+        .map_err(|err| match err.kind() {
+            jsonwebtoken::errors::ErrorKind::ExpiredSignature => "Token expired",
+            _ => "Invalid token",
+        })?
+        .claims;
+        let tenant = match claims.get(&self.jwt_tenant_claim) {
+            Some(serde_json::Value::String(tenant)) => tenant.clone(),
+            Some(other) => other.to_string(),
+            None => "unknown".to_string(),
+        };
+        Ok(Some(tenant))
+    }
+
+    /// 管理用エンドポイントへのアクセスを検証し、拒否する場合はそのレスポンスを返す。
+    ///
+    /// `ADMIN_TOKEN` が設定されている場合は `Authorization: Bearer <token>` が一致しなければ 401 を返す。
+    /// 未設定の場合はサンドボックス用途として誰でもアクセスできる。
+    fn reject_unauthorized_admin(&self, headers: &HeaderMap) -> Option<Response> {
+        let expected = self.admin_token.as_ref()?;
+        let provided = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "));
+        (provided != Some(expected.as_str()))
+            .then(|| self.error_response(StatusCode::UNAUTHORIZED, "Admin authorization required"))
+    }
+
+    /// 事前指定された結果があれば 1 回分消費して返す。
+    fn take_forced_outcome(&self) -> Option<ForcedOutcome> {
+        let mut forced = self.forced_outcome.lock();
+        let (outcome, remaining) = forced.as_mut()?;
+        let outcome = *outcome;
+        *remaining -= 1;
+        if *remaining == 0 {
+            *forced = None;
+        }
+        Some(outcome)
+    }
+
+    /// 直前の応答から `gap` 以上空くように次の応答時刻を予約して返す。
+    ///
+    /// 複数のリクエストが同時に完了しても予約は順番に `gap` ずつずれていくため、
+    /// 同時実行数に関係なくワーカー全体のスループットが `1 / gap` に抑えられる。
+    fn reserve_response_slot(&self, gap: Duration) -> Instant {
+        reserve_slot(&self.last_response_slot, gap)
+    }
+
+    /// リーキーバケットから次に処理へ流す時刻（`slot`）を予約し、待つ 1 件として数え始める。
+    ///
+    /// `reserve_response_slot` と同じ仕組みで、受付の間隔を `1 / leak_rate_rps`（最大 `MAX_LEAK_INTERVAL`）に揃える。
+    /// 返した値を捨てると数から外れるため、待機がキャンセルされても狂わない。
+    fn enter_leak_buffer(&self, config: &Configuration) -> LeakBufferEntry<'_> {
+        let gap = Duration::try_from_secs_f64(1.0 / config.leak_rate_rps)
+            .map_or(MAX_LEAK_INTERVAL, |gap| gap.min(MAX_LEAK_INTERVAL));
+        let slot = reserve_slot(&self.last_leak_slot, gap);
+        self.leak_buffered.fetch_add(1, Ordering::SeqCst);
+        self.publish_leak_depth();
+        LeakBufferEntry {
+            state: self,
+            slot,
+            gap,
+        }
+    }
+
+    /// リーキーバケットで待機中のリクエスト数を `worker_leaky_bucket_depth` に反映する。
+    fn publish_leak_depth(&self) {
+        gauge!("worker_leaky_bucket_depth", "worker" => self.worker_name.clone())
+            .set(self.leak_buffered.load(Ordering::SeqCst) as f64);
+    }
+
+    /// トークンバケットから 1 トークン取り出す。`rate_limit_rps` が 0 の場合は常に許可する。
+    fn take_rate_limit_token(&self, config: &Configuration) -> RateLimitDecision {
+        if config.rate_limit_rps <= 0.0 {
+            return RateLimitDecision::Unlimited;
+        }
+        let burst = config.effective_rate_limit_burst();
+        let (remaining, reset) =
+            self.rate_limiter
+                .lock()
+                .take(config.rate_limit_rps, burst as f64, Instant::now());
+        if remaining.is_some() {
+            RateLimitDecision::Allowed
+        } else {
+            RateLimitDecision::Limited {
+                limit: burst,
+                reset,
+            }
+        }
+    }
+
+    /// `/task` 1 件分の完了ログを出力する。
+    ///
+    /// エラー応答は常に warn で出力し、成功応答は `log_sample_rate` の割合だけ info で出力する。
+    fn log_task(&self, id: &str, status: StatusCode, elapsed: Duration) {
+        let elapsed_ms = elapsed.as_millis() as u64;
+        if status.is_client_error() || status.is_server_error() {
+            tracing::warn!(id, status = status.as_u16(), elapsed_ms, "task failed");
+        } else if rand::thread_rng().gen::<f64>() < self.log_sample_rate {
+            tracing::info!(id, status = status.as_u16(), elapsed_ms, "task completed");
+        }
+    }
+
+    /// `/task` の結果を `/report` 用に記録し、`REPORT_WINDOW` より古いものを捨てる。
+    fn record_request_sample(&self, status: StatusCode, elapsed: Duration) {
+        let now = Instant::now();
+        let mut samples = self.request_samples.lock();
+        while samples.front().is_some_and(|s| {
+            now.duration_since(s.at) > REPORT_WINDOW || samples.len() >= REPORT_SAMPLE_LIMIT
+        }) {
+            samples.pop_front();
+        }
+        samples.push_back(RequestSample {
+            at: now,
+            status,
+            elapsed_ms: elapsed.as_secs_f64() * 1000.0,
+        });
+    }
+
+    /// 記録済みの結果と現在の負荷から `CapacityReport` を組み立てる。
+    fn capacity_report(&self) -> CapacityReport {
+        let now = Instant::now();
+        let config = self.config.read().clone();
+        let window = self.utilization_since.lock().elapsed().min(REPORT_WINDOW);
+        let samples = self.request_samples.lock();
+        let recent: Vec<&RequestSample> = samples
+            .iter()
+            .filter(|s| now.duration_since(s.at) <= window)
+            .collect();
+
+        let mut per_second: HashMap<u64, usize> = HashMap::new();
+        for sample in &recent {
+            *per_second
+                .entry(now.duration_since(sample.at).as_secs())
+                .or_default() += 1;
+        }
+        let is_rejection = |status: StatusCode| {
+            status == StatusCode::SERVICE_UNAVAILABLE || status == StatusCode::TOO_MANY_REQUESTS
+        };
+        let rejected = recent.iter().filter(|s| is_rejection(s.status)).count();
+        let mut latencies: Vec<f64> = recent
+            .iter()
+            .filter(|s| s.status.is_success())
+            .map(|s| s.elapsed_ms)
+            .collect();
+        latencies.sort_by(f64::total_cmp);
+        let failure_latencies: Vec<f64> = recent
+            .iter()
+            .filter(|s| !s.status.is_success() && !is_rejection(s.status))
+            .map(|s| s.elapsed_ms)
+            .collect();
+
+        let window_secs = window.as_secs_f64().max(1.0);
+        CapacityReport {
+            window_secs: window.as_secs_f64(),
+            requests: recent.len(),
+            throughput_rps: recent.len() as f64 / window_secs,
+            max_throughput_rps: per_second.values().copied().max().unwrap_or(0) as f64,
+            current_load: self.current_load(&config),
+            max_concurrent_requests: config.max_concurrent_requests,
+            permit_utilization: self.permit_utilization(config.max_concurrent_requests),
+            rejection_rate: if recent.is_empty() {
+                0.0
+            } else {
+                rejected as f64 / recent.len() as f64
+            },
+            latency_p50_ms: percentile(&latencies, 50.0),
+            latency_p99_ms: percentile(&latencies, 99.0),
+            success_latency: LatencyPercentiles::from_unsorted(latencies),
+            failure_latency: LatencyPercentiles::from_unsorted(failure_latencies),
+        }
+    }
+
+    /// 現在のキュー深度で最高水位を更新し、`worker_queue_depth_max` に反映する。
+    ///
+    /// スクレイプ間隔の間に発生した一時的なキューの飽和を取りこぼさないためのもの。
+    fn record_queue_depth(&self, config: &Configuration) {
+        let depth = self.queue_depth(config) as i64;
+        let max = self
+            .queue_depth_max
+            .fetch_max(depth, Ordering::SeqCst)
+            .max(depth);
+        gauge!("worker_queue_depth_max", "worker" => self.worker_name.clone()).set(max as f64);
+    }
+
+    /// `per_id_metrics` で使う `id` ラベルの値。`PER_ID_METRICS_LIMIT` 件を超えた新しい id は `other` にする。
+    fn metric_id_label(&self, id: &str) -> String {
+        let mut ids = self.metric_ids.lock();
+        if ids.contains(id) || (ids.len() < PER_ID_METRICS_LIMIT && ids.insert(id.to_string())) {
+            id.to_string()
+        } else {
+            "other".to_string()
+        }
+    }
+
+    /// `SIMULATE_LEAK` が有効な場合に `bytes` を確保して保持し続け、`worker_leaked_bytes` に反映する。
+    ///
+    /// 合計が `LEAK_CAP_BYTES` に達した後は何もしない。確保した領域は実際に書き込んで常駐させる。
+    fn leak_memory(&self, bytes: i32) {
+        if !self.simulate_leak || bytes <= 0 {
+            return;
+        }
+        let bytes = bytes as u64;
+        let mut leaked = self.leaked.lock();
+        let total = self.leaked_bytes.load(Ordering::SeqCst);
+        if total + bytes > LEAK_CAP_BYTES {
+            return;
+        }
+        leaked.push(vec![0xA5; bytes as usize]);
+        self.leaked_bytes.store(total + bytes, Ordering::SeqCst);
+        gauge!("worker_leaked_bytes", "worker" => self.worker_name.clone())
+            .set((total + bytes) as f64);
+    }
+
+    /// `leak_memory` で保持しているバッファを解放する。
+    fn release_leaked_memory(&self) {
+        *self.leaked.lock() = Vec::new();
+        self.leaked_bytes.store(0, Ordering::SeqCst);
+        gauge!("worker_leaked_bytes", "worker" => self.worker_name.clone()).set(0.0);
+    }
+
+    /// `/task` の応答をステータスコード別に数え、`worker_task_responses_total` に計上する。
+    fn count_task_response(&self, status: StatusCode) {
+        *self
+            .task_responses
+            .lock()
+            .entry(status.as_u16())
+            .or_default() += 1;
+        counter!("worker_task_responses_total", "worker" => self.worker_name.clone(), "code" => status.as_u16().to_string())
+            .increment(1);
+    }
+
+    /// 保存されていたカウンタを引き継ぎ、`worker_task_responses_total` をその値から始める。
+    fn restore_task_responses(&self, persisted: PersistedState) {
+        for (code, count) in &persisted.task_responses {
+            counter!("worker_task_responses_total", "worker" => self.worker_name.clone(), "code" => code.to_string())
+                .absolute(*count);
+        }
+        *self.task_responses.lock() = persisted.task_responses;
+    }
+
+    /// `STATE_FILE` が設定されていれば、現在の累積カウンタを書き出す。
+    fn checkpoint(&self) {
+        let Some(path) = &self.state_file else {
+            return;
+        };
+        let persisted = PersistedState {
+            task_responses: self.task_responses.lock().clone(),
+        };
+        if let Err(err) = persisted.save(path) {
+            tracing::warn!("Failed to write state file {}: {}", path.display(), err);
+        }
+    }
+
+    /// エラーを直近のエラーに追加する。`RECENT_ERRORS_LIMIT` を超えた分は古いものから捨てる。
+    ///
+    /// `/task` の処理中であれば、そのタスクの id も記録する。
+    fn record_recent_error(&self, status: StatusCode, error: &str) {
+        let entry = RecentError {
+            timestamp: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            id: CURRENT_TASK_ID.try_with(Clone::clone).ok(),
+            status: status.as_u16(),
+            error: error.to_string(),
+        };
+        let mut recent = self.recent_errors.lock();
+        if recent.len() == RECENT_ERRORS_LIMIT {
+            recent.pop_front();
+        }
+        recent.push_back(entry);
+    }
+
+    /// 現在の設定と負荷からヘルス状態を求め、`record_health` で遷移を記録する。
+    fn health_snapshot(&self) -> HealthSnapshot {
+        let config = self.config.read();
+        let status = self.health_status(&config);
+        self.record_health(status);
+        HealthSnapshot {
+            status,
+            current_load: self.current_load(&config),
+            queue_depth: self.queue_depth(&config),
+            flap_rate: config.health_flap_rate,
+        }
+    }
+
+    /// 有効期限内のキャッシュ済みのヘルス状態。なければ `None`。
+    fn cached_health(&self) -> Option<HealthSnapshot> {
+        self.health_cache
+            .lock()
+            .filter(|(expires_at, _)| Instant::now() < *expires_at)
+            .map(|(_, snapshot)| snapshot)
+    }
+
+    /// `health_cache_ms` が正ならヘルス状態を求め直してキャッシュし、次に更新するまでの時間を返す。
+    ///
+    /// 更新が多少遅れてもキャッシュが切れないよう、有効期限は `health_cache_ms` の 2 倍にする。
+    /// 0 ならキャッシュを捨てて `HEALTH_CACHE_IDLE_INTERVAL` を返す。
+    fn refresh_health_cache(&self) -> Duration {
+        let cache_ms = self.config.read().health_cache_ms;
+        if cache_ms <= 0 {
+            *self.health_cache.lock() = None;
+            return HEALTH_CACHE_IDLE_INTERVAL;
+        }
+        let window = Duration::from_millis(cache_ms as u64);
+        let snapshot = self.health_snapshot();
+        *self.health_cache.lock() = Some((Instant::now() + window * 2, snapshot));
+        window
+    }
+
+    /// `handle_health` が求めた状態が直前の記録と異なれば遷移として追加する。
+    ///
+    /// 最初の記録以外の遷移は `worker_health_flaps_total` に計上する。`HEALTH_HISTORY_LIMIT` を超えた分は古いものから捨てる。
+    fn record_health(&self, status: &'static str) {
+        let mut history = self.health_history.lock();
+        let previous = history.back().map(|t| t.status);
+        if previous == Some(status) {
+            return;
+        }
+        if let Some(previous) = previous {
+            counter!("worker_health_flaps_total", "worker" => self.worker_name.clone(), "from" => previous, "to" => status)
+                .increment(1);
+        }
+        if history.len() == HEALTH_HISTORY_LIMIT {
+            history.pop_front();
+        }
+        history.push_back(HealthTransition {
+            timestamp: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            status,
+        });
+    }
+
+    /// `RECORD_EVENTS` が有効なら `/task` のイベントを追加する。`TASK_EVENTS_LIMIT` を超えた分は古いものから捨てる。
+    fn record_event(
+        &self,
+        event: &'static str,
+        id: Option<String>,
+        reason: Option<&'static str>,
+        status: Option<u16>,
+    ) {
+        let Some(events) = &self.task_events else {
+            return;
+        };
+        let mut events = events.lock();
+        if events.len() == TASK_EVENTS_LIMIT {
+            events.pop_front();
+        }
+        // Assigned under the lock so seq order matches the order in the buffer
+        let seq = self.task_event_seq.fetch_add(1, Ordering::Relaxed) + 1;
+        events.push_back(TaskEvent {
+            seq,
+            timestamp: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Nanos, true),
+            event,
+            id,
+            reason,
+            status,
+        });
+    }
+
+    /// 適用された設定を履歴に追加する。`CONFIG_HISTORY_LIMIT` を超えた分は古いものから捨てる。
+    fn record_config_history(&self, config: &Configuration) {
+        let mut history = self.config_history.lock();
+        if history.len() == CONFIG_HISTORY_LIMIT {
+            history.pop_front();
+        }
+        history.push_back(ConfigHistoryEntry {
+            timestamp: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            config: config.clone(),
+        });
+    }
+
+    /// ドレイン状態を切り替える。ドレイン中は新規タスクを受け付けず、ヘルスチェックも 503 を返す。
+    fn set_draining(&self, draining: bool) {
+        if self.draining.swap(draining, Ordering::SeqCst) != draining {
+            // Probes must see the drain state immediately, not after the cache expires
+            *self.health_cache.lock() = None;
+            if draining {
+                tracing::info!("Entering drain mode; new tasks will be rejected");
+                // Tasks parked by a pause are in flight and must finish for the drain to end
+                self.resumed.notify_waiters();
+            } else {
+                tracing::info!("Leaving drain mode; accepting tasks again");
+            }
+        }
+    }
+
+    /// `now` の時点で擬似障害の最中かどうか。
+    fn in_outage(&self, now: Instant) -> bool {
+        self.outage_until.lock().is_some_and(|until| now < until)
+    }
+
+    /// 擬似障害の抽選を 1 回行う。障害中でなければ `outage_probability` の確率で
+    /// `outage_duration_ms` の障害を開始し、障害が明けていれば回復を記録する。障害を開始した場合は true を返す。
+    fn roll_outage(&self, config: &Configuration, now: Instant) -> bool {
+        let mut outage_until = self.outage_until.lock();
+        if let Some(until) = *outage_until {
+            if now < until {
+                return false;
+            }
+            *outage_until = None;
+            tracing::info!("Simulated outage over; accepting tasks again");
+        }
+        if config.outage_duration_ms <= 0
+            || rand::thread_rng().gen::<f64>() >= config.outage_probability
+        {
+            return false;
+        }
+        *outage_until = Some(now + Duration::from_millis(config.outage_duration_ms as u64));
+        counter!("worker_outages_total", "worker" => self.worker_name.clone()).increment(1);
+        tracing::warn!(
+            "Simulated outage started for {}ms",
+            config.outage_duration_ms
+        );
+        true
+    }
+
+    /// 一時停止状態を切り替える。解除した場合は待機中のリクエストをすべて再開させる。
+    fn set_paused(&self, paused: bool) {
+        if self.paused.swap(paused, Ordering::SeqCst) != paused {
+            if paused {
+                tracing::info!("Processing paused; tasks will wait in the queue");
+            } else {
+                tracing::info!("Processing resumed");
+                self.resumed.notify_waiters();
+            }
+        }
+    }
+
+    /// 処理し終えた `/task`（バッチの各要素を含み、ウォームアップは除く）を 1 件数え、`max_lifetime_requests` にちょうど達したらシャットダウンを始めさせる。
+    fn count_lifetime_request(&self) {
+        let count = self.lifetime_requests.fetch_add(1, Ordering::SeqCst) + 1;
+        if self.max_lifetime_requests == Some(count) {
+            tracing::info!(
+                "Processed {} requests; MAX_LIFETIME_REQUESTS reached",
+                count
+            );
+            // notify_one keeps the permit even if shutdown_signal is not waiting yet
+            self.lifetime_exhausted.notify_one();
+        }
+    }
+
+    /// セッション `session` で成功したタスクとして `id` を記録し、依存先を待っているリクエストを起こす。
+    fn record_completed_task(&self, session: &str, id: &str) {
+        self.completed_tasks.lock().insert(session, id);
+        self.task_completed.notify_waiters();
+    }
+
+    /// セッション `session` で `dependency` のタスクが成功するまで、最大 `timeout` 待つ。
+    /// 期限までに成功しなければ `false` を返す。
+    async fn wait_for_dependency(
+        &self,
+        session: &str,
+        dependency: &str,
+        timeout: Duration,
+    ) -> bool {
+        let wait = async {
+            loop {
+                // Register before checking so a completion in between is not missed
+                let completed = self.task_completed.notified();
+                if self.completed_tasks.lock().contains(session, dependency) {
+                    return;
+                }
+                completed.await;
+            }
+        };
+        tokio::time::timeout(timeout, wait).await.is_ok()
+    }
+
+    /// シャットダウンを始めたことを記録し、一時停止で待っているリクエストを起こす。
+    fn begin_shutdown(&self) {
+        self.shutting_down.store(true, Ordering::SeqCst);
+        self.resumed.notify_waiters();
+    }
+
+    /// 一時停止が解除されるまで待つ。停止していなければ即座に返る。
+    ///
+    /// ドレインやシャットダウンが始まった場合も待つのをやめる。受け付け済みのタスクを処理し終えないと終了できないため。
+    async fn wait_until_resumed(&self) {
+        loop {
+            // Register before checking so a resume in between is not missed
+            let resumed = self.resumed.notified();
+            if !self.paused.load(Ordering::SeqCst)
+                || self.draining.load(Ordering::SeqCst)
+                || self.shutting_down.load(Ordering::SeqCst)
+            {
+                return;
+            }
+            resumed.await;
+        }
+    }
+
+    /// 設定変更 Webhook が設定されていれば、新しい設定を非同期に POST する。
+    ///
+    /// 呼び出し元を待たせない fire-and-forget で、失敗やタイムアウトはログに記録するだけで
+    /// 設定更新そのものには影響しない。
+    fn notify_config_change(&self, config: &Configuration) {
+        let Some(url) = self.config_change_webhook.clone() else {
+            return;
+        };
+        let request = self
+            .http_client
+            .post(&url)
+            .timeout(WEBHOOK_TIMEOUT)
+            .json(config);
+        tokio::spawn(async move {
+            match request.send().await.and_then(|r| r.error_for_status()) {
+                Ok(_) => tracing::debug!("Config change webhook delivered to {}", url),
+                Err(e) => tracing::warn!("Config change webhook to {} failed: {}", url, e),
+            }
+        });
+    }
+
+    /// 現在のメトリクスを Pushgateway に送る。`pushgateway_url` が未設定なら何もしない。
+    ///
+    /// 同じグループを PUT で丸ごと置き換えるので、消えたラベルの系列が残り続けることはない。
+    /// 失敗はログと `worker_pushgateway_failures_total` に記録するだけで、次の送信で取り戻す。
+    /// `handle_metrics` と同じく、描画は `spawn_blocking` で行いランタイムのワーカースレッドを塞がない。
+    async fn push_metrics(&self) {
+        let Some(url) = self.pushgateway_url.clone() else {
+            return;
+        };
+        let handle = self.prometheus_handle.clone();
+        let result = match tokio::task::spawn_blocking(move || handle.render()).await {
+            Ok(body) => self
+                .http_client
+                .put(url.clone())
+                .timeout(PUSHGATEWAY_TIMEOUT)
+                .header(header::CONTENT_TYPE, "text/plain; version=0.0.4")
+                .body(body)
+                .send()
+                .await
+                .and_then(|r| r.error_for_status())
+                .map(drop)
+                .map_err(|e| e.to_string()),
+            Err(e) => Err(e.to_string()),
+        };
+        match result {
+            Ok(()) => tracing::debug!("Pushed metrics to {}", url),
+            Err(e) => {
+                tracing::warn!("Pushing metrics to {} failed: {}", url, e);
+                counter!("worker_pushgateway_failures_total", "worker" => self.worker_name.clone())
+                    .increment(1);
+            }
+        }
+    }
+
+    /// 同時実行セマフォの許可数を新しい `max_concurrent_requests` に合わせて増減する。
+    ///
+    /// 増やす場合は即座に許可を追加する。減らす場合は空いている許可を破棄し、
+    /// 処理中のリクエストが保持している残りは解放され次第バックグラウンドで回収する。
+    fn resize_concurrency(self: &Arc<Self>, old: i32, new: i32) {
+        if new > old {
+            self.concurrency_semaphore.add_permits((new - old) as usize);
+            return;
+        }
+        let shrink = (old - new) as usize;
+        let remaining = shrink - self.concurrency_semaphore.forget_permits(shrink);
+        if remaining > 0 {
+            let state = Arc::clone(self);
+            tokio::spawn(async move {
+                if let Ok(permits) = state
+                    .concurrency_semaphore
+                    .acquire_many(remaining as u32)
+                    .await
+                {
+                    permits.forget();
+                }
+            });
+        }
+    }
+}
+
+/// 環境変数からi32値を取得し、存在しないか整数に変換できない場合はデフォルト値を返す。
+///
+/// 指定したキーの環境変数を読み取り、UTF-8文字列をi32として解析して返します。環境変数が未設定または解析に失敗した場合は `default` を返します。
+///
+/// # Examples
+///
+/// ```
+/// use std::env;
+/// // 値が設定されている場合はその値を返す
+/// env::set_var("TEST_INT", "42");
+/// assert_eq!(get_env_i32("TEST_INT", 10), 42);
+///
+/// // 不正な値の場合はデフォルトを返す
+/// env::set_var("TEST_INT", "not_an_int");
+/// assert_eq!(get_env_i32("TEST_INT", 10), 10);
+///
+/// // 未設定の場合はデフォルトを返す
+/// env::remove_var("TEST_INT");
+/// assert_eq!(get_env_i32("TEST_INT", 10), 10);
+/// ```
+fn get_env_i32(key: &str, default: i32) -> i32 {
+    env::var(key)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+}
+
+/// 環境変数を読み取り、f64 に変換して返す。
+///
+/// 指定した `key` の環境変数を読み取り、`f64` にパースできればその値を返します。環境変数が未設定またはパースに失敗した場合は `default` を返します。
+///
+/// # Examples
+///
+/// ```
+/// use std::env;
+///
+/// // 環境変数が数値の場合はその値を返す
+/// env::set_var("TEST_F64", "3.14");
+/// assert_eq!(get_env_f64("TEST_F64", 1.0), 3.14);
+///
+/// // 未設定または無効な値の場合はデフォルトを返す
+/// env::remove_var("TEST_F64");
+/// assert_eq!(get_env_f64("TEST_F64", 2.5), 2.5);
+/// env::set_var("TEST_F64", "not_a_number");
+/// assert_eq!(get_env_f64("TEST_F64", 4.2), 4.2);
+/// ```
+fn get_env_f64(key: &str, default: f64) -> f64 {
+    env::var(key)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+}
+
+/// 環境変数を真偽値として読み取る。
+///
+/// `1`/`true`/`yes`/`on` を真、`0`/`false`/`no`/`off` を偽とみなし（大文字小文字は区別しない）、
+/// 未設定またはそれ以外の値の場合は `default` を返す。
+///
+/// # Examples
+///
+/// ```
+/// std::env::set_var("TEST_BOOL", "Yes");
+/// assert!(get_env_bool("TEST_BOOL", false));
+/// std::env::set_var("TEST_BOOL", "maybe");
+/// assert!(!get_env_bool("TEST_BOOL", false));
+/// ```
+fn get_env_bool(key: &str, default: bool) -> bool {
+    env::var(key)
+        .ok()
+        .and_then(|v| parse_bool(&v))
+        .unwrap_or(default)
+}
+
+/// `get_env_bool` が受け付ける真偽値の表記を解釈する。それ以外の値は `None`。
+fn parse_bool(value: &str) -> Option<bool> {
+    match value.trim().to_ascii_lowercase().as_str() {
+        "1" | "true" | "yes" | "on" => Some(true),
+        "0" | "false" | "no" | "off" => Some(false),
+        _ => None,
+    }
+}
+
+/// 環境変数からランタイム設定を読み取り、Configuration構造体を生成する。
+///
+/// 環境変数が存在しないか解析できない場合は既定値を使用する：
+/// - `MAX_CONCURRENT_REQUESTS` → 10
+/// - `RESPONSE_DELAY_MS` → 100
+/// - `FAILURE_RATE` → 0.0
+/// - `QUEUE_SIZE` → 50（`MAX_QUEUE_SIZE` を超える値は切り詰める）
+/// - `CANARY_FRACTION` → 0.0
+/// - `BASE_JITTER_MS` → 0
+/// - `ECHO_CONFIG` → false
+/// - `MIN_INTER_RESPONSE_MS` → 0
+/// - `SHADOW_ENABLED` → false
+/// - `SHADOW_RESPONSE_DELAY_MS` → `RESPONSE_DELAY_MS` と同じ値
+/// - `SHADOW_FAILURE_RATE` → `FAILURE_RATE` と同じ値
+/// - `RATE_LIMIT_RPS` → 0.0（無効）
+/// - `RATE_LIMIT_BURST` → 0（`rate_limit_rps` から自動決定）
+/// - `LATENCY_BUDGET_MS` → 0（無制限）
+/// - `TRICKLE_BYTES_PER_SEC` → 0（無効）
+/// - `STALE_TIMESTAMP_RATE` → 0.0（無効）
+/// - `ACCEPT_ID_PATTERN` → 空（すべての id を受け付ける）
+/// - `ADMISSION_STRATEGY` → "default"（`ADMISSION_CONTROLLERS` に登録された名前のみ）
+/// - `PROBLEM_JSON` → false
+/// - `DOWNSTREAM_URL` → 空（下流呼び出しなし）
+/// - `DOWNSTREAM_CONNECT_DELAY_MS` → 0
+/// - `SUCCESS_COLOR` → 空（`WORKER_COLOR` を使う）
+/// - `DEGRADED_COLOR` → 空（`WORKER_COLOR` を使う）
+/// - `FAILURE_COLOR` → 空（`WORKER_COLOR` を使う）
+/// - `OUTAGE_PROBABILITY` → 0.0（無効。`OUTAGE_CHECK_INTERVAL` ごとの発生確率）
+/// - `OUTAGE_DURATION_MS` → 0
+/// - `FAILURE_SCALES_WITH_WEIGHT` → false
+/// - `UNFAIR_PREFIX` → 空（公平）
+/// - `INTERNAL_RETRIES` → 0（再試行しない）
+/// - `BROKEN_CONTENT_LENGTH_RATE` → 0.0（無効。`CHAOS_TRANSPORT` が有効な場合のみ作用）
+/// - `LEAK_RATE_RPS` → 0.0（無効）
+/// - `HEALTH_LOAD_WEIGHT` → 1.0
+/// - `HEALTH_QUEUE_WEIGHT` → 1.0
+/// - `HEALTH_SCORE_MODE` → "max"（`sum` で加重和）
+/// - `REPLICA_LAG_MS` → 0（`replicaLagMs` を含めない）
+/// - `REPLICA_LAG_JITTER_MS` → 0
+/// - `PER_ID_METRICS` → false
+/// - `FAILURE_TAKES_PRECEDENCE` → false（拒否が優先）
+/// - `SUCCESS_STATUS_CODE` → 200（200・201・202 のいずれか）
+/// - `ACCEPTED_LOCATION` → false
+/// - `LEAK_BYTES_PER_REQUEST` → 0（無効。`SIMULATE_LEAK` が有効な場合のみ作用）
+/// - `ACCEPT_TIMEOUT_MS` → 0（無制限）
+/// - `DEPENDENCY_TIMEOUT_MS` → 5000
+/// - `TRUNCATE_RESPONSE_RATE` → 0.0（無効。`CHAOS_TRANSPORT` が有効な場合のみ作用）
+/// - `DIURNAL_PERIOD_MS` → 600000（1 日を 10 分に圧縮）
+/// - `DIURNAL_AMPLITUDE` → 0.0（無効）
+/// - `INCLUDE_QUEUE_POSITION` → false
+/// - `SINGLE_FLIGHT` → false
+/// - `LATENCY_TABLE` → 空（`delay_ms:weight` のカンマ区切り。例: `10:80,100:15,1000:5`）
+/// - `TASK_STEPS` → false（本文の `steps` を無視）
+/// - `PROCESSING_TIMEOUT_MS` → 0（無制限）
+/// - `STREAM_TRAILERS` → false
+/// - `AUTO_ID` → false（空の id は 400）
+/// - `HEALTH_FLAP_RATE` → 既定 0.0（`CHAOS_HEALTH` が有効な場合のみ作用）
+/// - `TRACE_TASK_IDS` → 空（カンマ区切りの id のリスト）
+/// - `PAYLOAD_SIZE_BYTES` → 0（本文の最小サイズなし）
+/// - `RESPONSE_SIZE_ALIGNMENT` → 0（揃えない）
+/// - `CONFIG_READ_DELAY_MS` → 0（`DEBUG_ENDPOINTS` が有効な場合のみ作用）
+/// - `DOWNSTREAM_TARGETS` → 空（`url=weight` のカンマ区切り。空なら `downstream_url` を使う）
+/// - `DOWNSTREAM_FANOUT` → 1（1 リクエストにつき 1 回呼び出す）
+/// - `FANOUT_QUORUM` → 0（すべての呼び出しの成功を待つ）
+/// - `HEALTH_CACHE_MS` → 0（毎回計算する）
+/// - `SCHEMA_DRIFT_RATE` → 既定 0.0（`CHAOS_TRANSPORT` が有効な場合のみ作用）
+/// - `MAX_BATCH_SIZE` → 1000（`POST /task/batch` の 1 回の要素数の上限。`MAX_BATCH_SIZE_LIMIT` まで）
+///
+/// 範囲外の値は黙って切り詰められる。`STRICT_CONFIG` を有効にすると、`main` は代わりに
+/// `strict_config_errors` で問題のある変数をすべてログに出し、非ゼロで終了する。
+///
+/// # Examples
+///
+/// ```
+/// use std::env;
+/// // 環境変数が未設定の場合はデフォルトが使われる
+/// env::remove_var("MAX_CONCURRENT_REQUESTS");
+/// env::remove_var("RESPONSE_DELAY_MS");
+/// env::remove_var("FAILURE_RATE");
+/// env::remove_var("QUEUE_SIZE");
+///
+/// let cfg = load_config();
+/// assert_eq!(cfg.max_concurrent_requests, 10);
+/// assert_eq!(cfg.response_delay_ms, 100);
+/// assert_eq!(cfg.failure_rate, 0.0);
+/// assert_eq!(cfg.queue_size, 50);
+/// assert_eq!(cfg.canary_fraction, 0.0);
+/// assert_eq!(cfg.base_jitter_ms, 0);
+/// assert!(!cfg.echo_config);
+/// assert_eq!(cfg.min_inter_response_ms, 0);
+/// ```
+fn load_config() -> Configuration {
+    let max_concurrent = get_env_i32("MAX_CONCURRENT_REQUESTS", 10).max(1);
+    let response_delay = get_env_i32("RESPONSE_DELAY_MS", 100).max(0);
+    let failure_rate = get_env_f64("FAILURE_RATE", 0.0).clamp(0.0, 1.0);
+    let queue_size = clamp_queue_size(get_env_i32("QUEUE_SIZE", 50).max(1));
+    let canary_fraction = get_env_f64("CANARY_FRACTION", 0.0).clamp(0.0, 1.0);
+    let base_jitter_ms = get_env_i32("BASE_JITTER_MS", 0).max(0);
+    let echo_config = get_env_bool("ECHO_CONFIG", false);
+    let min_inter_response_ms = get_env_i32("MIN_INTER_RESPONSE_MS", 0).max(0);
+    let shadow_enabled = get_env_bool("SHADOW_ENABLED", false);
+    let shadow_response_delay_ms = get_env_i32("SHADOW_RESPONSE_DELAY_MS", response_delay).max(0);
+    let shadow_failure_rate = get_env_f64("SHADOW_FAILURE_RATE", failure_rate).clamp(0.0, 1.0);
+    let rate_limit_rps = get_env_f64("RATE_LIMIT_RPS", 0.0).max(0.0);
+    let rate_limit_burst = get_env_i32("RATE_LIMIT_BURST", 0).max(0);
+    let latency_budget_ms = get_env_i32("LATENCY_BUDGET_MS", 0).max(0);
+    let trickle_bytes_per_sec = get_env_i32("TRICKLE_BYTES_PER_SEC", 0).max(0);
+    let stale_timestamp_rate = get_env_f64("STALE_TIMESTAMP_RATE", 0.0).clamp(0.0, 1.0);
+    let accept_id_pattern = env::var("ACCEPT_ID_PATTERN").unwrap_or_default();
+    let admission_strategy = env::var("ADMISSION_STRATEGY")
+        .ok()
+        .filter(|v| is_admission_strategy(v))
+        .unwrap_or_else(|| "default".to_string());
+    let problem_json = get_env_bool("PROBLEM_JSON", false);
+    let downstream_url = env::var("DOWNSTREAM_URL").unwrap_or_default();
+    let downstream_connect_delay_ms = get_env_i32("DOWNSTREAM_CONNECT_DELAY_MS", 0).max(0);
+    let success_color = env::var("SUCCESS_COLOR").unwrap_or_default();
+    let degraded_color = env::var("DEGRADED_COLOR").unwrap_or_default();
+    let failure_color = env::var("FAILURE_COLOR").unwrap_or_default();
+    let outage_probability = get_env_f64("OUTAGE_PROBABILITY", 0.0).clamp(0.0, 1.0);
+    let outage_duration_ms = get_env_i32("OUTAGE_DURATION_MS", 0).max(0);
+    let failure_scales_with_weight = get_env_bool("FAILURE_SCALES_WITH_WEIGHT", false);
+    let unfair_prefix = env::var("UNFAIR_PREFIX").unwrap_or_default();
+    let internal_retries = get_env_i32("INTERNAL_RETRIES", 0).max(0);
+    let broken_content_length_rate = get_env_f64("BROKEN_CONTENT_LENGTH_RATE", 0.0).clamp(0.0, 1.0);
+    let leak_rate_rps = get_env_f64("LEAK_RATE_RPS", 0.0).max(0.0);
+    let health_load_weight = get_env_f64("HEALTH_LOAD_WEIGHT", 1.0).max(0.0);
+    let health_queue_weight = get_env_f64("HEALTH_QUEUE_WEIGHT", 1.0).max(0.0);
+    let health_score_mode = env::var("HEALTH_SCORE_MODE")
+        .ok()
+        .filter(|v| HEALTH_SCORE_MODES.contains(&v.as_str()))
+        .unwrap_or_else(|| "max".to_string());
+    let replica_lag_ms = get_env_i32("REPLICA_LAG_MS", 0).max(0);
+    let replica_lag_jitter_ms = get_env_i32("REPLICA_LAG_JITTER_MS", 0).max(0);
+    let per_id_metrics = get_env_bool("PER_ID_METRICS", false);
+    let failure_takes_precedence = get_env_bool("FAILURE_TAKES_PRECEDENCE", false);
+    let success_status_code = Some(get_env_i32("SUCCESS_STATUS_CODE", 200))
+        .filter(|v| SUCCESS_STATUS_CODES.contains(v))
+        .unwrap_or(200);
+    let accepted_location = get_env_bool("ACCEPTED_LOCATION", false);
+    let leak_bytes_per_request = get_env_i32("LEAK_BYTES_PER_REQUEST", 0).max(0);
+    let accept_timeout_ms = get_env_i32("ACCEPT_TIMEOUT_MS", 0).max(0);
+    let dependency_timeout_ms = get_env_i32("DEPENDENCY_TIMEOUT_MS", 5000).max(0);
+    let truncate_response_rate = get_env_f64("TRUNCATE_RESPONSE_RATE", 0.0).clamp(0.0, 1.0);
+    let diurnal_period_ms = get_env_i32("DIURNAL_PERIOD_MS", 600_000).max(1);
+    let diurnal_amplitude = get_env_f64("DIURNAL_AMPLITUDE", 0.0).clamp(0.0, 1.0);
+    let include_queue_position = get_env_bool("INCLUDE_QUEUE_POSITION", false);
+    let single_flight = get_env_bool("SINGLE_FLIGHT", false);
+    let latency_table = env::var("LATENCY_TABLE")
+        .ok()
+        .and_then(|raw| parse_latency_table(&raw))
+        .filter(|table| valid_latency_table(table))
+        .unwrap_or_default();
+    let task_steps = get_env_bool("TASK_STEPS", false);
+    let processing_timeout_ms = get_env_i32("PROCESSING_TIMEOUT_MS", 0).max(0);
+    let stream_trailers = get_env_bool("STREAM_TRAILERS", false);
+    let auto_id = get_env_bool("AUTO_ID", false);
+    let health_flap_rate = get_env_f64("HEALTH_FLAP_RATE", 0.0).clamp(0.0, 1.0);
+    let trace_task_ids = env::var("TRACE_TASK_IDS")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .map(String::from)
+        .collect::<Vec<_>>();
+    let payload_size_bytes = get_env_i32("PAYLOAD_SIZE_BYTES", 0).clamp(0, MAX_RESPONSE_SIZE_BYTES);
+    let response_size_alignment =
+        get_env_i32("RESPONSE_SIZE_ALIGNMENT", 0).clamp(0, MAX_RESPONSE_SIZE_BYTES);
+    let config_read_delay_ms = get_env_i32("CONFIG_READ_DELAY_MS", 0).max(0);
+    let downstream_targets = env::var("DOWNSTREAM_TARGETS")
+        .ok()
+        .and_then(|raw| parse_downstream_targets(&raw))
+        .filter(|targets| valid_downstream_targets(targets))
+        .unwrap_or_default();
+    let downstream_fanout = get_env_i32("DOWNSTREAM_FANOUT", 1).clamp(1, MAX_DOWNSTREAM_FANOUT);
+    let fanout_quorum = get_env_i32("FANOUT_QUORUM", 0).max(0);
+    let health_cache_ms = get_env_i32("HEALTH_CACHE_MS", 0).max(0);
+    let schema_drift_rate = get_env_f64("SCHEMA_DRIFT_RATE", 0.0).clamp(0.0, 1.0);
+    let max_batch_size = get_env_i32("MAX_BATCH_SIZE", 1000).clamp(1, MAX_BATCH_SIZE_LIMIT);
+
+    Configuration {
+        max_concurrent_requests: max_concurrent,
+        response_delay_ms: response_delay,
+        failure_rate,
+        queue_size,
+        canary_fraction,
+        base_jitter_ms,
+        echo_config,
+        min_inter_response_ms,
+        shadow_enabled,
+        shadow_response_delay_ms,
+        shadow_failure_rate,
+        rate_limit_rps,
+        rate_limit_burst,
+        latency_budget_ms,
+        trickle_bytes_per_sec,
+        stale_timestamp_rate,
+        accept_id_pattern,
+        admission_strategy,
+        problem_json,
+        downstream_url,
+        downstream_connect_delay_ms,
+        success_color,
+        degraded_color,
+        failure_color,
+        outage_probability,
+        outage_duration_ms,
+        failure_scales_with_weight,
+        unfair_prefix,
+        internal_retries,
+        broken_content_length_rate,
+        leak_rate_rps,
+        health_load_weight,
+        health_queue_weight,
+        health_score_mode,
+        replica_lag_ms,
+        replica_lag_jitter_ms,
+        per_id_metrics,
+        failure_takes_precedence,
+        success_status_code,
+        accepted_location,
+        leak_bytes_per_request,
+        accept_timeout_ms,
+        dependency_timeout_ms,
+        truncate_response_rate,
+        diurnal_period_ms,
+        diurnal_amplitude,
+        include_queue_position,
+        single_flight,
+        latency_table,
+        task_steps,
+        processing_timeout_ms,
+        stream_trailers,
+        auto_id,
+        health_flap_rate,
+        trace_task_ids,
+        payload_size_bytes,
+        response_size_alignment,
+        config_read_delay_ms,
+        downstream_targets,
+        downstream_fanout,
+        fanout_quorum,
+        health_cache_ms,
+        schema_drift_rate,
+        max_batch_size,
+    }
+}
+
+/// 設定項目の環境変数 1 つ分の解決結果。`STRICT_CONFIG` の検査と `GET /debug/env` で使う。
+#[derive(Debug, Serialize)]
+struct EnvResolution {
+    name: String,
+    /// 読み取った生の値。未設定なら `null`、秘密情報なら `[redacted]`。
+    raw: Option<String>,
+    /// 値を型どおりに解釈できたか。未設定なら `null`。
+    parsed: Option<bool>,
+    /// 範囲内の値としてそのまま採用されたか。未設定または解釈できなければ `null`。
+    accepted: Option<bool>,
+    /// 既定値や切り詰めを適用した後の値。設定項目でない変数では `null`。
+    effective: serde_json::Value,
+}
+
+/// 設定項目以外で起動時に読み取る環境変数。`GET /debug/env` では生の値のみを示す。
+const STARTUP_ENV_VARS: &[&str] = &[
+    "PORT",
+    "WORKER_NAME",
+    "WORKER_COLOR",
+    "WORKER_VERSION",
+    "CANARY_VERSION",
+    "DELAY_SPREAD_PCT",
+    "STRICT_CONFIG",
+    "CHAOS_TRANSPORT",
+    "CHAOS_HEALTH",
+    "STICKY_SESSIONS",
+    "STICKY_COOKIE_NAME",
+    "STICKY_COOKIE_TTL_SECS",
+    "MAX_PARSE_MS",
+    "STATE_FILE",
+    "SIMULATE_LEAK",
+    "CONFIG_CHANGE_WEBHOOK",
+    "PUSHGATEWAY_URL",
+    "TASK_PROFILES",
+    "HARDWARE_CLASSES",
+    "TENANT_QUOTAS",
+    "TENANT_DEFAULT_QUOTA",
+    "METRIC_PREFIX",
+    "TASK_WEIGHT_BUCKETS",
+    "CONCURRENT_LOAD_BUCKETS",
+    "DEBUG_ENDPOINTS",
+    "DISABLED_ENDPOINTS",
+    "LOG_SAMPLE_RATE",
+    "RESPONSE_HEADERS",
+    "DOWNSTREAM_POOLING",
+    "WARMUP_TASK_ID",
+    "PRE_STOP_DELAY_MS",
+    "RECORD_EVENTS",
+    "MAX_LIFETIME_REQUESTS",
+    "ADMIN_TOKEN",
+    "JWT_SECRET",
+    "JWT_TENANT_CLAIM",
+    "MAX_CONNECTIONS",
+    "UNIX_SOCKET_PATH",
+];
+
+/// 値を表示してはならない環境変数。
+const SECRET_ENV_VARS: &[&str] = &["ADMIN_TOKEN", "JWT_SECRET"];
+
+/// 各設定項目の環境変数について、生の値・解釈の可否・採用の可否・実効値を求める。
+///
+/// 環境変数名はフィールド名を大文字にしたもの。範囲の判定は `PATCH /config` と同じ条件
+/// （`rejected_fields`）で行う。`config` には `load_config` の結果を渡す。
+fn resolve_config_env(config: &Configuration) -> Vec<EnvResolution> {
+    let loaded = serde_json::to_value(config).unwrap_or_default();
+    let mut resolutions = Vec::new();
+    let mut provided = serde_json::Map::new();
+    for (field, current) in loaded.as_object().into_iter().flatten() {
+        let name = field.to_ascii_uppercase();
+        let raw = env::var(&name).ok();
+        let parsed = raw.as_deref().map(|raw| match current {
+            serde_json::Value::Bool(_) => parse_bool(raw).map(serde_json::Value::from),
+            serde_json::Value::Number(n) if n.is_i64() => {
+                raw.trim().parse::<i32>().ok().map(serde_json::Value::from)
+            }
+            serde_json::Value::Number(_) => raw
+                .trim()
+                .parse::<f64>()
+                .ok()
+                .filter(|v| v.is_finite())
+                .map(serde_json::Value::from),
+            serde_json::Value::Array(_) => match field.as_str() {
+                "latency_table" => {
+                    parse_latency_table(raw).and_then(|table| serde_json::to_value(table).ok())
+                }
+                "downstream_targets" => parse_downstream_targets(raw)
+                    .and_then(|targets| serde_json::to_value(targets).ok()),
+                _ => Some(serde_json::Value::from(
+                    raw.split(',')
+                        .map(str::trim)
+                        .filter(|v| !v.is_empty())
+                        .collect::<Vec<_>>(),
+                )),
+            },
+            _ => Some(serde_json::Value::from(raw)),
+        });
+        if let Some(Some(value)) = &parsed {
+            provided.insert(field.clone(), value.clone());
+        }
+        resolutions.push(EnvResolution {
+            name,
+            raw,
+            parsed: parsed.as_ref().map(Option::is_some),
+            accepted: parsed.as_ref().and_then(|p| p.as_ref().map(|_| true)),
+            effective: current.clone(),
+        });
+    }
+
+    let rejected = serde_json::from_value::<ConfigUpdate>(provided.into())
+        .map(|update| rejected_fields(config, &update))
+        .unwrap_or_default();
+    for resolution in &mut resolutions {
+        if rejected.contains(&resolution.name.to_ascii_lowercase()) {
+            resolution.accepted = Some(false);
+        }
+    }
+    resolutions
+}
+
+/// `STRICT_CONFIG` 用に、`load_config` が黙ってデフォルト値に戻したり切り詰めたりした環境変数を列挙する。
+///
+/// 値を解釈できない場合と、`PATCH /config` と同じ条件で範囲外と判定される場合を
+/// それぞれエラーメッセージとして返す。`config` には `load_config` の結果を渡す。
+fn strict_config_errors(config: &Configuration) -> Vec<String> {
+    resolve_config_env(config)
+        .into_iter()
+        .filter_map(|r| match (r.parsed, r.accepted) {
+            (Some(false), _) => Some(format!("{}={:?} could not be parsed", r.name, r.raw?)),
+            (_, Some(false)) => Some(format!("{}={:?} is out of range", r.name, r.raw?)),
+            _ => None,
+        })
+        .collect()
+}
+
+/// 設定に関わる環境変数がどう解決されたかを一覧で返すデバッグ用ハンドラ。
+///
+/// 設定項目の変数は `load_config` による解決結果を、それ以外の起動時の変数は生の値のみを返す。
+/// `ADMIN_TOKEN` と `JWT_SECRET` の値は伏せる。`DEBUG_ENDPOINTS` が有効な場合のみルーティングされ、管理者認証が必要。
+async fn handle_debug_env(State(state): State<Arc<AppState>>, headers: HeaderMap) -> Response {
+    if let Some(resp) = state.reject_unauthorized_admin(&headers) {
+        return resp;
+    }
+    Json(debug_env_resolutions()).into_response()
+}
+
+/// `GET /debug/env` の本文。秘密情報を伏せた全環境変数の解決結果。
+fn debug_env_resolutions() -> Vec<EnvResolution> {
+    let mut resolutions = resolve_config_env(&load_config());
+    resolutions.extend(STARTUP_ENV_VARS.iter().map(|name| EnvResolution {
+        name: name.to_string(),
+        raw: env::var(name).ok(),
+        parsed: None,
+        accepted: None,
+        effective: serde_json::Value::Null,
+    }));
+    for resolution in &mut resolutions {
+        if SECRET_ENV_VARS.contains(&resolution.name.as_str()) && resolution.raw.is_some() {
+            resolution.raw = Some("[redacted]".to_string());
+        }
+    }
+    resolutions
+}
+
+/// 起動時に一度だけ `response_delay_ms` を `±spread_pct` % の範囲でランダムにずらし、選んだ割合を返す。
+///
+/// 同じ設定のワーカーを多数並べたときに全員が同一のレイテンシになる不自然さを避けるためのもの。
+/// リクエストごとに変わるジッターとは異なり、そのワーカーの存続期間中は同じオフセットが使われる。
+fn apply_delay_spread(config: &mut Configuration, spread_pct: f64) -> f64 {
+    let offset = rand::thread_rng().gen_range(-spread_pct..=spread_pct);
+    let delay = config.response_delay_ms as f64 * (1.0 + offset / 100.0);
+    config.response_delay_ms = delay.round().max(0.0) as i32;
+    offset
+}
+
+/// `TASK_PROFILES` 環境変数からタスクプロファイルを読み込む。
+///
+/// 書式は `name:max_concurrent` をカンマで区切ったもの（例: `heavy:2,light:8`）。
+/// 解析できないエントリや上限が 0 のエントリは警告を出して無視する。未設定なら空のマップを返す。
+///
+/// # Examples
+///
+/// ```
+/// std::env::set_var("TASK_PROFILES", "heavy:2, light:8, broken");
+/// let profiles = load_task_profiles();
+/// assert_eq!(profiles["heavy"].max_concurrent, 2);
+/// assert_eq!(profiles["light"].max_concurrent, 8);
+/// assert!(!profiles.contains_key("broken"));
+/// ```
+fn load_task_profiles() -> HashMap<String, TaskProfile> {
+    let raw = env::var("TASK_PROFILES").unwrap_or_default();
+    parse_task_profiles(&raw)
+}
+
+fn parse_task_profiles(raw: &str) -> HashMap<String, TaskProfile> {
+    let mut profiles = HashMap::new();
+    for entry in raw.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let parsed = entry
+            .split_once(':')
+            .and_then(|(name, limit)| Some((name.trim(), limit.trim().parse::<usize>().ok()?)))
+            .filter(|(name, limit)| !name.is_empty() && *limit > 0);
+        match parsed {
+            Some((name, max_concurrent)) => {
+                profiles.insert(
+                    name.to_string(),
+                    TaskProfile {
+                        max_concurrent,
+                        semaphore: Semaphore::new(max_concurrent),
+                    },
+                );
+            }
+            None => tracing::warn!("Ignoring invalid TASK_PROFILES entry: {:?}", entry),
+        }
+    }
+    profiles
+}
+
+/// `TENANT_QUOTAS` 環境変数の値（`tenant:max_concurrent` のカンマ区切り。例: `acme:2,globex:8`）を読み込む。
+///
+/// 解析できないエントリや上限が 0 のエントリは警告を出して無視する。
+fn parse_tenant_quotas(raw: &str) -> HashMap<String, usize> {
+    let mut quotas = HashMap::new();
+    for entry in raw.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let parsed = entry
+            .split_once(':')
+            .and_then(|(name, limit)| Some((name.trim(), limit.trim().parse::<usize>().ok()?)))
+            .filter(|(name, limit)| !name.is_empty() && *limit > 0);
+        match parsed {
+            Some((name, limit)) => {
+                quotas.insert(name.to_string(), limit);
+            }
+            None => tracing::warn!("Ignoring invalid TENANT_QUOTAS entry: {:?}", entry),
+        }
+    }
+    quotas
+}
+
+/// `HARDWARE_CLASSES` 環境変数の値からハードウェアクラスを読み込む。
+///
+/// 書式は `color:weight:latency_multiplier` をカンマで区切ったもの（例: `#22C55E:3:1.0,#EF4444:1:2.5`）。
+/// 解析できないエントリ、重みが正でないエントリ、倍率が負のエントリは警告を出して無視する。
+fn parse_hardware_classes(raw: &str) -> Vec<HardwareClass> {
+    let mut classes = Vec::new();
+    for entry in raw.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let parsed = entry
+            .rsplitn(3, ':')
+            .collect::<Vec<_>>()
+            .try_into()
+            .ok()
+            .and_then(|[multiplier, weight, color]: [&str; 3]| {
+                Some(HardwareClass {
+                    color: color.trim().to_string(),
+                    weight: weight.trim().parse::<f64>().ok()?,
+                    latency_multiplier: multiplier.trim().parse::<f64>().ok()?,
+                })
+            })
+            .filter(|c| {
+                !c.color.is_empty()
+                    && c.weight.is_finite()
+                    && c.weight > 0.0
+                    && c.latency_multiplier.is_finite()
+                    && c.latency_multiplier >= 0.0
+            });
+        match parsed {
+            Some(class) => classes.push(class),
+            None => tracing::warn!("Ignoring invalid HARDWARE_CLASSES entry: {:?}", entry),
+        }
+    }
+    classes
+}
+
+/// `LATENCY_TABLE` 環境変数の値（`delay_ms:weight` のカンマ区切り）を解釈する。解釈できないエントリがあれば `None`。
+fn parse_latency_table(raw: &str) -> Option<Vec<LatencyEntry>> {
+    raw.split(',')
+        .map(str::trim)
+        .filter(|e| !e.is_empty())
+        .map(|entry| {
+            let (delay, weight) = entry.split_once(':')?;
+            Some(LatencyEntry {
+                delay_ms: delay.trim().parse().ok()?,
+                weight: weight.trim().parse().ok()?,
+            })
+        })
+        .collect()
+}
+
+/// `latency_table` として使えるか。空か、全エントリの遅延と重みが非負で重みの合計が正であること。
+fn valid_latency_table(table: &[LatencyEntry]) -> bool {
+    table.is_empty()
+        || (table
+            .iter()
+            .all(|e| e.delay_ms >= 0 && e.weight.is_finite() && e.weight >= 0.0)
+            && table.iter().map(|e| e.weight).sum::<f64>() > 0.0)
+}
+
+/// `DOWNSTREAM_TARGETS` の `url=weight` をカンマ区切りにした書式を解釈する。URL 側の `=` を許すため最後の `=` で分ける。
+fn parse_downstream_targets(raw: &str) -> Option<Vec<DownstreamTarget>> {
+    raw.split(',')
+        .map(str::trim)
+        .filter(|e| !e.is_empty())
+        .map(|entry| {
+            let (url, weight) = entry.rsplit_once('=')?;
+            Some(DownstreamTarget {
+                url: url.trim().to_string(),
+                weight: weight.trim().parse().ok()?,
+            })
+        })
+        .collect()
+}
+
+/// `downstream_targets` として使えるか。空か、全エントリの URL が空でなく重みが非負で、重みの合計が正であること。
+fn valid_downstream_targets(targets: &[DownstreamTarget]) -> bool {
+    targets.is_empty()
+        || (targets
+            .iter()
+            .all(|t| !t.url.is_empty() && t.weight.is_finite() && t.weight >= 0.0)
+            && targets.iter().map(|t| t.weight).sum::<f64>() > 0.0)
+}
+
+/// `RESPONSE_HEADERS` 環境変数から、すべてのレスポンスに付与するヘッダーを読み込む。
+///
+/// 書式は `Name: Value` を改行で区切ったもの（例: `$'Cache-Control: no-store\nX-Env: test'`）。
+/// 値にはカンマやセミコロンを含められる。名前や値として不正なエントリは警告を出して無視する。
+fn load_response_headers() -> HeaderMap {
+    let raw = env::var("RESPONSE_HEADERS").unwrap_or_default();
+    parse_response_headers(&raw)
+}
+
+fn parse_response_headers(raw: &str) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    for entry in raw.lines().map(str::trim).filter(|e| !e.is_empty()) {
+        let parsed = entry.split_once(':').and_then(|(name, value)| {
+            let name = HeaderName::from_bytes(name.trim().as_bytes()).ok()?;
+            let value = HeaderValue::from_str(value.trim()).ok()?;
+            Some((name, value))
+        });
+        match parsed {
+            Some((name, value)) => {
+                headers.append(name, value);
+            }
+            None => tracing::warn!("Ignoring invalid RESPONSE_HEADERS entry: {:?}", entry),
+        }
+    }
+    headers
+}
+
+/// 環境変数からヒストグラムのバケット境界をカンマ区切りで読み取る。
+///
+/// 値は昇順に並べ替えられる。未設定、空、または数値として解析できない要素が含まれる場合は `default` を返す。
+///
+/// # Examples
+///
+/// ```
+/// std::env::set_var("TEST_BUCKETS", "4, 1,2");
+/// assert_eq!(get_env_buckets("TEST_BUCKETS", &[10.0]), vec![1.0, 2.0, 4.0]);
+/// std::env::set_var("TEST_BUCKETS", "1,x");
+/// assert_eq!(get_env_buckets("TEST_BUCKETS", &[10.0]), vec![10.0]);
+/// ```
+fn get_env_buckets(key: &str, default: &[f64]) -> Vec<f64> {
+    let parsed: Option<Vec<f64>> = env::var(key).ok().and_then(|raw| {
+        raw.split(',')
+            .map(|v| v.trim().parse::<f64>().ok().filter(|b| b.is_finite()))
+            .collect()
+    });
+    match parsed {
+        Some(mut buckets) if !buckets.is_empty() => {
+            buckets.sort_by(f64::total_cmp);
+            buckets.dedup();
+            buckets
+        }
+        _ => default.to_vec(),
+    }
+}
+
+/// `queue_size` を `MAX_QUEUE_SIZE` 以下に切り詰め、切り詰めた場合は警告を記録する。
+fn clamp_queue_size(queue_size: i32) -> i32 {
+    if queue_size > MAX_QUEUE_SIZE {
+        tracing::warn!(
+            "queue_size {} exceeds the maximum of {}; clamping",
+            queue_size,
+            MAX_QUEUE_SIZE
+        );
+        MAX_QUEUE_SIZE
+    } else {
+        queue_size
+    }
+}
+
+/// Prometheus メトリクスを初期化してカスタムヒストグラムバケットを設定し、レンダリング用のハンドルを返す。
+///
+/// この関数はサービスで使用するメトリクスレコーダーをインストールし、
+/// リクエスト処理時間を収集する `worker_request_duration_ms` メトリクスと、
+/// タスクの重みを収集する `worker_task_weight` メトリクス（`TASK_WEIGHT_BUCKETS` で上書き可能）、
+/// 到着時点の同時実行数を収集する `worker_concurrent_load` メトリクス（`CONCURRENT_LOAD_BUCKETS` で上書き可能）、
+/// 拒否時点のキュー使用率を収集する `worker_rejection_queue_ratio` メトリクスに対して
+/// カスタムバケットを設定してからハンドルを返します。
+/// `prefix` が既定の `worker` 以外なら、すべてのメトリクス名の `worker_` をその接頭辞に置き換える `PrefixedRecorder` を挟みます。
+///
+/// # Returns
+///
+/// `PrometheusHandle` — メトリクスをレンダリング・取得するためのハンドル。
+///
+/// # Examples
+///
+/// ```
+/// let handle = setup_metrics(DEFAULT_METRIC_PREFIX);
+/// let output = handle.render();
+/// assert!(output.contains("worker_request_duration_ms"));
+/// ```
+fn setup_metrics(prefix: &str) -> PrometheusHandle {
+    let recorder = PrometheusBuilder::new()
+        .set_buckets_for_metric(
+            Matcher::Full(format!("{prefix}_request_duration_ms")),
+            &[1.0, 2.0, 4.0, 8.0, 16.0, 32.0, 64.0, 128.0, 256.0, 512.0],
+        )
+        .unwrap()
+        .set_buckets_for_metric(
+            Matcher::Full(format!("{prefix}_task_weight")),
+            &get_env_buckets(
+                "TASK_WEIGHT_BUCKETS",
+                &[0.1, 0.25, 0.5, 1.0, 2.0, 4.0, 8.0, 16.0],
+            ),
+        )
+        .unwrap()
+        .set_buckets_for_metric(
+            Matcher::Full(format!("{prefix}_concurrent_load")),
+            &get_env_buckets(
+                "CONCURRENT_LOAD_BUCKETS",
+                &[0.0, 1.0, 2.0, 4.0, 8.0, 16.0, 32.0, 64.0, 128.0],
+            ),
+        )
+        .unwrap()
+        .set_buckets_for_metric(
+            Matcher::Full(format!("{prefix}_rejection_queue_ratio")),
+            &[0.1, 0.25, 0.5, 0.75, 0.9, 0.95, 1.0],
+        )
+        .unwrap()
+        .build_recorder();
+    let handle = recorder.handle();
+    if prefix == DEFAULT_METRIC_PREFIX {
+        metrics::set_global_recorder(recorder).unwrap();
+    } else {
+        metrics::set_global_recorder(PrefixedRecorder::new(prefix, recorder)).unwrap();
+    }
+    handle
+}
+
+/// メトリクス名の先頭の `worker_` を `METRIC_PREFIX` の接頭辞に置き換えてから内側のレコーダーへ渡すレコーダー。
+///
+/// 各メトリクスは `worker_` で始まる名前で記録しているため、名前の組み立てを呼び出し側に散らさずに済む。
+struct PrefixedRecorder<R> {
+    prefix: String,
+    inner: R,
+}
+
+impl<R> PrefixedRecorder<R> {
+    fn new(prefix: &str, inner: R) -> Self {
+        Self {
+            prefix: prefix.to_string(),
+            inner,
+        }
+    }
+
+    fn rename(&self, name: &str) -> String {
+        match name.strip_prefix("worker_") {
+            Some(rest) => format!("{}_{}", self.prefix, rest),
+            None => name.to_string(),
+        }
+    }
+
+    fn key(&self, key: &Key) -> Key {
+        Key::from_parts(
+            self.rename(key.name()),
+            key.labels().cloned().collect::<Vec<_>>(),
+        )
+    }
+}
+
+impl<R: Recorder> Recorder for PrefixedRecorder<R> {
+    fn describe_counter(&self, key: KeyName, unit: Option<Unit>, description: SharedString) {
+        let key = KeyName::from(self.rename(key.as_str()));
+        self.inner.describe_counter(key, unit, description);
+    }
+
+    fn describe_gauge(&self, key: KeyName, unit: Option<Unit>, description: SharedString) {
+        let key = KeyName::from(self.rename(key.as_str()));
+        self.inner.describe_gauge(key, unit, description);
+    }
+
+    fn describe_histogram(&self, key: KeyName, unit: Option<Unit>, description: SharedString) {
+        let key = KeyName::from(self.rename(key.as_str()));
+        self.inner.describe_histogram(key, unit, description);
+    }
+
+    fn register_counter(&self, key: &Key, metadata: &Metadata<'_>) -> metrics::Counter {
+        self.inner.register_counter(&self.key(key), metadata)
+    }
+
+    fn register_gauge(&self, key: &Key, metadata: &Metadata<'_>) -> metrics::Gauge {
+        self.inner.register_gauge(&self.key(key), metadata)
+    }
+
+    fn register_histogram(&self, key: &Key, metadata: &Metadata<'_>) -> metrics::Histogram {
+        self.inner.register_histogram(&self.key(key), metadata)
+    }
+}
+
+/// `METRIC_PREFIX` として使える値か。Prometheus のメトリクス名の一部として有効な英数字と `_` のみを許す。
+fn valid_metric_prefix(prefix: &str) -> bool {
+    let mut chars = prefix.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// `TaskRequest.steps` を順に実行し、失敗したかどうかを返す。
+///
+/// 結果が事前指定されている場合は `fail` の手順で抽選せず、手順をすべて実行した上でその結果にする。
+/// `processing_timeout_ms` などで途中で捨てられた場合は、実行中の `cpu` の手順もそこで止める。
+async fn run_task_steps(steps: &[TaskStep], forced: Option<ForcedOutcome>) -> bool {
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let _cancel_on_drop = CancelOnDrop(Arc::clone(&cancelled));
+    for step in steps {
+        match *step {
+            TaskStep::Sleep(ms) => sleep(Duration::from_millis(ms)).await,
+            TaskStep::Cpu(ms) => {
+                let busy = Duration::from_millis(ms);
+                let cancelled = Arc::clone(&cancelled);
+                // Spin off the async workers so other requests keep being served
+                let _ = tokio::task::spawn_blocking(move || {
+                    let started = Instant::now();
+                    while started.elapsed() < busy && !cancelled.load(Ordering::Relaxed) {
+                        std::hint::spin_loop();
+                    }
+                })
+                .await;
+            }
+            TaskStep::Fail(rate) if forced.is_none() => {
+                if rand::thread_rng().gen::<f64>() < rate {
+                    return true;
+                }
+            }
+            TaskStep::Fail(_) => {}
+        }
+    }
+    forced == Some(ForcedOutcome::Fail)
+}
+
+/// 捨てられた時点で旗を立てる。ブロッキングスレッドで動く処理に、呼び出し元がもう待っていないことを伝える。
+struct CancelOnDrop(Arc<AtomicBool>);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+/// 受付前の待機 `wait` を `deadline` までで打ち切る。間に合わなければ `Rejection::AcceptTimeout` を返す。
+async fn wait_for_admission(
+    deadline: Option<Instant>,
+    wait: impl std::future::Future<Output = ()>,
+) -> Result<(), Rejection> {
+    match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline.into(), wait)
+            .await
+            .map_err(|_| Rejection::AcceptTimeout),
+        None => {
+            wait.await;
+            Ok(())
+        }
+    }
+}
+
+/// 1 回分の失敗判定。結果が事前指定されていればそれに従い、なければ `effective_failure_rate` で抽選する。
+fn roll_failure(config: &Configuration, forced: Option<ForcedOutcome>, weight: f64) -> bool {
+    match forced {
+        Some(ForcedOutcome::Fail) => true,
+        Some(ForcedOutcome::Success) => false,
+        _ => rand::thread_rng().gen::<f64>() < config.effective_failure_rate(weight),
+    }
+}
+
+/// 受付制御に拒否されたリクエストを、拒否の代わりに失敗（500）として返すかどうか。
+///
+/// 拒否は処理の前、失敗判定は処理の後に行われるため、既定では拒否が優先され失敗判定は行わない。
+/// `failure_takes_precedence` が有効な場合のみ、拒否されたリクエストにも（遅延なしで）失敗判定を行う。
+fn failure_overrides_rejection(
+    config: &Configuration,
+    forced: Option<ForcedOutcome>,
+    weight: f64,
+) -> bool {
+    config.failure_takes_precedence && roll_failure(config, forced, weight)
+}
+
+/// 処理時間をミリ秒の `i64` に変換する。表せない長さの場合は警告を出して `i64::MAX` にする。
+fn duration_millis(elapsed: Duration) -> i64 {
+    i64::try_from(elapsed.as_millis()).unwrap_or_else(|_| {
+        tracing::warn!(
+            "Processing time {:?} does not fit in i64 milliseconds; capping",
+            elapsed
+        );
+        i64::MAX
+    })
+}
+
+/// `base_ms × weight` に `0..=jitter_ms` の一様乱数を加えた処理遅延を求める。
+fn simulated_delay(base_ms: i32, weight: f64, jitter_ms: i32) -> Duration {
+    let mut delay_ms = (base_ms as f64 * weight) as u64;
+    if jitter_ms > 0 {
+        delay_ms += rand::thread_rng().gen_range(0..=jitter_ms as u64);
+    }
+    Duration::from_millis(delay_ms)
+}
+
+/// 下流の `url` へタスクを転送し、下流の応答を待つ。
+///
+/// 呼び出しは `worker_downstream_calls_total` に呼び出し先（`target`）と結果ごとに数える。
+/// 呼び出し前に `downstream_connect_delay_ms` だけ待って DNS 解決や接続確立のコストを再現し、
+/// その待ち時間は `worker_downstream_connect_delay_ms` に、実際の呼び出し時間は
+/// `worker_downstream_duration_ms` に別々に記録する。接続の再利用は `DOWNSTREAM_POOLING` で切り替える。
+/// 接続エラー・タイムアウト・エラーステータスは `Err` として返す。
+async fn call_downstream(
+    state: &AppState,
+    config: &Configuration,
+    url: &str,
+    task: &TaskRequest,
+) -> Result<(), String> {
+    if config.downstream_connect_delay_ms > 0 {
+        sleep(Duration::from_millis(
+            config.downstream_connect_delay_ms as u64,
+        ))
+        .await;
+        histogram!("worker_downstream_connect_delay_ms", "worker" => state.worker_name.clone())
+            .record(config.downstream_connect_delay_ms as f64);
+    }
+
+    let start = Instant::now();
+    let result = state
+        .downstream_client
+        .post(url)
+        .timeout(DOWNSTREAM_TIMEOUT)
+        .json(task)
+        .send()
+        .await
+        .and_then(|r| r.error_for_status());
+    let outcome = if result.is_ok() { "success" } else { "failed" };
+    counter!("worker_downstream_calls_total", "worker" => state.worker_name.clone(), "target" => url.to_string(), "outcome" => outcome)
+        .increment(1);
+    histogram!("worker_downstream_duration_ms", "worker" => state.worker_name.clone(), "outcome" => outcome)
+        .record(start.elapsed().as_millis() as f64);
+    result.map(|_| ()).map_err(|e| e.to_string())
+}
+
+/// `downstream_fanout` 回の下流呼び出しを並行して行い、`fanout_quorum` 件の成功を待つ。
+///
+/// 呼び出し先は 1 回ごとに `pick_downstream_url` で選ぶ。クォーラムが 0 か `downstream_fanout` 以上ならすべての成功を待つため、
+/// 所要時間は最も遅い呼び出しで決まる。クォーラムに達した時点、または失敗が重なって達しようがなくなった時点で
+/// 残りの呼び出しを打ち切る。結果は `worker_fanout_requests_total` に記録し、個々の呼び出しは
+/// `call_downstream` が記録する。下流がなければ何もせず `Ok` を返す。
+async fn fan_out_downstream(
+    state: &AppState,
+    config: &Configuration,
+    task: &TaskRequest,
+) -> Result<(), String> {
+    let urls: Vec<String> = (0..config.downstream_fanout.max(1))
+        .filter_map(|_| config.pick_downstream_url().map(String::from))
+        .collect();
+    if urls.len() <= 1 {
+        return match urls.first() {
+            Some(url) => call_downstream(state, config, url, task)
+                .await
+                .map_err(|e| format!("{}: {}", url, e)),
+            None => Ok(()),
+        };
+    }
+
+    let fanout = urls.len();
+    let quorum = match config.fanout_quorum as usize {
+        q if q > 0 && q < fanout => q,
+        _ => fanout,
+    };
+    let mut calls: FuturesUnordered<_> = urls
+        .iter()
+        .map(|url| async move { (url, call_downstream(state, config, url, task).await) })
+        .collect();
+    let (mut succeeded, mut failed) = (0, 0);
+    let mut last_error = None;
+    while let Some((url, result)) = calls.next().await {
+        match result {
+            Ok(()) => succeeded += 1,
+            Err(e) => {
+                failed += 1;
+                last_error = Some(format!("{}: {}", url, e));
+            }
+        }
+        if succeeded >= quorum || fanout - failed < quorum {
+            break;
+        }
+    }
+    let outcome = if succeeded >= quorum {
+        "success"
+    } else {
+        "failed"
+    };
+    counter!("worker_fanout_requests_total", "worker" => state.worker_name.clone(), "outcome" => outcome)
+        .increment(1);
+    if succeeded >= quorum {
+        Ok(())
+    } else {
+        Err(format!(
+            "{} of {} calls succeeded, {} required (last error from {})",
+            succeeded,
+            fanout,
+            quorum,
+            last_error.unwrap_or_default()
+        ))
+    }
+}
+
+/// シャドウ処理経路を切り離したタスクとして実行する。
+///
+/// `shadow_response_delay_ms` と `shadow_failure_rate` による別のシミュレーションモデルで同じリクエストを処理し、
+/// 所要時間と結果を `worker_shadow_request_duration_ms`・`worker_shadow_requests_total` にのみ記録する。
+/// 本来の `worker_request_duration_ms`・`worker_requests_total` とは名前を分け、既存のクエリに混ざらないようにする。
+/// 許可は消費せず、クライアントへの応答にも影響しないため、同一負荷の下で 2 つの設定を並べて比較できる。
+fn spawn_shadow(state: &Arc<AppState>, config: &Configuration, weight: f64) {
+    let worker = state.worker_name.clone();
+    let delay = simulated_delay(
+        config.shadow_response_delay_ms,
+        weight,
+        config.base_jitter_ms,
+    );
+    let failure_rate = config.shadow_failure_rate;
+    tokio::spawn(async move {
+        let start = Instant::now();
+        sleep(delay).await;
+        histogram!("worker_shadow_request_duration_ms", "worker" => worker.clone())
+            .record(start.elapsed().as_millis() as f64);
+        let status = if rand::thread_rng().gen::<f64>() < failure_rate {
+            "failed"
+        } else {
+            "success"
+        };
+        counter!("worker_shadow_requests_total", "worker" => worker, "status" => status)
+            .increment(1);
+    });
+}
+
+/// タスク要求を処理し、成功時は TaskResponse を、失敗時は ErrorResponse を返すハンドラ。
+///
+/// 必要に応じてキュー許可を取得して同時実行数を管理し、構成に基づく遅延をシミュレートし、
+/// プロセッシング時間やステータス（success/failed/rejected/overloaded）をプロメテウス用メトリクスに記録する。
+/// - `JWT_SECRET` が設定されている場合は、`Authorization: Bearer` の HS256 JWT を検証し、欠落・不正・期限切れなら
+///   401 を返す。`JWT_TENANT_CLAIM`（既定 `tenant`）のクレームを `worker_authenticated_requests_total` の `tenant` ラベルにする。
+/// - `SIMULATE_LEAK` が有効な場合は、リクエストごとに `leak_bytes_per_request` バイトを確保したまま解放しない
+///   （`worker_leaked_bytes` に反映。合計は `LEAK_CAP_BYTES` まで、`POST /reset` で解放）。
+/// - `STICKY_SESSIONS` が有効な場合は、すべての応答にこのワーカーを指すアフィニティ Cookie（`STICKY_COOKIE_NAME`、
+///   既定 `worker_affinity`、有効期限 `STICKY_COOKIE_TTL_SECS` 秒、既定 3600）を付け、リクエストの Cookie が
+///   このワーカーを指していたかを `X-Worker-Affinity: hit` / `miss` で示す。
+/// - `id` が空か空白のみの場合は 400 を返す（エラー "Missing task id"）。`auto_id` が有効な場合は拒否せず、
+///   UUID v4 形式のランダムな id を割り当てて処理し、その id をレスポンスやログ、メトリクスに使う。
+/// - `depends_on` が指定されている場合は、同じセッション（`X-Session-Id` ヘッダー、なければ共通）でその id のタスクが
+///   成功するまで処理を始めずに待つ。`dependency_timeout_ms` 以内に成功しなければ 424 を返す（エラー "Dependency … did not complete"）。
+/// - `WARMUP_TASK_ID` と一致する id は認証やテナント上限、キューを通さず即座に 200 を返す。ドレイン中や過負荷時でも
+///   拒否されず、`worker_requests_total` などの通常のメトリクスや `/report`、`MAX_LIFETIME_REQUESTS` の件数にも
+///   計上しない（`worker_warmup_requests_total` のみ）。
+/// - ドレイン中は 503 を返す（エラー "Worker draining"）。
+/// - `outage_probability` による擬似障害の最中は 503 を返す（エラー "Simulated outage"）。
+/// - `accept_id_pattern` が設定されていて id が一致しない場合は、許可を消費せずに 404 を返す（エラー "Not my shard"）。
+/// - `POST /pause` で一時停止されている間は、キューの枠を 1 つ確保した上で再開まで待機する
+///   （キューが満杯なら通常通り 503）。再開後は以下の受付判定を通常通り行う。
+/// - `leak_rate_rps` が正の場合は、リーキーバケットで到着のばらつきを均す。各リクエストはキューの枠を 1 つ確保して
+///   バッファに入り、`1 / leak_rate_rps` 間隔で順に以下の受付判定へ進む（バッファ溢れ、つまりキューが満杯なら 503）。
+///   トークンバケットと違いバーストは許さない。待機中の数は `worker_leaky_bucket_depth` に反映する。
+/// - 一時停止とリーキーバケットでの待機の合計が `accept_timeout_ms` を超えた場合は 408 を返す
+///   （エラー "Timed out waiting for admission"。満杯による 503 とは区別して `accept_timeout` として計上）。
+/// - 以下のレート制限・キュー・同時実行数の判定は `admission_strategy` で選ばれた `AdmissionController` が行う
+///   （既定の `DefaultAdmission` の挙動を記す）。`failure_takes_precedence` が有効な場合は、拒否されたリクエストにも
+///   その場で失敗判定を行い、失敗なら拒否の代わりに 500 を返す（`failure_overrides_rejection`）。
+/// - `rate_limit_rps` によるトークンバケットが空の場合は 429 を返す（エラー "Rate limit exceeded"）。
+///   `RateLimit-Limit`（バケット容量）、`RateLimit-Remaining`、`RateLimit-Reset`（満杯に戻るまでの秒数）、`Retry-After` ヘッダーを付与する。
+/// - キューが満杯の場合は 503 を返す（エラー "Queue full - service overloaded"）。
+/// - 同時実行上限を超えた場合は 503 を返す（エラーに現在数と上限を含む）。
+/// - `profile` で指定したタスクプロファイルの同時実行上限を超えた場合も 503 を返す。
+///   プロファイルの上限はワーカー全体の上限に加えて適用され、未指定・未定義のプロファイルでは全体の上限のみが使われる。
+/// - `processing_timeout_ms` が設定されていて、遅延（`weight` による伸びと内部リトライ、`steps` の `sleep`・`cpu` を含む）が
+///   それを超える場合は、その時間で処理を打ち切って 503 を返す（エラー "Processing timeout"。`processing_timeout` として計上）。
+///   時間切れの判定は 3 段階あり、受付前の待機は `accept_timeout_ms`（408）、受付後の遅延部分だけは `processing_timeout_ms`（503）、
+///   下流呼び出しや `min_inter_response_ms` の待機まで含めた処理時間は `latency_budget_ms`（504）で打ち切る。
+///   `processing_timeout_ms` で打ち切った場合は下流呼び出しなどの後続を行わないため、`latency_budget_ms` の判定は行われない。
+/// - `downstream_url` が設定されている場合は遅延の後に許可を保持したままタスクを下流へ転送し、
+///   下流の呼び出しが失敗した場合は 502 を返す（エラー "Downstream call failed"）。`downstream_targets` が空でなければ
+///   `downstream_url` の代わりに、リクエストごとに重みに従って選んだ 1 つの下流へ転送する。`downstream_fanout` が 2 以上なら
+///   その回数だけ並行して転送し（呼び出し先は 1 回ごとに選ぶ）、`fanout_quorum` 件（0 ですべて）の成功を待つ。
+///   クォーラムに届かなければ 502 を返す（`fan_out_downstream`）。
+/// - `latency_budget_ms` が設定されていて、遅延や待機を合計した処理時間がそれを超えた場合は、
+///   個々のステップが成功していても 504 を返す（エラー "Budget exceeded"）。
+/// - 設定された failure_rate によっては 500 を返す（エラー "Simulated failure"）。`internal_retries` が設定されている場合は、
+///   失敗のたびに遅延と失敗判定をやり直し、最大その回数まで内部で再試行してから最終結果を返す
+///   （成功時は `attempts` に試行回数を含め、再試行は `worker_internal_retries_total` に計上）。`failure_scales_with_weight` が
+///   有効な場合の確率は `failure_rate × weight`（1.0 で頭打ち）になる。
+/// - `CHAOS_TRANSPORT` が有効な場合に限り、成功時に `broken_content_length_rate` の確率で
+///   実際より長い `Content-Length` を付けて返す（`worker_broken_content_length_total` に計上）。
+///   同じく `truncate_response_rate` の確率で、フレーミングは正しいまま本文の JSON を途中で切って返す
+///   （トランスポートではなく JSON の解析で失敗させる。`worker_truncated_responses_total` に計上）。
+///   さらに `schema_drift_rate` の確率で、値ではなくスキーマの異なる `DriftedTaskResponse` を返す
+///   （互換性のないバージョンが混在したデプロイの再現。`worker_schema_drift_responses_total` に計上）。
+/// - 成功時は `success_status_code`（既定 200。201・202 も指定可）で TaskResponse を JSON で返す。`payload_size_bytes`・
+///   `response_size_alignment` が正の場合は、本文をその長さ以上・その倍数になるまで `padding` フィールドで水増しする。202 かつ `accepted_location` が
+///   有効な場合は、非同期処理の状態確認先を模した `Location: /task/{id}/status` を付ける。
+///   `color` は受付時のヘルス状態が `healthy` なら `success_color`、
+///   それ以外なら `degraded_color`（未設定なら `WORKER_COLOR`）。`HARDWARE_CLASSES` が定義されている場合は、
+///   リクエストごとに選んだクラスの色を返し、そのクラスの倍率を `response_delay_ms` に掛ける。エラー時は `failure_color` を `color` として含める。`stale_timestamp_rate` の確率で `timestamp` を現在時刻ではなく
+///   起動時に記録した古い時刻にする（キャッシュ層が古いデータを返した状況の再現。`worker_stale_responses_total` に計上）。
+///   `replica_lag_ms`（と `replica_lag_jitter_ms` による揺らぎ）が設定されている場合は、読み取り後の整合性を扱うクライアント向けに
+///   `replicaLagMs` を含める（情報のみで処理には影響しない）。`include_queue_position` が有効な場合は、許可を要求した時点で
+///   キュー（一時停止中やリーキーバケットで待機中のものを含む）にいた先行リクエストの数を `queuePosition` として含める。`trickle_bytes_per_sec` が設定されている場合は、
+///   本文をその速度で少しずつストリーミングする（処理時間には含まれず、クライアントの読み取りタイムアウトの検証用）。
+///   さらに `stream_trailers` が有効なら、本文の後に `x-processing-time-ms` と `x-status` を HTTP トレーラーとして送り、
+///   `Trailer` ヘッダーで予告する。このワーカーは HTTP/1.1 で応答するため、トレーラーはリクエストに `TE: trailers` を
+///   付けたクライアントにしか送られず、途中のプロキシで落とされることも多い。確実に受け取れるのは HTTP/2 で中継された場合に限られる。
+///
+/// エラー本文は通常 `ErrorResponse` だが、`problem_json` が有効か `Accept: application/problem+json` の場合は
+/// `problem_json_errors` により RFC 7807 形式に変換される（他のエンドポイントのエラーも同様）。
+///
+/// `POST /debug/force` で結果が事前指定されている場合は、設定や乱数に関わらずその結果になる
+/// （overload は即座に 503、fail と success は通常通り処理した上で結果のみ固定。キュー満杯などの実際の拒否は優先される）。
+/// `DEBUG_ENDPOINTS` が有効な場合は `?force=fail` や `?delay=500` でそのリクエストだけ結果や
+/// `response_delay_ms` を上書きでき、クエリでの指定は事前指定より優先される（事前指定は消費されない）。
+/// 同じく `DEBUG_ENDPOINTS` が有効な場合、本文の `force_error` を指定すると `?force=fail` と同様に通常通り処理した上で、
+/// 500 の `error` をそのメッセージにする（無効な場合は無視される）。
+/// `config_read_delay_ms` が正なら、設定を読む前にその間だけ待つ（`contend_config_read`）。
+///
+/// `echo_config` が設定またはクエリ（`?echo_config=true`）で有効な場合、成功レスポンスに
+/// 処理時点の `Configuration` のスナップショットを `config` として含める。
+///
+/// `diurnal_amplitude` が正の場合は、`response_delay_ms` と `failure_rate` を `diurnal_period_ms` 周期の正弦波で
+/// ±`diurnal_amplitude` の割合だけ変動させてから処理する（1 日の負荷の波の再現。設定値そのものは変わらない）。
+///
+/// `latency_table` が空でなければ、リクエストごとに重みに従って選んだエントリの `delay_ms` を `response_delay_ms` の代わりに使う。
+/// `task_steps` が有効で本文に `steps`（例: `[{"sleep": 50}, {"cpu": 20}, {"fail": 0.1}]`）がある場合は、
+/// 通常の遅延・失敗判定・内部リトライの代わりにその手順を順に実行する。`steps` が解釈できない、`fail` の確率が
+/// 0.0..=1.0 の外、`sleep`・`cpu` が `MAX_TASK_STEP_MS` を超える、手順が `MAX_TASK_STEPS` 個を超える場合は 400。
+/// 無効な場合の `steps` は中身を見ずに無視する。
+/// 遅延は `response_delay_ms × weight` に `0..=base_jitter_ms` の一様乱数を加えたもの。
+/// `shadow_enabled` が有効な場合は、受け付けたリクエストごとにシャドウ経路（`spawn_shadow`）も並行して実行する。
+/// `min_inter_response_ms` が正の場合は、さらに直前の応答からその間隔が空くまで許可を保持したまま待機する。
+///
+/// `TENANT_QUOTAS`・`TENANT_DEFAULT_QUOTA` が設定されている場合は、`X-Tenant` ヘッダー（なければ `default`）のテナントごとに
+/// ワーカー全体の上限とは別の同時実行上限を課し、超えたリクエストは 429 を返す（エラー "Tenant quota exceeded"）。
+/// `TENANT_QUOTAS` にないテナントはまとめて `other` として扱い、合わせて `TENANT_DEFAULT_QUOTA` 件まで（0 で無制限）とする。
+/// 処理中の数は `worker_tenant_in_flight` に反映する。
+///
+/// `single_flight` が有効な場合、同じ `id` のタスクが処理中ならそれに相乗りし、許可を消費せずに同じレスポンスを返す
+/// （`worker_coalesced_requests_total` で数える）。完了後に届いた同じ id のリクエストは改めて処理する。
+///
+/// `per_id_metrics` が有効な場合は `worker_request_duration_ms` に `id` ラベルを付ける。カーディナリティを抑えるため、
+/// ラベルにする id は最初の `PER_ID_METRICS_LIMIT` 件までで、以降の id は `other` にまとめる。
+///
+/// リクエストごとの完了ログは成功時には `LOG_SAMPLE_RATE` の割合だけ出力し、エラー（4xx/5xx）は常に出力する。
+/// `id` が `trace_task_ids` のいずれかと完全一致する場合は、サンプリングに関わらず受け取った `TaskRequest` と
+/// 返すレスポンス（ステータス・ヘッダー・本文）を info で出力する。本文を読み切ってから返すため、
+/// そのリクエストでは `trickle_bytes_per_sec` による分割送信は行われない。
+///
+/// 注意: 関数は State と Json の抽出済みパラメータを受け取り、キューと同時実行の各セマフォから許可を取得・解放する。処理中数やキュー深度はこれらのセマフォから導出される。
+///
+/// # Examples
+///
+/// ```
+/// use axum::Json;
+/// use axum::extract::State;
+/// use std::sync::Arc;
+///
+/// // テスト用に AppState を構築し、ルート経由で handle_task を呼び出すのが最も簡単な利用方法です。
+/// // ここでは概念例として、実際の構築手順は省略しています。
+///
+/// // let app_state = Arc::new(AppState::new_for_test());
+/// // let req = TaskRequest { id: "1".into(), weight: Some(1.0), profile: None, force_error: None, depends_on: None, steps: None };
+/// // let resp = handle_task(State(app_state), Query(TaskQuery::default()), HeaderMap::new(), Json(req)).await;
+/// ```
+async fn handle_task(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<TaskQuery>,
+    headers: HeaderMap,
+    Json(task): Json<TaskRequest>,
+) -> Response {
+    let auth = state.authenticate_task(&headers);
+    serve_task(&state, query, &headers, auth, task).await
+}
+
+/// `/task` と `/task/batch` の各要素に共通する 1 件分の処理。
+///
+/// `auth` は呼び出し側で済ませた `authenticate_task` の結果で、id の検証の後に評価する。
+/// ウォームアップの即答、テナント上限、ログ・/report・イベント・`MAX_LIFETIME_REQUESTS` への計上はここで行うため、
+/// どの経路から来たタスクも同じように数えられる。
+async fn serve_task(
+    state: &Arc<AppState>,
+    query: TaskQuery,
+    headers: &HeaderMap,
+    auth: Result<Option<String>, &'static str>,
+    mut task: TaskRequest,
+) -> Response {
+    state.contend_config_read().await;
+    if task.id.trim().is_empty() && state.config.read().auto_id {
+        task.id = random_task_id();
+    }
+    // Warmup probes are answered before auth, quotas and any of the per-request accounting
+    if state.warmup_task_id.as_deref() == Some(task.id.as_str()) {
+        counter!("worker_warmup_requests_total", "worker" => state.worker_name.clone())
+            .increment(1);
+        let version = state.worker_version.clone();
+        return Json(state.task_response(task.id, 0, version)).into_response();
+    }
+    let id = task.id.clone();
+    let traced = state.config.read().trace_task_ids.contains(&id);
+    if traced {
+        let request = serde_json::to_string(&task).unwrap_or_default();
+        tracing::info!(id, request, "traced task request");
+    }
+    let session = headers
+        .get(SESSION_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+    let start = Instant::now();
+    // Sample concurrency at arrival so the distribution survives between scrapes
+    let (load, leak_bytes) = {
+        let config = state.config.read();
+        (state.current_load(&config), config.leak_bytes_per_request)
+    };
+    state.leak_memory(leak_bytes);
+    histogram!("worker_concurrent_load", "worker" => state.worker_name.clone()).record(load as f64);
+    let mut response = CURRENT_TASK_ID
+        .scope(id.clone(), async {
+            if task.id.trim().is_empty() {
+                counter!("worker_requests_total", "worker" => state.worker_name.clone(), "status" => "missing_id", "version" => state.worker_version.clone()).increment(1);
+                return state.error_response(StatusCode::BAD_REQUEST, "Missing task id");
+            }
+            match auth {
+                Ok(tenant) => {
+                    if let Some(tenant) = tenant {
+                        counter!("worker_authenticated_requests_total", "worker" => state.worker_name.clone(), "tenant" => tenant)
+                            .increment(1);
+                    }
+                    if let Some(dependency) = &task.depends_on {
+                        let timeout = Duration::from_millis(
+                            state.config.read().dependency_timeout_ms as u64,
+                        );
+                        if !state.wait_for_dependency(&session, dependency, timeout).await {
+                            counter!("worker_requests_total", "worker" => state.worker_name.clone(), "status" => "failed_dependency", "version" => state.worker_version.clone()).increment(1);
+                            return state.error_response(
+                                StatusCode::FAILED_DEPENDENCY,
+                                format!("Dependency {} did not complete", dependency),
+                            );
+                        }
+                    }
+                    let Ok(_tenant_slot) = state.acquire_tenant_slot(headers) else {
+                        return state.refuse_task(
+                            "tenant_quota_exceeded",
+                            &state.worker_version,
+                            StatusCode::TOO_MANY_REQUESTS,
+                            "Tenant quota exceeded",
+                        );
+                    };
+                    if state.config.read().single_flight {
+                        process_task_single_flight(state, query, task).await
+                    } else {
+                        process_task(state, query, task).await
+                    }
+                }
+                Err(reason) => {
+                    counter!("worker_requests_total", "worker" => state.worker_name.clone(), "status" => "unauthorized", "version" => state.worker_version.clone()).increment(1);
+                    state.error_response(StatusCode::UNAUTHORIZED, reason)
+                }
+            }
+        })
+        .await;
+    if response.status().is_success() {
+        state.record_completed_task(&session, &id);
+    }
+    state.apply_affinity(headers, &mut response);
+    state.log_task(&id, response.status(), start.elapsed());
+    state.record_request_sample(response.status(), start.elapsed());
+    state.count_task_response(response.status());
+    state.count_lifetime_request();
+    // Every refusal path tags its response, so this is the one place rejections are recorded
+    if let Some(TaskRejected(reason)) = response.extensions().get::<TaskRejected>().copied() {
+        state.record_event("rejected", Some(id.clone()), Some(reason), None);
+    }
+    state.record_event(
+        "completed",
+        Some(id.clone()),
+        None,
+        Some(response.status().as_u16()),
+    );
+    if traced {
+        return trace_task_response(state, &id, response).await;
+    }
+    response
+}
+
+/// `trace_task_ids` に一致したタスクのレスポンスを読み切って info で出力し、同じ内容のレスポンスを返す。
+///
+/// 本文を読み切ってから返すため `trickle_bytes_per_sec` による分割送信は行われないが、トレーラーはそのまま送る。
+async fn trace_task_response(state: &AppState, id: &str, response: Response) -> Response {
+    let Some(buffered) = BufferedResponse::buffer(response).await else {
+        tracing::warn!(id, "traced task response body could not be read");
+        return state.error_response(StatusCode::INTERNAL_SERVER_ERROR, "Task processing failed");
+    };
+    let headers = format!("{:?}", buffered.headers);
+    let trailers = format!("{:?}", buffered.trailers);
+    let body = String::from_utf8_lossy(&buffered.body).into_owned();
+    tracing::info!(
+        id,
+        status = buffered.status.as_u16(),
+        headers,
+        trailers,
+        body,
+        "traced task response"
+    );
+    buffered.into_response()
+}
+
+/// 同じ id のタスクが処理中ならその結果を待って同じレスポンスを返し、なければ自分で処理する。
+///
+/// 処理は別タスクで行うため、最初のリクエストのクライアントが切断しても後続のリクエストには結果が届く。
+/// 共有のため本文は読み終えてから返す（`trickle_bytes_per_sec` による分割送信などは行われない）。
+async fn process_task_single_flight(
+    state: &Arc<AppState>,
+    query: TaskQuery,
+    task: TaskRequest,
+) -> Response {
+    let flight = {
+        let mut in_flight = state.in_flight_tasks.lock();
+        match in_flight.get(&task.id) {
+            Some(flight) => {
+                counter!("worker_coalesced_requests_total", "worker" => state.worker_name.clone())
+                    .increment(1);
+                flight.clone()
+            }
+            None => {
+                let key = task.id.clone();
+                let entry = InFlightEntry {
+                    state: Arc::clone(state),
+                    id: key.clone(),
+                };
+                let handle = tokio::spawn(CURRENT_TASK_ID.scope(key.clone(), async move {
+                    let response = process_task(&entry.state, query, task).await;
+                    let buffered = BufferedResponse::buffer(response).await;
+                    drop(entry);
+                    buffered
+                }));
+                let flight = handle.map(|r| r.ok().flatten()).boxed().shared();
+                // The leader cannot remove its entry before this insert: it needs the same lock
+                in_flight.insert(key, flight.clone());
+                flight
+            }
+        }
+    };
+    match flight.await {
+        Some(response) => response.into_response(),
+        None => state.error_response(StatusCode::INTERNAL_SERVER_ERROR, "Task processing failed"),
+    }
+}
+
+/// `auto_id` で空の id の代わりに使う、UUID v4 形式のランダムな id。
+fn random_task_id() -> String {
+    let bits: u128 = rand::thread_rng().gen();
+    let bits = (bits & !(0xF << 76) & !(0x3 << 62)) | (0x4 << 76) | (0x2 << 62);
+    let hex = format!("{:032x}", bits);
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+/// `handle_task` の本体。受付判定から遅延のシミュレーション、結果の決定までを行いレスポンスを返す。
+async fn process_task(state: &Arc<AppState>, query: TaskQuery, task: TaskRequest) -> Response {
+    let mut config = state.config.read().clone();
+    config.apply_latency_table();
+    config.apply_diurnal(f64::from_bits(state.diurnal_wave.load(Ordering::Relaxed)));
+    let version = state.pick_version(config.canary_fraction).to_string();
+    let hardware_class = state.pick_hardware_class();
+    if let Some(class) = hardware_class {
+        counter!("worker_hardware_class_requests_total", "worker" => state.worker_name.clone(), "class" => class.color.clone())
+            .increment(1);
+        config.response_delay_ms =
+            (config.response_delay_ms as f64 * class.latency_multiplier).round() as i32;
+    }
+    let mut query_force = None;
+    let mut forced_error = None;
+    if state.debug_endpoints {
+        if let Some(delay) = query.delay.filter(|v| *v >= 0) {
+            config.response_delay_ms = delay;
+        }
+        query_force = query.force;
+        forced_error = task.force_error.clone();
+        if forced_error.is_some() {
+            query_force = Some(ForcedOutcome::Fail);
+        }
+    }
+    let weight = task.weight.unwrap_or(1.0).max(0.1);
+    histogram!("worker_task_weight", "worker" => state.worker_name.clone()).record(weight);
+
+    if state.draining.load(Ordering::SeqCst) {
+        return state.refuse_task(
+            "draining",
+            &version,
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Worker draining",
+        );
+    }
+
+    if state.in_outage(Instant::now()) {
+        return state.refuse_task(
+            "outage",
+            &version,
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Simulated outage",
+        );
+    }
+
+    if !state.accepts_task_id(&task.id) {
+        counter!("worker_requests_total", "worker" => state.worker_name.clone(), "status" => "not_my_shard", "version" => version.clone()).increment(1);
+        return state.error_response(StatusCode::NOT_FOUND, "Not my shard");
+    }
+
+    let steps = match task.steps.clone().filter(|_| config.task_steps) {
+        Some(raw) => match parse_task_steps(raw) {
+            Ok(steps) => Some(steps),
+            Err(message) => {
+                counter!("worker_requests_total", "worker" => state.worker_name.clone(), "status" => "invalid_steps", "version" => version.clone()).increment(1);
+                return state.error_response(StatusCode::BAD_REQUEST, message);
+            }
+        },
+        None => None,
+    };
+
+    let forced = query_force.or_else(|| state.take_forced_outcome());
+    if forced == Some(ForcedOutcome::Overload) {
+        return state.refuse_task(
+            "overloaded",
+            &version,
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Forced overload",
+        );
+    }
+
+    // Requests already holding queue permits are ahead of this one
+    let queue_position = state.queue_depth(&config);
+
+    // Waiting before admission shares one deadline; exceeding it is a 408, not a 503
+    let accept_deadline = (config.accept_timeout_ms > 0)
+        .then(|| Instant::now() + Duration::from_millis(config.accept_timeout_ms as u64));
+
+    // A task that waited keeps its queue permit into admission, so newer arrivals cannot take its slot
+    let mut queued = None;
+    if state.paused.load(Ordering::SeqCst) {
+        // Park in the queue so the backlog shows up in queue_depth, but never beyond queue_size
+        let Ok(parked) = state.queue_semaphore.try_acquire() else {
+            return state.rejection_response(Rejection::QueueFull, &version);
+        };
+        state.record_queue_depth(&config);
+        let waited = wait_for_admission(accept_deadline, state.wait_until_resumed()).await;
+        if let Err(rejection) = waited {
+            return state.rejection_response(rejection, &version);
+        }
+        queued = Some(parked);
+    }
+
+    if config.leak_rate_rps > 0.0 {
+        // Buffer in the queue and release at a constant rate; overflow is a full queue
+        let buffered = match queued.take() {
+            Some(parked) => parked,
+            None => match state.queue_semaphore.try_acquire() {
+                Ok(permit) => permit,
+                Err(_) => return state.rejection_response(Rejection::QueueFull, &version),
+            },
+        };
+        state.record_queue_depth(&config);
+        let entry = state.enter_leak_buffer(&config);
+        let waited =
+            wait_for_admission(accept_deadline, tokio::time::sleep_until(entry.slot.into())).await;
+        drop(entry);
+        if let Err(rejection) = waited {
+            return state.rejection_response(rejection, &version);
+        }
+        queued = Some(buffered);
+    }
+
+    let controller = admission_controller(&config.admission_strategy);
+    let admission = match controller.admit(state, &config, &task, queued) {
+        Ok(admission) => admission,
+        Err(_) if failure_overrides_rejection(&config, forced, weight) => {
+            return state.failure_response(&version, forced_error);
+        }
+        Err(rejection) => return state.rejection_response(rejection, &version),
+    };
+    let Admission {
+        queue_permit: permit,
+        concurrency_permit,
+        profile,
+    } = admission;
+    state.record_event("admitted", Some(task.id.clone()), None, None);
+    // Sampled once admitted, so the request counts towards its own load
+    let degraded = state.health_status(&config) != "healthy";
+    gauge!("worker_current_load", "worker" => state.worker_name.clone())
+        .set(state.current_load(&config) as f64);
+
+    let start = Instant::now();
+
+    if config.shadow_enabled {
+        spawn_shadow(state, &config, weight);
+    }
+
+    let work = async {
+        let mut attempts = 1;
+        let failed = if let Some(steps) = &steps {
+            run_task_steps(steps, forced).await
+        } else {
+            // Simulate processing with delay, plus a uniform jitter floor so latencies spread out
+            sleep(simulated_delay(
+                config.response_delay_ms,
+                weight,
+                config.base_jitter_ms,
+            ))
+            .await;
+
+            // Roll the outcome, retrying internally with a fresh delay for each extra attempt
+            loop {
+                let failed = roll_failure(&config, forced, weight);
+                if !failed || attempts > config.internal_retries {
+                    break failed;
+                }
+                attempts += 1;
+                counter!("worker_internal_retries_total", "worker" => state.worker_name.clone())
+                    .increment(1);
+                sleep(simulated_delay(
+                    config.response_delay_ms,
+                    weight,
+                    config.base_jitter_ms,
+                ))
+                .await;
+            }
+        };
+        (failed, attempts)
+    };
+    // Only the simulated work is bounded here; the overall budget is checked after all steps
+    let work = if config.processing_timeout_ms > 0 {
+        let limit = Duration::from_millis(config.processing_timeout_ms as u64);
+        tokio::time::timeout(limit, work).await.ok()
+    } else {
+        Some(work.await)
+    };
+    let timed_out = work.is_none();
+    let (failed, attempts) = work.unwrap_or((false, 1));
+
+    // Enforce the minimum spacing between responses while still holding the permits
+    if config.min_inter_response_ms > 0 && !timed_out {
+        let gap = Duration::from_millis(config.min_inter_response_ms as u64);
+        tokio::time::sleep_until(state.reserve_response_slot(gap).into()).await;
+    }
+
+    let downstream = if timed_out {
+        Ok(())
+    } else {
+        fan_out_downstream(state, &config, &task).await
+    };
+
+    let processing_time = duration_millis(start.elapsed());
+    if config.per_id_metrics {
+        let id = state.metric_id_label(&task.id);
+        histogram!("worker_request_duration_ms", "worker" => state.worker_name.clone(), "version" => version.clone(), "id" => id).record(processing_time as f64);
+    } else {
+        histogram!("worker_request_duration_ms", "worker" => state.worker_name.clone(), "version" => version.clone()).record(processing_time as f64);
+    }
+    if let Some(class) = hardware_class {
+        histogram!("worker_hardware_class_duration_ms", "worker" => state.worker_name.clone(), "class" => class.color.clone())
+            .record(processing_time as f64);
+    }
+
+    // Cleanup
+    if let Some((name, profile, p)) = profile {
+        drop(p);
+        gauge!("worker_profile_in_flight", "worker" => state.worker_name.clone(), "profile" => name.clone())
+            .set(profile.in_flight() as f64);
+    }
+    drop(concurrency_permit);
+    state.record_permit_held(start.elapsed());
+    drop(permit);
+    gauge!("worker_current_load", "worker" => state.worker_name.clone())
+        .set(state.current_load(&config) as f64);
+
+    if timed_out {
+        counter!("worker_requests_total", "worker" => state.worker_name.clone(), "status" => "processing_timeout", "version" => version.clone()).increment(1);
+        return state.error_response(StatusCode::SERVICE_UNAVAILABLE, "Processing timeout");
+    }
+
+    if let Err(err) = downstream {
+        tracing::warn!("Downstream call failed: {}", err);
+        counter!("worker_requests_total", "worker" => state.worker_name.clone(), "status" => "downstream_failed", "version" => version.clone()).increment(1);
+        return state.error_response(StatusCode::BAD_GATEWAY, "Downstream call failed");
+    }
+
+    // Enforce the total latency budget across all simulated steps
+    if config.latency_budget_ms > 0 && processing_time > config.latency_budget_ms as i64 {
+        counter!("worker_budget_exceeded_total", "worker" => state.worker_name.clone())
+            .increment(1);
+        counter!("worker_requests_total", "worker" => state.worker_name.clone(), "status" => "budget_exceeded", "version" => version.clone()).increment(1);
+        return state.error_response(
+            StatusCode::GATEWAY_TIMEOUT,
+            format!(
+                "Budget exceeded ({}ms > {}ms)",
+                processing_time, config.latency_budget_ms
+            ),
+        );
+    }
+
+    // Simulate failure based on failure rate
+    if failed {
+        return state.failure_response(&version, forced_error);
+    }
+
+    // Success response
+    counter!("worker_requests_total", "worker" => state.worker_name.clone(), "status" => "success", "version" => version.clone()).increment(1);
+
+    let trickle_bytes_per_sec = config.trickle_bytes_per_sec;
+    let stream_trailers = config.stream_trailers;
+    let broken_content_length = state.chaos_transport
+        && rand::thread_rng().gen::<f64>() < config.broken_content_length_rate;
+    let truncated =
+        state.chaos_transport && rand::thread_rng().gen::<f64>() < config.truncate_response_rate;
+    let drifted =
+        state.chaos_transport && rand::thread_rng().gen::<f64>() < config.schema_drift_rate;
+    let status = u16::try_from(config.success_status_code)
+        .ok()
+        .and_then(|code| StatusCode::from_u16(code).ok())
+        .unwrap_or(StatusCode::OK);
+    let location = (status == StatusCode::ACCEPTED && config.accepted_location)
+        .then(|| HeaderValue::from_str(&format!("/task/{}/status", task.id)).ok())
+        .flatten();
+    let mut response = state.task_response(task.id, processing_time, version);
+    response.color = match hardware_class {
+        Some(class) => class.color.clone(),
+        None => state.outcome_color(&config, degraded),
+    };
+    response.attempts = (config.internal_retries > 0).then_some(attempts);
+    response.replica_lag_ms = config.sample_replica_lag();
+    response.queue_position = config.include_queue_position.then_some(queue_position);
+    if rand::thread_rng().gen::<f64>() < config.stale_timestamp_rate {
+        // Pretend a cache in front of us served an old copy
+        counter!("worker_stale_responses_total", "worker" => state.worker_name.clone())
+            .increment(1);
+        response.timestamp = state.stale_timestamp.clone();
+    }
+    let min_size = config.payload_size_bytes as usize;
+    let alignment = config.response_size_alignment as usize;
+    if query.echo_config.unwrap_or(config.echo_config) {
+        response.config = Some(config);
+    }
+    let body = if drifted {
+        counter!("worker_schema_drift_responses_total", "worker" => state.worker_name.clone())
+            .increment(1);
+        serde_json::to_vec(&DriftedTaskResponse::from(&response))
+    } else {
+        serde_json::to_vec(&response)
+    };
+    let body = pad_response_body(body.unwrap_or_default(), min_size, alignment);
+
+    let mut response = if broken_content_length {
+        counter!("worker_broken_content_length_total", "worker" => state.worker_name.clone())
+            .increment(1);
+        misframed_response(body)
+    } else if truncated {
+        counter!("worker_truncated_responses_total", "worker" => state.worker_name.clone())
+            .increment(1);
+        let mut body = body;
+        // Correctly framed, but the JSON stops halfway through
+        body.truncate(body.len() / 2);
+        ([(header::CONTENT_TYPE, "application/json")], body).into_response()
+    } else if trickle_bytes_per_sec > 0 {
+        let trailers = stream_trailers.then(|| {
+            let mut trailers = HeaderMap::new();
+            trailers.insert(PROCESSING_TIME_TRAILER, processing_time.into());
+            trailers.insert(STATUS_TRAILER, status.as_u16().into());
+            trailers
+        });
+        let mut streamed = (
+            [(header::CONTENT_TYPE, "application/json")],
+            trickle_body(body, trickle_bytes_per_sec as u64, trailers),
+        )
+            .into_response();
+        if stream_trailers {
+            // hyper only sends trailer fields announced up front
+            streamed.headers_mut().insert(
+                header::TRAILER,
+                HeaderValue::from_static("x-processing-time-ms, x-status"),
+            );
+        }
+        streamed
+    } else {
+        ([(header::CONTENT_TYPE, "application/json")], body).into_response()
+    };
+    *response.status_mut() = status;
+    if let Some(location) = location {
+        response.headers_mut().insert(header::LOCATION, location);
+    }
+    response
+}
+
+/// JSON オブジェクトの本文 `body` に `padding` フィールドを足し、`min_size` 以上かつ `alignment` の倍数の長さにする。
+///
+/// どちらも 0 なら無効。足りない分が空の `padding` フィールドにも満たない場合は、次の倍数
+/// （`alignment` が 0 なら空のフィールドを足した長さ）まで広げる。既に条件を満たしていればそのまま返す。
+///
+/// # Examples
+///
+/// ```
+/// let body = pad_response_body(br#"{"id":"a"}"#.to_vec(), 0, 64);
+/// assert_eq!(body.len(), 64);
+/// assert!(body.ends_with(b"xxx\"}"));
+/// ```
+fn pad_response_body(mut body: Vec<u8>, min_size: usize, alignment: usize) -> Vec<u8> {
+    let align = |len: usize| {
+        if alignment > 0 {
+            len.next_multiple_of(alignment)
+        } else {
+            len
+        }
+    };
+    let mut target = align(body.len().max(min_size));
+    if target == body.len() || body.last() != Some(&b'}') {
+        return body;
+    }
+    if target < body.len() + PADDING_FIELD_BYTES {
+        target = align(body.len() + PADDING_FIELD_BYTES);
+    }
+    body.pop();
+    body.extend_from_slice(br#","padding":""#);
+    body.resize(target - 2, b'x');
+    body.extend_from_slice(br#""}"#);
+    body
+}
+
+/// 実際の本文より `MISFRAMED_EXTRA_BYTES` だけ長い `Content-Length` を宣言したレスポンスを作る。
+///
+/// 長さの分からないストリームとして本文を渡すため、hyper は宣言された長さを信じてそのまま送り出し、
+/// 本文が尽きたところで接続を切る。クライアントからは途中で途切れた本文に見える。
+fn misframed_response(body: Vec<u8>) -> Response {
+    let declared = body.len() + MISFRAMED_EXTRA_BYTES;
+    let stream = stream::unfold(Some(body), |body| async move {
+        match body {
+            Some(body) => Some((Ok::<_, io::Error>(Bytes::from(body)), None)),
+            None => {
+                // Give hyper time to flush what was sent before the body ends short
+                sleep(MISFRAMED_FLUSH_DELAY).await;
+                None
+            }
+        }
+    });
+    (
+        [
+            (
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("application/json"),
+            ),
+            (header::CONTENT_LENGTH, HeaderValue::from(declared)),
+        ],
+        Body::from_stream(stream),
+    )
+        .into_response()
+}
+
+/// 本文を `bytes_per_sec` の速度で少しずつ送り出すストリーミングボディを作る。
+///
+/// 約 100ms ごとに `bytes_per_sec / 10` バイト（最低 1 バイト）のチャンクを送る。
+/// 最初のチャンクは待たずに送るため、最初のバイトまでの時間には影響しない。
+/// `trailers` を渡すと、本文を送り終えた後に HTTP トレーラーとして送る。
+fn trickle_body(body: Vec<u8>, bytes_per_sec: u64, trailers: Option<HeaderMap>) -> Body {
+    let chunk_size = (bytes_per_sec / 10).max(1) as usize;
+    let interval = Duration::from_secs_f64(chunk_size as f64 / bytes_per_sec as f64);
+    let chunks: VecDeque<Bytes> = body
+        .chunks(chunk_size)
+        .map(Bytes::copy_from_slice)
+        .collect();
+
+    let stream = stream::unfold(
+        (chunks, true, trailers),
+        move |(mut chunks, first, trailers)| async move {
+            let Some(chunk) = chunks.pop_front() else {
+                let trailers = Frame::trailers(trailers?);
+                return Some((Ok::<_, io::Error>(trailers), (chunks, false, None)));
+            };
+            if !first {
+                sleep(interval).await;
+            }
+            Some((Ok(Frame::data(chunk)), (chunks, false, trailers)))
+        },
+    );
+    Body::new(StreamBody::new(stream))
+}
+
+/// JSON 配列を要素ごとに切り出す逐次デコーダ。
+///
+/// 本文を受け取った分ずつ `feed` に渡すと、読み終えた要素のバイト列を返す。保持するのは読みかけの要素 1 つ分
+/// （最大 `MAX_BATCH_ELEMENT_BYTES`）だけなので、配列全体の大きさに関係なくメモリ使用量は一定になる。
+/// 要素の中身は検証せず、切り出した要素の解釈は呼び出し元が行う。
+#[derive(Default)]
+struct JsonArraySplitter {
+    state: SplitState,
+    depth: u32,
+    in_string: bool,
+    escaped: bool,
+    element: Vec<u8>,
+    /// これまでに `element` が保持した最大のバイト数。
+    peak_buffered: usize,
+}
+
+#[derive(Default, PartialEq)]
+enum SplitState {
+    /// 配列の `[` を待っている。
+    #[default]
+    Start,
+    /// 最初の要素か、空の配列の `]` を待っている。
+    First,
+    /// `,` の後の要素を待っている。
+    Next,
+    /// 要素を読んでいる。
+    Element,
+    /// `]` まで読み終えた。後ろには空白のみを許す。
+    Done,
+}
+
+impl JsonArraySplitter {
+    /// `chunk` を読み進め、読み終えた要素を `elements` に追加する。配列として不正なら理由を返す。
+    fn feed(&mut self, chunk: &[u8], elements: &mut Vec<Vec<u8>>) -> Result<(), String> {
+        for &byte in chunk {
+            match self.state {
+                SplitState::Start | SplitState::First | SplitState::Next | SplitState::Done
+                    if byte.is_ascii_whitespace() => {}
+                SplitState::Start if byte == b'[' => self.state = SplitState::First,
+                SplitState::Start => return Err("Batch must be a JSON array".to_string()),
+                SplitState::First if byte == b']' => self.state = SplitState::Done,
+                SplitState::First | SplitState::Next if byte == b']' || byte == b',' => {
+                    return Err("Missing array element".to_string());
+                }
+                SplitState::First | SplitState::Next => {
+                    self.state = SplitState::Element;
+                    self.push_outside_string(byte)?;
+                }
+                SplitState::Element if self.in_string => {
+                    if self.escaped {
+                        self.escaped = false;
+                    } else if byte == b'\\' {
+                        self.escaped = true;
+                    } else if byte == b'"' {
+                        self.in_string = false;
+                    }
+                    self.push(byte)?;
+                }
+                SplitState::Element if self.depth == 0 && (byte == b',' || byte == b']') => {
+                    elements.push(std::mem::take(&mut self.element));
+                    self.state = if byte == b',' {
+                        SplitState::Next
+                    } else {
+                        SplitState::Done
+                    };
+                }
+                SplitState::Element => self.push_outside_string(byte)?,
+                SplitState::Done => return Err("Unexpected data after the array".to_string()),
+            }
+        }
+        Ok(())
+    }
+
+    /// 本文を読み終えた時点で呼ぶ。配列が閉じていなければ理由を返す。
+    fn finish(&self) -> Result<(), String> {
+        if self.state == SplitState::Done {
+            Ok(())
+        } else {
+            Err("Unterminated array".to_string())
+        }
+    }
+
+    /// 文字列の外のバイトを要素に加え、文字列の開始と入れ子の深さを追う。
+    fn push_outside_string(&mut self, byte: u8) -> Result<(), String> {
+        match byte {
+            b'"' => self.in_string = true,
+            b'{' | b'[' => self.depth += 1,
+            b'}' | b']' => self.depth = self.depth.saturating_sub(1),
+            _ => {}
+        }
+        self.push(byte)
+    }
+
+    fn push(&mut self, byte: u8) -> Result<(), String> {
+        if self.element.len() == MAX_BATCH_ELEMENT_BYTES {
+            return Err(format!(
+                "Batch element too large (max {} bytes)",
+                MAX_BATCH_ELEMENT_BYTES
+            ));
+        }
+        self.element.push(byte);
+        self.peak_buffered = self.peak_buffered.max(self.element.len());
+        Ok(())
+    }
+}
+
+/// `POST /task/batch` の結果。要素ごとの結果は持たず、ステータスコードごとの件数だけを返す。
+#[derive(Debug, Serialize)]
+struct BatchResponse {
+    worker: String,
+    total: u64,
+    /// `TaskRequest` として解釈できなかった要素の数。
+    invalid: u64,
+    statuses: BTreeMap<u16, u64>,
+}
+
+/// タスクの JSON 配列を受け取り、要素ごとに `/task` と同じ処理を行うハンドラ。
+///
+/// 認証はリクエスト全体で一度だけ行い、失敗した場合は本文を読まずに 401 を返す。各要素はこのリクエストのヘッダー
+/// （テナント・セッション）を使って `serve_task` で処理し、`/task` 1 件と同じようにテナント上限やログ、
+/// /report・`MAX_LIFETIME_REQUESTS` に数える。
+///
+/// 本文は `JsonArraySplitter` で要素ごとに読みながら処理し、配列全体をメモリに載せない。同時に処理するのは
+/// `max_concurrent_requests` 件までで、それを超える分は処理が空くまで本文の読み込みを止める。
+/// 要素が `max_batch_size` 件を超えた場合は 413、配列として不正な場合や要素が `MAX_BATCH_ELEMENT_BYTES` を超える
+/// 場合は 400 を返す。いずれもそれまでに読んだ要素は処理済みになる。`TaskRequest` として解釈できない要素は
+/// 処理せずに `invalid` として数える。
+async fn handle_task_batch(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    body: Body,
+) -> Response {
+    let tenant = match state.authenticate_task(&headers) {
+        Ok(tenant) => tenant,
+        Err(reason) => {
+            counter!("worker_requests_total", "worker" => state.worker_name.clone(), "status" => "unauthorized", "version" => state.worker_version.clone()).increment(1);
+            return state.error_response(StatusCode::UNAUTHORIZED, reason);
+        }
+    };
+    let (max_batch_size, max_in_flight) = {
+        let config = state.config.read();
+        (
+            config.max_batch_size as u64,
+            config.max_concurrent_requests.max(1) as usize,
+        )
+    };
+    let mut splitter = JsonArraySplitter::default();
+    let mut body = body.into_data_stream();
+    let mut in_flight = FuturesUnordered::new();
+    let mut elements = Vec::new();
+    let mut result = BatchResponse {
+        worker: state.worker_name.clone(),
+        total: 0,
+        invalid: 0,
+        statuses: BTreeMap::new(),
+    };
+    let outcome = loop {
+        let chunk = match body.next().await {
+            Some(Ok(chunk)) => chunk,
+            Some(Err(err)) => break Err((StatusCode::BAD_REQUEST, err.to_string())),
+            None => break splitter.finish().map_err(|e| (StatusCode::BAD_REQUEST, e)),
+        };
+        if let Err(err) = splitter.feed(&chunk, &mut elements) {
+            break Err((StatusCode::BAD_REQUEST, err));
+        }
+        let mut too_large = false;
+        for element in elements.drain(..) {
+            if result.total == max_batch_size {
+                too_large = true;
+                break;
+            }
+            result.total += 1;
+            let Ok(task) = serde_json::from_slice::<TaskRequest>(&element) else {
+                result.invalid += 1;
+                continue;
+            };
+            while in_flight.len() >= max_in_flight {
+                if let Some(status) = in_flight.next().await {
+                    *result.statuses.entry(status).or_default() += 1;
+                }
+            }
+            let (state, headers, tenant) = (&state, &headers, tenant.clone());
+            in_flight.push(async move {
+                let response =
+                    serve_task(state, TaskQuery::default(), headers, Ok(tenant), task).await;
+                response.status().as_u16()
+            });
+        }
+        if too_large {
+            break Err((
+                StatusCode::PAYLOAD_TOO_LARGE,
+                format!("Batch too large (max {})", max_batch_size),
+            ));
+        }
+    };
+    while let Some(status) = in_flight.next().await {
+        *result.statuses.entry(status).or_default() += 1;
+    }
+    match outcome {
+        Ok(()) => Json(result).into_response(),
+        Err((status, message)) => state.error_response(status, message),
+    }
+}
+
+/// `GET /task/{id}/progress` が 1 行ずつ送る進捗。
+#[derive(Serialize)]
+struct ProgressLine<'a> {
+    id: &'a str,
+    step: u32,
+    total: u32,
+    percent: u32,
+}
+
+/// `id` のタスクの進捗ストリームの各行（改行付きの JSON）。同じ `id` なら常に同じバイト列になる。
+fn progress_lines(id: &str) -> Vec<Bytes> {
+    (0..=PROGRESS_STEPS)
+        .map(|step| {
+            let line = ProgressLine {
+                id,
+                step,
+                total: PROGRESS_STEPS,
+                percent: step * 100 / PROGRESS_STEPS,
+            };
+            let mut bytes = serde_json::to_vec(&line).unwrap_or_default();
+            bytes.push(b'\n');
+            Bytes::from(bytes)
+        })
+        .collect()
+}
+
+/// `Range` ヘッダーの値を長さ `len` の本文に対する範囲（両端を含む）として解釈する。
+///
+/// `bytes=a-b`・`bytes=a-`・`bytes=-n` の単一範囲のみ受け付ける。書式が不正、複数範囲、満たせない範囲は `None`。
+fn parse_byte_range(raw: &str, len: u64) -> Option<(u64, u64)> {
+    let spec = raw.trim().strip_prefix("bytes=")?;
+    let (start, end) = spec.trim().split_once('-')?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => {
+            let suffix: u64 = suffix.parse().ok()?;
+            (len.checked_sub(suffix.min(len))?, len.checked_sub(1)?)
+        }
+        (start, "") => (start.parse().ok()?, len.checked_sub(1)?),
+        (start, end) => (
+            start.parse().ok()?,
+            end.parse::<u64>().ok()?.min(len.checked_sub(1)?),
+        ),
+    };
+    (start <= end && start < len).then_some((start, end))
+}
+
+/// タスクの進捗を NDJSON で少しずつ送るハンドラ。Range 対応のプロキシの検証用。
+///
+/// 0% から 100% まで `PROGRESS_STEPS + 1` 行を、`response_delay_ms` を行数で割った間隔で送る。本文は `id` だけで
+/// 決まるため長さを事前に求められ、`Accept-Ranges: bytes` と `Content-Length` を付ける。`Range` ヘッダーがあれば
+/// その範囲だけを 206 と `Content-Range` で返し、解釈できない・満たせない範囲には `Content-Range: bytes */長さ` 付きの
+/// 416 を返す。実際のタスクの処理とは関係なく、受付判定やメトリクスの対象にもならない。
+async fn handle_task_progress(
+    State(state): State<Arc<AppState>>,
+    UrlPath(id): UrlPath<String>,
+    headers: HeaderMap,
+) -> Response {
+    let lines = progress_lines(&id);
+    let len: u64 = lines.iter().map(|line| line.len() as u64).sum();
+    let range = match headers.get(header::RANGE) {
+        Some(raw) => match raw.to_str().ok().and_then(|raw| parse_byte_range(raw, len)) {
+            Some(range) => Some(range),
+            None => {
+                let mut resp =
+                    state.error_response(StatusCode::RANGE_NOT_SATISFIABLE, "Invalid range");
+                if let Ok(value) = HeaderValue::from_str(&format!("bytes */{}", len)) {
+                    resp.headers_mut().insert(header::CONTENT_RANGE, value);
+                }
+                return resp;
+            }
+        },
+        None => None,
+    };
+    let (start, end) = range.unwrap_or((0, len - 1));
+
+    // Keep only the slices of each line that fall inside the requested range
+    let mut offset = 0;
+    let mut chunks = VecDeque::new();
+    for line in lines {
+        let line_len = line.len() as u64;
+        let (line_start, line_end) = (offset, offset + line_len - 1);
+        offset += line_len;
+        if line_end < start || line_start > end {
+            continue;
+        }
+        let from = start.saturating_sub(line_start) as usize;
+        let to = (end.min(line_end) - line_start + 1) as usize;
+        chunks.push_back(line.slice(from..to));
+    }
+    let interval = Duration::from_millis(
+        state.config.read().response_delay_ms.max(0) as u64 / u64::from(PROGRESS_STEPS + 1),
+    );
+    let stream = stream::unfold((chunks, true), move |(mut chunks, first)| async move {
+        let chunk = chunks.pop_front()?;
+        if !first {
+            sleep(interval).await;
+        }
+        Some((Ok::<_, io::Error>(chunk), (chunks, false)))
+    });
+
+    let status = if range.is_some() {
+        StatusCode::PARTIAL_CONTENT
+    } else {
+        StatusCode::OK
+    };
+    let mut resp = (status, Body::from_stream(stream)).into_response();
+    let headers = resp.headers_mut();
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/x-ndjson"),
+    );
+    headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    headers.insert(header::CONTENT_LENGTH, HeaderValue::from(end - start + 1));
+    if range.is_some() {
+        if let Ok(value) = HeaderValue::from_str(&format!("bytes {}-{}/{}", start, end, len)) {
+            headers.insert(header::CONTENT_RANGE, value);
+        }
+    }
+    resp
+}
+
+/// ヘルスチェックを作成し、現在の負荷とキュー深度に基づいてサービスの状態を返すハンドラ。
+///
+/// 現在の同時処理数とキュー深度を取得し、構成の最大値に対する比率を `health_load_weight`・`health_queue_weight`
+/// で重み付けして `health_score_mode`（既定 `max`、`sum` で加重和）でまとめたスコアから状態を決定する：
+/// - スコアが 0.9 以上なら `unhealthy`
+/// - スコアが 0.7 以上なら `degraded`
+/// - それ以外は `healthy`
+///
+/// ドレイン中は負荷に関わらず `draining` を 503 とともに返し、ロードバランサーがこのワーカーを外せるようにする。
+/// 前回と異なる状態になった場合は `GET /health/history` 用に遷移を記録する（`record_health`）。
+///
+/// `CHAOS_HEALTH` が有効な場合は `health_flap_rate` の確率で、そのレスポンスに限り実際より 1 段階悪い状態
+/// （`healthy` → `degraded` → `unhealthy`）を返す（`worker_health_flaps_injected_total` に計上）。
+/// プローブの `failureThreshold` が単発の悪い応答に耐えられるかを確かめるためのノイズで、
+/// 遷移の記録や負荷に基づく判定そのものには影響しない。
+///
+/// `health_cache_ms` が正の場合は、バックグラウンドの `refresh_health_cache` がその間隔で求め直した状態を返し、
+/// プローブが集中しても設定ロックを読まない（負荷の値は最大でその程度古くなる）。ドレインの開始・終了はキャッシュを捨てて即座に反映する。
+///
+/// 返却される JSON ペイロードは `HealthResponse` で、状態文字列、現在の負荷（in-flight リクエスト数）、キュー深度、
+/// `POST /pause` による一時停止中かどうか（`paused`）を含む。
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::sync::Arc;
+/// use axum::extract::State;
+///
+/// #[tokio::main]
+/// async fn main() {
+///     // `state` は実際のアプリケーションの共有状態を指す Arc<AppState> とする
+///     let state: Arc<AppState> = /* 既存の AppState を構築 */ unimplemented!();
+///     let response = handle_health(State(state)).await;
+///     // `response` は `HealthResponse` を含む JSON レスポンスになる
+/// }
+/// ```
+async fn handle_health(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    let snapshot = match state.cached_health() {
+        Some(snapshot) => snapshot,
+        None => {
+            state.contend_config_read().await;
+            state.health_snapshot()
+        }
+    };
+    let status = snapshot.status;
+    let status = if state.chaos_health && rand::thread_rng().gen::<f64>() < snapshot.flap_rate {
+        let reported = worse_health_status(status);
+        if reported != status {
+            counter!("worker_health_flaps_injected_total", "worker" => state.worker_name.clone(), "actual" => status, "reported" => reported)
+                .increment(1);
+        }
+        reported
+    } else {
+        status
+    };
+
+    let code = if status == "draining" {
+        StatusCode::SERVICE_UNAVAILABLE
+    } else {
+        StatusCode::OK
+    };
+    (
+        code,
+        Json(HealthResponse {
+            status: status.to_string(),
+            current_load: snapshot.current_load,
+            queue_depth: snapshot.queue_depth,
+            paused: state.paused.load(Ordering::SeqCst),
+        }),
+    )
+}
+
+/// `status` より 1 段階悪いヘルス状態。`unhealthy` と `draining` はそのまま返す。
+fn worse_health_status(status: &'static str) -> &'static str {
+    match status {
+        "healthy" => "degraded",
+        "degraded" => "unhealthy",
+        other => other,
+    }
+}
+
+/// 設定（Configuration）の現在値をJSONで返すエンドポイントハンドラ。
+///
+/// レスポンスとして現在の `Configuration` クローンをJSON形式で返します。
+/// `DEBUG_ENDPOINTS` が有効なら、`/task`・`/health` と同じく読む前に `config_read_delay_ms` だけ待ちます。
+async fn handle_config_get(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    state.contend_config_read().await;
+    let config = state.config.read().clone();
+    Json(config)
+}
+
+/// 設定値を受け取り、妥当なフィールドのみアプリケーションのランタイム設定に反映して更新済みの設定を返すハンドラー。
+///
+/// `PATCH /config` と、互換性のために残している `POST /config` で使われる部分更新。
+/// 設定全体の置き換えは `PUT /config`（`handle_config_replace`）を使う。
+///
+/// 与えられた `ConfigUpdate` のうち、指定されたフィールドが次の条件を満たす場合にのみ現在の設定へ適用される:
+/// - `max_concurrent_requests > 0`
+/// - `response_delay_ms >= 0`
+/// - `0.0 <= failure_rate <= 1.0`
+/// - `queue_size > 0`（`MAX_QUEUE_SIZE` を超える値は切り詰める）
+/// - `0.0 <= canary_fraction <= 1.0`
+/// - `base_jitter_ms >= 0`
+/// - `min_inter_response_ms >= 0`
+/// - `shadow_response_delay_ms >= 0`
+/// - `0.0 <= shadow_failure_rate <= 1.0`
+/// - `rate_limit_rps >= 0.0`（0 で無効）
+/// - `rate_limit_burst >= 0`（0 で `rate_limit_rps` の切り上げ値）
+/// - `latency_budget_ms >= 0`（0 で無制限）
+/// - `trickle_bytes_per_sec >= 0`（0 で一括送信）
+/// - `0.0 <= stale_timestamp_rate <= 1.0`
+/// - `accept_id_pattern` は任意の文字列（空で無効。正規表現として不正な場合はエラーを記録してフィルタを無効化）
+/// - `admission_strategy` は `ADMISSION_CONTROLLERS` に登録された名前
+/// - `downstream_url` は任意の文字列（空で下流呼び出しなし）
+/// - `downstream_connect_delay_ms >= 0`
+/// - `success_color`・`degraded_color`・`failure_color` は任意の文字列（空で `WORKER_COLOR`）
+/// - `0.0 <= outage_probability <= 1.0`
+/// - `outage_duration_ms >= 0`
+/// - `unfair_prefix` は任意の文字列（空で無効）
+/// - `internal_retries >= 0`
+/// - `0.0 <= broken_content_length_rate <= 1.0`
+/// - `leak_rate_rps >= 0.0`（0 で無効）
+/// - `health_load_weight >= 0.0`
+/// - `health_queue_weight >= 0.0`
+/// - `health_score_mode` は `max` か `sum`
+/// - `replica_lag_ms >= 0`（0 で `replica_lag_jitter_ms` のみ）
+/// - `replica_lag_jitter_ms >= 0`
+/// - `success_status_code` は 200・201・202 のいずれか
+/// - `leak_bytes_per_request >= 0`（0 で無効）
+/// - `accept_timeout_ms >= 0`（0 で無制限）
+/// - `dependency_timeout_ms >= 0`
+/// - `0.0 <= truncate_response_rate <= 1.0`
+/// - `diurnal_period_ms > 0`
+/// - `0.0 <= diurnal_amplitude <= 1.0`（0 で無効）
+/// - `processing_timeout_ms >= 0`（0 で無制限）
+/// - `latency_table` の各エントリは `delay_ms >= 0`・`weight >= 0.0` で、重みの合計が正（空で `response_delay_ms` を使う）
+/// - `0.0 <= health_flap_rate <= 1.0`
+/// - `trace_task_ids` は任意の id のリスト（空で無効）
+/// - `0 <= payload_size_bytes <= MAX_RESPONSE_SIZE_BYTES`（0 で無効）
+/// - `0 <= response_size_alignment <= MAX_RESPONSE_SIZE_BYTES`（0 で無効）
+/// - `config_read_delay_ms >= 0`（0 で無効）
+/// - `downstream_targets` の各エントリは `url` が空でなく `weight >= 0.0` で、重みの合計が正（空で `downstream_url` を使う）
+/// - `1 <= downstream_fanout <= MAX_DOWNSTREAM_FANOUT`
+/// - `fanout_quorum >= 0`（0 か `downstream_fanout` 以上ですべて）
+/// - `health_cache_ms >= 0`（0 で毎回計算する）
+/// - `0.0 <= schema_drift_rate <= 1.0`
+/// - `1 <= max_batch_size <= MAX_BATCH_SIZE_LIMIT`
+///
+/// 省略されたフィールドは現在の値のまま維持される。
+/// 更新後の設定はログに記録され、`CONFIG_CHANGE_WEBHOOK` が設定されていればその URL へも通知された上で、
+/// クライアントへ JSON として返される。
+///
+/// # Returns
+///
+/// 更新後の `Configuration` を含む JSON レスポンス。
+///
+/// # Examples
+///
+/// ```ignore
+/// // ハンドラーの使い方（概念例）
+/// // 実際の呼び出しは Axum のルーティング経由で行われる。
+/// let update = ConfigUpdate {
+///     failure_rate: Some(0.1),
+///     canary_fraction: Some(0.25),
+///     ..Default::default()
+/// };
+/// // PATCH /config に update を送ると、更新後の設定が JSON で返る
+/// ```
+async fn handle_config_update(
+    State(state): State<Arc<AppState>>,
+    Json(new_config): Json<ConfigUpdate>,
+) -> impl IntoResponse {
+    Json(apply_config_update(&state, &new_config))
+}
+
+/// 設定全体を置き換える `PUT /config` のハンドラー。
+///
+/// 本文は `Configuration` のすべてのフィールドを含む必要があり、欠けているフィールドがある場合や、
+/// `PATCH /config` では無視されるような範囲外の値（実行時に縮小できない `queue_size` を含む）がある場合は、
+/// 設定を一切変更せずに 400 を返す。エラーには該当するフィールド名と、部分更新には PATCH を使う旨を含める。
+/// 受理された場合の反映・記録・通知は `PATCH /config` と同じ。
+///
+/// # Returns
+///
+/// 置き換え後の `Configuration` を含む JSON レスポンス、または 400 の `ErrorResponse`。
+async fn handle_config_replace(
+    State(state): State<Arc<AppState>>,
+    Json(body): Json<serde_json::Value>,
+) -> Response {
+    let current = serde_json::to_value(state.config.read().clone()).unwrap_or_default();
+    let provided = body.as_object().cloned().unwrap_or_default();
+    let missing: Vec<&str> = current
+        .as_object()
+        .into_iter()
+        .flat_map(|fields| fields.keys())
+        .filter(|key| provided.get(*key).is_none_or(|v| v.is_null()))
+        .map(String::as_str)
+        .collect();
+    if !missing.is_empty() {
+        return state.error_response(
+            StatusCode::BAD_REQUEST,
+            format!(
+                "PUT replaces the whole config; missing fields: {} (use PATCH for partial updates)",
+                missing.join(", ")
+            ),
+        );
+    }
+
+    let update: ConfigUpdate = match serde_json::from_value(body) {
+        Ok(update) => update,
+        Err(err) => {
+            return state
+                .error_response(StatusCode::BAD_REQUEST, format!("Invalid config: {}", err))
+        }
+    };
+
+    let invalid = rejected_fields(&state.config.read(), &update);
+    if !invalid.is_empty() {
+        return state.error_response(
+            StatusCode::BAD_REQUEST,
+            format!("Invalid config fields: {}", invalid.join(", ")),
+        );
+    }
+
+    Json(apply_config_update(&state, &update)).into_response()
+}
+
+/// 部分更新を現在の設定にマージし、セマフォの調整・履歴への記録・変更通知を行って更新後の設定を返す。
+///
+/// 書き込みロックはマージと版番号の採番の間だけ保持し、`handle_health` や `/task` の読み取りを長く塞がない。
+/// セマフォの増減幅はロック下の現在値との差分として求め、ロックを放してから反映する。差分の加算・回収は
+/// 順序に依存しないため、同時に複数の更新が届いても許可数は最終的な `queue_size`・`max_concurrent_requests` からずれない。
+///
+/// 更新のたびに `worker_config_changes_total` を加算し、`worker_config_version` を 1 つ進める。
+fn apply_config_update(state: &Arc<AppState>, new_config: &ConfigUpdate) -> Configuration {
+    let (previous, updated, version) = {
+        let mut config = state.config.write();
+        let previous = config.clone();
+        merge_config_update(&mut config, new_config);
+        if config.accept_id_pattern != previous.accept_id_pattern {
+            // Kept under the lock so racing pattern changes are applied in order
+            state.set_accept_id_pattern(&config.accept_id_pattern);
+        }
+        let version = state.config_version.fetch_add(1, Ordering::SeqCst) + 1;
+        (previous, config.clone(), version)
+    };
+    if updated.max_concurrent_requests != previous.max_concurrent_requests {
+        state.resize_concurrency(
+            previous.max_concurrent_requests,
+            updated.max_concurrent_requests,
+        );
+    }
+    if updated.queue_size > previous.queue_size {
+        // The delta was taken against the locked value, so concurrent updates never over-add
+        state
+            .queue_semaphore
+            .add_permits((updated.queue_size - previous.queue_size) as usize);
+    }
+    if updated.per_id_metrics && !previous.per_id_metrics {
+        tracing::warn!("{}", PER_ID_METRICS_WARNING);
+    }
+    counter!("worker_config_changes_total", "worker" => state.worker_name.clone()).increment(1);
+    gauge!("worker_config_version", "worker" => state.worker_name.clone()).set(version as f64);
+    tracing::info!("Config updated (version {}): {:?}", version, updated);
+    state.record_config_history(&updated);
+    state.notify_config_change(&updated);
+    updated
+}
+
+/// `update` で指定されたフィールドのうち、`merge_config_update` で無視または切り詰められるものの名前を返す。
+///
+/// 妥当性の条件を二重に持たないよう、実際に `base` へマージした結果と要求値を比べて判定する。
+fn rejected_fields(base: &Configuration, update: &ConfigUpdate) -> Vec<String> {
+    let mut merged = base.clone();
+    merge_config_update(&mut merged, update);
+    let requested = serde_json::to_value(update).unwrap_or_default();
+    let merged = serde_json::to_value(&merged).unwrap_or_default();
+    requested
+        .as_object()
+        .into_iter()
+        .flat_map(|fields| fields.iter())
+        .filter(|(key, value)| !value.is_null() && merged.get(key.as_str()) != Some(*value))
+        .map(|(key, _)| key.clone())
+        .collect()
+}
+
+/// `ConfigUpdate` のうち指定され、かつ妥当なフィールドだけを `config` に書き込む。副作用は持たない。
+fn merge_config_update(config: &mut Configuration, new_config: &ConfigUpdate) {
+    if let Some(max_concurrent) = new_config.max_concurrent_requests.filter(|v| *v > 0) {
+        config.max_concurrent_requests = max_concurrent;
+    }
+    if let Some(delay) = new_config.response_delay_ms.filter(|v| *v >= 0) {
+        config.response_delay_ms = delay;
+    }
+    if let Some(rate) = new_config.failure_rate.filter(|v| (0.0..=1.0).contains(v)) {
+        config.failure_rate = rate;
+    }
+    if let Some(fraction) = new_config
+        .canary_fraction
+        .filter(|v| (0.0..=1.0).contains(v))
+    {
+        config.canary_fraction = fraction;
+    }
+    if let Some(jitter) = new_config.base_jitter_ms.filter(|v| *v >= 0) {
+        config.base_jitter_ms = jitter;
+    }
+    if let Some(echo) = new_config.echo_config {
+        config.echo_config = echo;
+    }
+    if let Some(gap) = new_config.min_inter_response_ms.filter(|v| *v >= 0) {
+        config.min_inter_response_ms = gap;
+    }
+    if let Some(value) = new_config.shadow_enabled {
+        config.shadow_enabled = value;
+    }
+    if let Some(delay) = new_config.shadow_response_delay_ms.filter(|v| *v >= 0) {
+        config.shadow_response_delay_ms = delay;
+    }
+    if let Some(rate) = new_config
+        .shadow_failure_rate
+        .filter(|v| (0.0..=1.0).contains(v))
+    {
+        config.shadow_failure_rate = rate;
+    }
+    if let Some(rps) = new_config.rate_limit_rps.filter(|v| *v >= 0.0) {
+        config.rate_limit_rps = rps;
+    }
+    if let Some(burst) = new_config.rate_limit_burst.filter(|v| *v >= 0) {
+        config.rate_limit_burst = burst;
+    }
+    if let Some(budget) = new_config.latency_budget_ms.filter(|v| *v >= 0) {
+        config.latency_budget_ms = budget;
+    }
+    if let Some(rate) = new_config.trickle_bytes_per_sec.filter(|v| *v >= 0) {
+        config.trickle_bytes_per_sec = rate;
+    }
+    if let Some(rate) = new_config
+        .stale_timestamp_rate
+        .filter(|v| (0.0..=1.0).contains(v))
+    {
+        config.stale_timestamp_rate = rate;
+    }
+    if let Some(pattern) = &new_config.accept_id_pattern {
+        config.accept_id_pattern = pattern.clone();
+    }
+    if let Some(strategy) = new_config
+        .admission_strategy
+        .as_ref()
+        .filter(|v| is_admission_strategy(v))
+    {
+        config.admission_strategy = strategy.clone();
+    }
+    if let Some(value) = new_config.problem_json {
+        config.problem_json = value;
+    }
+    if let Some(url) = &new_config.downstream_url {
+        config.downstream_url = url.clone();
+    }
+    if let Some(delay) = new_config.downstream_connect_delay_ms.filter(|v| *v >= 0) {
+        config.downstream_connect_delay_ms = delay;
+    }
+    if let Some(color) = &new_config.success_color {
+        config.success_color = color.clone();
+    }
+    if let Some(color) = &new_config.degraded_color {
+        config.degraded_color = color.clone();
+    }
+    if let Some(color) = &new_config.failure_color {
+        config.failure_color = color.clone();
+    }
+    if let Some(probability) = new_config
+        .outage_probability
+        .filter(|v| (0.0..=1.0).contains(v))
+    {
+        config.outage_probability = probability;
+    }
+    if let Some(duration) = new_config.outage_duration_ms.filter(|v| *v >= 0) {
+        config.outage_duration_ms = duration;
+    }
+    if let Some(value) = new_config.failure_scales_with_weight {
+        config.failure_scales_with_weight = value;
+    }
+    if let Some(prefix) = &new_config.unfair_prefix {
+        config.unfair_prefix = prefix.clone();
+    }
+    if let Some(retries) = new_config.internal_retries.filter(|v| *v >= 0) {
+        config.internal_retries = retries;
+    }
+    if let Some(rate) = new_config
+        .broken_content_length_rate
+        .filter(|v| (0.0..=1.0).contains(v))
+    {
+        config.broken_content_length_rate = rate;
+    }
+    if let Some(rps) = new_config.leak_rate_rps.filter(|v| *v >= 0.0) {
+        config.leak_rate_rps = rps;
+    }
+    if let Some(weight) = new_config
+        .health_load_weight
+        .filter(|v| v.is_finite() && *v >= 0.0)
+    {
+        config.health_load_weight = weight;
+    }
+    if let Some(weight) = new_config
+        .health_queue_weight
+        .filter(|v| v.is_finite() && *v >= 0.0)
+    {
+        config.health_queue_weight = weight;
+    }
+    if let Some(mode) = new_config
+        .health_score_mode
+        .as_ref()
+        .filter(|v| HEALTH_SCORE_MODES.contains(&v.as_str()))
+    {
+        config.health_score_mode = mode.clone();
+    }
+    if let Some(lag) = new_config.replica_lag_ms.filter(|v| *v >= 0) {
+        config.replica_lag_ms = lag;
+    }
+    if let Some(jitter) = new_config.replica_lag_jitter_ms.filter(|v| *v >= 0) {
+        config.replica_lag_jitter_ms = jitter;
+    }
+    if let Some(value) = new_config.per_id_metrics {
+        config.per_id_metrics = value;
+    }
+    if let Some(value) = new_config.failure_takes_precedence {
+        config.failure_takes_precedence = value;
+    }
+    if let Some(code) = new_config
+        .success_status_code
+        .filter(|v| SUCCESS_STATUS_CODES.contains(v))
+    {
+        config.success_status_code = code;
+    }
+    if let Some(value) = new_config.accepted_location {
+        config.accepted_location = value;
+    }
+    if let Some(bytes) = new_config.leak_bytes_per_request.filter(|v| *v >= 0) {
+        config.leak_bytes_per_request = bytes;
+    }
+    if let Some(timeout) = new_config.accept_timeout_ms.filter(|v| *v >= 0) {
+        config.accept_timeout_ms = timeout;
+    }
+    if let Some(timeout) = new_config.dependency_timeout_ms.filter(|v| *v >= 0) {
+        config.dependency_timeout_ms = timeout;
+    }
+    if let Some(rate) = new_config
+        .truncate_response_rate
+        .filter(|v| (0.0..=1.0).contains(v))
+    {
+        config.truncate_response_rate = rate;
+    }
+    if let Some(period) = new_config.diurnal_period_ms.filter(|v| *v > 0) {
+        config.diurnal_period_ms = period;
+    }
+    if let Some(amplitude) = new_config
+        .diurnal_amplitude
+        .filter(|v| (0.0..=1.0).contains(v))
+    {
+        config.diurnal_amplitude = amplitude;
+    }
+    if let Some(value) = new_config.include_queue_position {
+        config.include_queue_position = value;
+    }
+    if let Some(value) = new_config.single_flight {
+        config.single_flight = value;
+    }
+    if let Some(table) = new_config
+        .latency_table
+        .as_ref()
+        .filter(|t| valid_latency_table(t))
+    {
+        config.latency_table = table.clone();
+    }
+    if let Some(value) = new_config.task_steps {
+        config.task_steps = value;
+    }
+    if let Some(timeout) = new_config.processing_timeout_ms.filter(|v| *v >= 0) {
+        config.processing_timeout_ms = timeout;
+    }
+    if let Some(value) = new_config.stream_trailers {
+        config.stream_trailers = value;
+    }
+    if let Some(value) = new_config.auto_id {
+        config.auto_id = value;
+    }
+    if let Some(rate) = new_config
+        .health_flap_rate
+        .filter(|v| (0.0..=1.0).contains(v))
+    {
+        config.health_flap_rate = rate;
+    }
+    if let Some(ids) = &new_config.trace_task_ids {
+        config.trace_task_ids = ids.clone();
+    }
+    if let Some(size) = new_config
+        .payload_size_bytes
+        .filter(|v| (0..=MAX_RESPONSE_SIZE_BYTES).contains(v))
+    {
+        config.payload_size_bytes = size;
+    }
+    if let Some(alignment) = new_config
+        .response_size_alignment
+        .filter(|v| (0..=MAX_RESPONSE_SIZE_BYTES).contains(v))
+    {
+        config.response_size_alignment = alignment;
+    }
+    if let Some(delay) = new_config.config_read_delay_ms.filter(|v| *v >= 0) {
+        config.config_read_delay_ms = delay;
+    }
+    if let Some(targets) = new_config
+        .downstream_targets
+        .as_ref()
+        .filter(|t| valid_downstream_targets(t))
+    {
+        config.downstream_targets = targets.clone();
+    }
+    if let Some(fanout) = new_config
+        .downstream_fanout
+        .filter(|v| (1..=MAX_DOWNSTREAM_FANOUT).contains(v))
+    {
+        config.downstream_fanout = fanout;
+    }
+    if let Some(quorum) = new_config.fanout_quorum.filter(|v| *v >= 0) {
+        config.fanout_quorum = quorum;
+    }
+    if let Some(ms) = new_config.health_cache_ms.filter(|v| *v >= 0) {
+        config.health_cache_ms = ms;
+    }
+    if let Some(rate) = new_config
+        .schema_drift_rate
+        .filter(|v| (0.0..=1.0).contains(v))
+    {
+        config.schema_drift_rate = rate;
+    }
+    if let Some(size) = new_config
+        .max_batch_size
+        .filter(|v| (1..=MAX_BATCH_SIZE_LIMIT).contains(v))
+    {
+        config.max_batch_size = size;
+    }
+    // Handle queue_size change; permits are added by the caller
+    if let Some(new_queue_size) = new_config
+        .queue_size
+        .filter(|v| *v > 0)
+        .map(clamp_queue_size)
+        .filter(|v| *v != config.queue_size)
+    {
+        // Note: Decreasing semaphore permits atomically is complex in Tokio;
+        // for simplicity, we only support increasing. Decreasing requires
+        // acquiring permits which may block. Log a warning if decrease attempted.
+        if new_queue_size < config.queue_size {
+            tracing::warn!(
+                "Cannot decrease queue_size from {} to {} at runtime; only increases are supported",
+                config.queue_size,
+                new_queue_size
+            );
+        } else {
+            config.queue_size = new_queue_size;
+        }
+    }
+}
+
+/// HTML に埋め込む文字列をエスケープする。
+fn escape_html(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// ワーカー名・色・バージョンと利用可能なエンドポイントを返すルートパスのハンドラ。
+///
+/// 人がブラウザや curl で直接アクセスしたときの案内用で、`Accept` に `text/html` が
+/// 含まれる場合は簡単な HTML ページを、それ以外は `IndexResponse` の JSON を返す。
+/// メトリクスは記録しない。
+async fn handle_index(State(state): State<Arc<AppState>>, headers: HeaderMap) -> impl IntoResponse {
+    let wants_html = headers
+        .get(header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.contains("text/html"));
+
+    if !wants_html {
+        return Json(IndexResponse {
+            worker: state.worker_name.clone(),
+            color: state.worker_color.clone(),
+            version: state.worker_version.clone(),
+            endpoints: state.endpoints(),
+        })
+        .into_response();
+    }
+
+    let name = escape_html(&state.worker_name);
+    let color = escape_html(&state.worker_color);
+    let items: String = state
+        .endpoints()
+        .iter()
+        .map(|e| format!("<li><code>{}</code></li>", e))
+        .collect();
+    Html(format!(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>{name}</title></head>\
+         <body><h1 style=\"color: {color}\">{name}</h1>\
+         <p>version {version}</p><ul>{items}</ul></body></html>",
+        version = escape_html(&state.worker_version),
+    ))
+    .into_response()
+}
+
+/// 次の N 回の `/task` の結果を事前に固定するデバッグ用ハンドラ。
+///
+/// `{ "outcome": "success" | "fail" | "overload", "count": N }` を受け取り、`count` が 0 なら指定を解除する。
+/// `DEBUG_ENDPOINTS` が有効な場合のみルーティングされ、管理者認証が必要。現在の指定状態を返す。
+async fn handle_debug_force(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(request): Json<ForceRequest>,
+) -> Response {
+    if let Some(resp) = state.reject_unauthorized_admin(&headers) {
+        return resp;
+    }
+    let mut forced = state.forced_outcome.lock();
+    *forced = (request.count > 0).then_some((request.outcome, request.count));
+    tracing::info!("Forced outcome set: {:?}", *forced);
+    Json(ForceResponse {
+        outcome: forced.map(|(o, _)| o),
+        remaining: forced.map_or(0, |(_, n)| n),
+    })
+    .into_response()
+}
+
+/// 起動時の設定とそれ以降に適用された設定の履歴を古い順に返す管理用ハンドラ。
+///
+/// 最大 `CONFIG_HISTORY_LIMIT` 件まで保持し、ライブセッション中にレイテンシが変化した理由を
+/// 設定変更の時刻と突き合わせて調べられるようにする。管理者認証が必要。
+async fn handle_config_history(State(state): State<Arc<AppState>>, headers: HeaderMap) -> Response {
+    if let Some(resp) = state.reject_unauthorized_admin(&headers) {
+        return resp;
+    }
+    let history: Vec<ConfigHistoryEntry> = state.config_history.lock().iter().cloned().collect();
+    Json(history).into_response()
+}
+
+/// `error_response` で返した直近のエラーを古い順に返す管理用ハンドラ。
+///
+/// 最大 `RECENT_ERRORS_LIMIT` 件まで保持し、失敗が急増したときにログを探さずに実際のエラー本文
+/// （時刻、`/task` であれば id、ステータス）を確認できるようにする。管理者認証が必要。
+async fn handle_recent_errors(State(state): State<Arc<AppState>>, headers: HeaderMap) -> Response {
+    if let Some(resp) = state.reject_unauthorized_admin(&headers) {
+        return resp;
+    }
+    let recent: Vec<RecentError> = state.recent_errors.lock().iter().cloned().collect();
+    Json(recent).into_response()
+}
+
+/// `GET /health` で観測したヘルス状態の遷移を古い順に返す管理用ハンドラ。
+///
+/// 最大 `HEALTH_HISTORY_LIMIT` 件まで保持し、`healthy` と `degraded` を行き来するような、
+/// その時点のヘルスチェックだけでは見えない不安定さを確認できるようにする。管理者認証が必要。
+async fn handle_health_history(State(state): State<Arc<AppState>>, headers: HeaderMap) -> Response {
+    if let Some(resp) = state.reject_unauthorized_admin(&headers) {
+        return resp;
+    }
+    let history: Vec<HealthTransition> = state.health_history.lock().iter().cloned().collect();
+    Json(history).into_response()
+}
+
+/// `RECORD_EVENTS` で記録した `/task` のイベント（`TaskEvent`）を古い順に返す管理用ハンドラ。
+///
+/// `?since_seq=` に最後に受け取った `seq` を渡してポーリングすれば、テストでリクエストの時系列を欠けも重複もなく
+/// 組み立て直せる（`TASK_EVENTS_LIMIT` を超えて捨てられた分を除く）。`?since=` に RFC 3339 の時刻を渡すとそれより後の
+/// イベントに絞れるが、同じ時刻に記録されたイベントを取りこぼしうる。両方指定した場合は両方の条件で絞る。
+/// 記録が無効なら 404、`since` が解釈できなければ 400 を返す。管理者認証が必要。
+async fn handle_events(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Query(query): Query<EventsQuery>,
+) -> Response {
+    if let Some(resp) = state.reject_unauthorized_admin(&headers) {
+        return resp;
+    }
+    let Some(events) = &state.task_events else {
+        return state.error_response(StatusCode::NOT_FOUND, "Event recording is disabled");
+    };
+    let since = match query
+        .since
+        .as_deref()
+        .map(chrono::DateTime::parse_from_rfc3339)
+    {
+        Some(Ok(since)) => Some(
+            since
+                .with_timezone(&chrono::Utc)
+                .to_rfc3339_opts(chrono::SecondsFormat::Nanos, true),
+        ),
+        Some(Err(_)) => {
+            return state.error_response(StatusCode::BAD_REQUEST, "Invalid since timestamp")
+        }
+        None => None,
+    };
+    let events: Vec<TaskEvent> = events
+        .lock()
+        .iter()
+        .filter(|e| since.as_ref().is_none_or(|since| e.timestamp > *since))
+        .filter(|e| query.since_seq.is_none_or(|since_seq| e.seq > since_seq))
+        .cloned()
+        .collect();
+    Json(events).into_response()
+}
+
+/// 容量計画の議論向けに、直近の処理結果を要約した `CapacityReport` を返す管理用ハンドラ。
+///
+/// Prometheus のクエリを書かずに、スループット・利用率・拒否率・レイテンシをひと目で確認できる。
+/// 状態は変更しない。管理者認証が必要。
+async fn handle_report(State(state): State<Arc<AppState>>, headers: HeaderMap) -> Response {
+    if let Some(resp) = state.reject_unauthorized_admin(&headers) {
+        return resp;
+    }
+    Json(state.capacity_report()).into_response()
+}
+
+/// 合成したタスクを HTTP を通さずに `process_task` で処理し、その結果と所要時間を返す管理用ハンドラ。
+///
+/// 負荷の比率だけでなく処理経路そのものが動いていることを確かめるためのもので、遅延や失敗率などの設定は
+/// 通常のタスクと同様に適用される。タスクが成功しなかった場合は 503 を返す。管理者認証が必要。
+async fn handle_selftest(State(state): State<Arc<AppState>>, headers: HeaderMap) -> Response {
+    if let Some(resp) = state.reject_unauthorized_admin(&headers) {
+        return resp;
+    }
+    let task = TaskRequest {
+        id: SELFTEST_TASK_ID.to_string(),
+        weight: None,
+        profile: None,
+        force_error: None,
+        depends_on: None,
+        steps: None,
+    };
+    let start = Instant::now();
+    let response = process_task(&state, TaskQuery::default(), task).await;
+    let latency_ms = start.elapsed().as_secs_f64() * 1000.0;
+    let status = response.status();
+    let error = if status.is_success() {
+        None
+    } else {
+        let body = axum::body::to_bytes(response.into_body(), PARSE_GUARD_BODY_LIMIT)
+            .await
+            .unwrap_or_default();
+        Some(
+            serde_json::from_slice::<ErrorResponse>(&body)
+                .map(|e| e.error)
+                .unwrap_or_else(|_| String::from_utf8_lossy(&body).into_owned()),
+        )
+    };
+    let code = if error.is_none() {
+        StatusCode::OK
+    } else {
+        tracing::warn!("Self-test failed with status {}", status);
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (
+        code,
+        Json(SelfTestReport {
+            ok: error.is_none(),
+            status: status.as_u16(),
+            latency_ms,
+            error,
+        }),
+    )
+        .into_response()
+}
+
+/// タスクの処理を一時停止する管理用ハンドラ。
+///
+/// 停止中のタスクは拒否されずにキューの範囲内で待機するため、`queue_depth` が積み上がる様子を
+/// そのまま観察できる。処理中のタスクには影響しない。管理者認証が必要。
+async fn handle_pause(State(state): State<Arc<AppState>>, headers: HeaderMap) -> Response {
+    if let Some(resp) = state.reject_unauthorized_admin(&headers) {
+        return resp;
+    }
+    state.set_paused(true);
+    StatusCode::NO_CONTENT.into_response()
+}
+
+/// `POST /pause` による一時停止を解除し、待機中のタスクを再開させる管理用ハンドラ。管理者認証が必要。
+async fn handle_resume(State(state): State<Arc<AppState>>, headers: HeaderMap) -> Response {
+    if let Some(resp) = state.reject_unauthorized_admin(&headers) {
+        return resp;
+    }
+    state.set_paused(false);
+    StatusCode::NO_CONTENT.into_response()
+}
+
+/// 観測用に蓄積している統計値を初期化する管理用ハンドラ。
+///
+/// キュー深度の最高水位（`worker_queue_depth_max`）を 0 に戻し、許可の利用率（`worker_permit_utilization`）の
+/// 集計と `/report` 用に記録した結果をやり直す。`leak_bytes_per_request` で保持していたメモリも解放する。管理者認証が必要。
+async fn handle_reset(State(state): State<Arc<AppState>>, headers: HeaderMap) -> Response {
+    if let Some(resp) = state.reject_unauthorized_admin(&headers) {
+        return resp;
+    }
+    state.queue_depth_max.store(0, Ordering::SeqCst);
+    gauge!("worker_queue_depth_max", "worker" => state.worker_name.clone()).set(0.0);
+    state.reset_permit_utilization();
+    state.request_samples.lock().clear();
+    gauge!("worker_permit_utilization", "worker" => state.worker_name.clone()).set(0.0);
+    state.release_leaked_memory();
+    tracing::info!("Statistics reset");
+    StatusCode::NO_CONTENT.into_response()
+}
+
+/// テストの同期点として、バッファされている出力をすべて書き出してから 200 を返す管理用ハンドラ。
+///
+/// 定期更新のゲージ（`worker_permit_utilization`）を即座に最新化し、ログの出力先である
+/// 標準出力・標準エラーをフラッシュする。バッファを持たない出力には何もしない。管理者認証が必要。
+async fn handle_flush(State(state): State<Arc<AppState>>, headers: HeaderMap) -> Response {
+    if let Some(resp) = state.reject_unauthorized_admin(&headers) {
+        return resp;
+    }
+    state.publish_permit_utilization();
+    if let Err(err) = io::stdout().flush().and_then(|_| io::stderr().flush()) {
+        tracing::warn!("Failed to flush log output: {}", err);
+        return state.error_response(StatusCode::INTERNAL_SERVER_ERROR, "Flush failed");
+    }
+    StatusCode::OK.into_response()
+}
+
+/// Prometheus のメトリクスをレンダリングして HTTP レスポンスの本文を生成するハンドラ。
+///
+/// 返り値は Prometheus ハンドラがレンダリングしたメトリクス本文（テキスト）で、HTTP のレスポンス本文として返却されます。
+/// ラベルの種類が多いと描画が重くなるため、描画と絞り込みは `spawn_blocking` で行い、ランタイムのワーカースレッドを塞がないようにします。
+/// 本文は `METRICS_CHUNK_BYTES` ごとに分けて送り、描画にかかった時間は `worker_metrics_render_duration_ms` に記録します。
+/// `?prefix=worker_request` のように指定すると、名前がその接頭辞で始まるメトリクス（`# HELP`・`# TYPE` 行を含む）だけに絞り込みます。
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::sync::Arc;
+/// use axum::extract::State;
+///
+/// // `state` はサーバの共有状態で、内部に `prometheus_handle` を保持している想定です。
+/// # async fn example(state: Arc<AppState>) {
+/// let resp = handle_metrics(State(state), Query(MetricsQuery::default())).await;
+/// // `resp` はレンダリング済みメトリクスを含む HTTP レスポンスとなります。
+/// # }
+/// ```
+async fn handle_metrics(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<MetricsQuery>,
+) -> Response {
+    let handle = state.prometheus_handle.clone();
+    let started = Instant::now();
+    let rendered = tokio::task::spawn_blocking(move || {
+        let rendered = handle.render();
+        match query.prefix.filter(|p| !p.is_empty()) {
+            Some(prefix) => filter_metrics(&rendered, &prefix),
+            None => rendered,
+        }
+    })
+    .await;
+    histogram!("worker_metrics_render_duration_ms", "worker" => state.worker_name.clone())
+        .record(started.elapsed().as_secs_f64() * 1000.0);
+
+    let Ok(rendered) = rendered else {
+        return state.error_response(StatusCode::INTERNAL_SERVER_ERROR, "Metrics render failed");
+    };
+    let chunks: Vec<_> = rendered
+        .as_bytes()
+        .chunks(METRICS_CHUNK_BYTES)
+        .map(|chunk| Ok::<_, io::Error>(Bytes::copy_from_slice(chunk)))
+        .collect();
+    (
+        [(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("text/plain; charset=utf-8"),
+        )],
+        Body::from_stream(stream::iter(chunks)),
+    )
+        .into_response()
+}
+
+/// Prometheus のテキスト形式から、名前が `prefix` で始まるメトリクスの行だけを残す。
+///
+/// サンプル行は `{` か空白までを、`# HELP`・`# TYPE` 行は 3 番目の語をメトリクス名とみなす。
+fn filter_metrics(rendered: &str, prefix: &str) -> String {
+    rendered
+        .lines()
+        .filter(|line| {
+            let name = match line.strip_prefix('#') {
+                Some(comment) => comment.split_whitespace().nth(1).unwrap_or_default(),
+                None => line.split(['{', ' ']).next().unwrap_or_default(),
+            };
+            !name.is_empty() && name.starts_with(prefix)
+        })
+        .fold(String::new(), |mut out, line| {
+            out.push_str(line);
+            out.push('\n');
+            out
+        })
+}
+
+/// ハンドラ内のパニックを 500 の `ErrorResponse` に変換し、`worker_panics_total` を加算する。
+///
+/// `CatchPanicLayer` から呼ばれ、パニックのペイロードが文字列であればログに残す。
+/// クライアントにはパニック内容を返さず、固定のエラーメッセージのみを返す。
+fn panic_response(worker_name: &str, err: Box<dyn Any + Send + 'static>) -> Response {
+    let detail = err
+        .downcast_ref::<String>()
+        .map(String::as_str)
+        .or_else(|| err.downcast_ref::<&str>().copied())
+        .unwrap_or("unknown panic payload");
+    tracing::error!("Handler panicked: {}", detail);
+    counter!("worker_panics_total", "worker" => worker_name.to_string()).increment(1);
+
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(ErrorResponse {
+            error: "Internal server error".to_string(),
+            worker: worker_name.to_string(),
+            color: None,
+        }),
+    )
+        .into_response()
+}
+
+/// 許可の利用率を `PERMIT_UTILIZATION_INTERVAL` ごとに `worker_permit_utilization` ゲージへ反映し続ける。
+///
+/// 値が低いままならキューや同時実行数の設定が負荷に対して過大であることを示す。
+async fn report_permit_utilization(state: Arc<AppState>) {
+    let mut ticker = tokio::time::interval(PERMIT_UTILIZATION_INTERVAL);
+    loop {
+        ticker.tick().await;
+        state.publish_permit_utilization();
+    }
+}
+
+/// `RESPONSE_HEADERS` で指定されたヘッダーをすべてのレスポンスに付与するミドルウェア。
+///
+/// 同名のヘッダーがハンドラで設定されている場合は指定値で置き換える。
+async fn inject_response_headers(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let mut response = next.run(request).await;
+    for name in state.response_headers.keys() {
+        response.headers_mut().remove(name);
+    }
+    for (name, value) in &state.response_headers {
+        response.headers_mut().append(name, value.clone());
+    }
+    response
+}
+
+/// `MAX_PARSE_MS` が設定されている場合に、解析時間を制限して本文の JSON を `T` として読む抽出器。
+///
+/// 本文を 1 度だけ別スレッドで `T` に解析し、制限時間を過ぎた時点で解析そのものを打ち切って 400 を返す
+/// （`worker_parse_timeouts_total` に計上）。本文は `PARSE_GUARD_BODY_LIMIT` まで（超えれば 413）。
+/// 構文エラーや `Content-Type` の誤りは `Json` 抽出器と同じ応答にする。未設定なら `Json` と同じ。
+struct TimedJson<T>(T);
+
+impl<T: DeserializeOwned + Send + 'static> FromRequest<Arc<AppState>> for TimedJson<T> {
+    type Rejection = Response;
+
+    async fn from_request(request: Request, state: &Arc<AppState>) -> Result<Self, Response> {
+        let limit = match state.max_parse {
+            Some(limit) if has_json_content_type(request.headers()) => limit,
+            _ => return plain_json(request, state).await,
+        };
+        let (parts, body) = request.into_parts();
+        let Ok(bytes) = axum::body::to_bytes(body, PARSE_GUARD_BODY_LIMIT).await else {
+            return Err(
+                state.error_response(StatusCode::PAYLOAD_TOO_LARGE, "Request body too large")
+            );
+        };
+        match parse_json_within::<T>(bytes.clone(), limit).await {
+            Ok(value) => Ok(TimedJson(value)),
+            Err(ParseFailure::TimedOut) => {
+                counter!("worker_parse_timeouts_total", "worker" => state.worker_name.clone())
+                    .increment(1);
+                Err(state.error_response(
+                    StatusCode::BAD_REQUEST,
+                    format!(
+                        "Request body took longer than {}ms to parse",
+                        limit.as_millis()
+                    ),
+                ))
+            }
+            // Only the error path reads the body again, to give the usual extractor rejection
+            Err(ParseFailure::Invalid) => {
+                plain_json(Request::from_parts(parts, Body::from(bytes)), state).await
+            }
+        }
+    }
+}
+
+/// `TimedJson` の代わりに `Json` 抽出器で本文を読む。
+async fn plain_json<T: DeserializeOwned>(
+    request: Request,
+    state: &Arc<AppState>,
+) -> Result<TimedJson<T>, Response> {
+    Json::<T>::from_request(request, state)
+        .await
+        .map(|Json(value)| TimedJson(value))
+        .map_err(IntoResponse::into_response)
+}
+
+/// `Json` 抽出器が受け付ける `Content-Type`（`application/json` か `application/*+json`）かどうか。
+fn has_json_content_type(headers: &HeaderMap) -> bool {
+    let Some(content_type) = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+    else {
+        return false;
+    };
+    let essence = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    essence == "application/json"
+        || (essence.starts_with("application/") && essence.ends_with("+json"))
+}
+
+/// `parse_json_within` が解析に失敗した理由。
+#[derive(Debug, PartialEq)]
+enum ParseFailure {
+    TimedOut,
+    Invalid,
+}
+
+/// `bytes` を `limit` 以内に `T` として解析する。解析はブロッキング用のスレッドで行い、時間切れになった時点で止める。
+async fn parse_json_within<T: DeserializeOwned + Send + 'static>(
+    bytes: Bytes,
+    limit: Duration,
+) -> Result<T, ParseFailure> {
+    let deadline = Instant::now() + limit;
+    let decode = tokio::task::spawn_blocking(move || {
+        serde_json::from_reader::<_, T>(DeadlineReader {
+            bytes: &bytes,
+            deadline,
+            reads: 0,
+        })
+    });
+    match decode.await {
+        Ok(Ok(value)) => Ok(value),
+        // The reader is the only source of I/O errors, and it only fails once the deadline passes
+        Ok(Err(err)) if err.is_io() => Err(ParseFailure::TimedOut),
+        _ => Err(ParseFailure::Invalid),
+    }
+}
+
+/// `deadline` を過ぎると読み込みを失敗させる、メモリ上の本文のリーダー。
+///
+/// `serde_json::from_reader` は少しずつ読み進めるため、解析の途中で打ち切れる。時刻は `DEADLINE_CHECK_INTERVAL` 回の
+/// 読み込みごとに確かめる。
+struct DeadlineReader<'a> {
+    bytes: &'a [u8],
+    deadline: Instant,
+    reads: u32,
+}
+
+impl io::Read for DeadlineReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.reads.is_multiple_of(DEADLINE_CHECK_INTERVAL) && Instant::now() >= self.deadline {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "parse deadline exceeded",
+            ));
+        }
+        self.reads = self.reads.wrapping_add(1);
+        self.bytes.read(buf)
+    }
+}
+
+/// `STATE_CHECKPOINT_INTERVAL` ごとに累積のカウンタを `STATE_FILE` に書き出し続ける。
+async fn checkpoint_state(state: Arc<AppState>) {
+    let mut ticker = tokio::time::interval(STATE_CHECKPOINT_INTERVAL);
+    loop {
+        ticker.tick().await;
+        state.checkpoint();
+    }
+}
+
+/// `PUSHGATEWAY_INTERVAL` ごとに現在のメトリクスを Pushgateway へ送り続ける。
+async fn push_metrics_periodically(state: Arc<AppState>) {
+    let mut ticker = tokio::time::interval(PUSHGATEWAY_INTERVAL);
+    loop {
+        ticker.tick().await;
+        state.push_metrics().await;
+    }
+}
+
+/// Pushgateway のベース URL `base` から、ジョブ名 `job` のグループへの送信先を組み立てる。
+///
+/// `job` はパスの 1 セグメントとしてエスケープする。`base` が URL として不正なら `None`。
+///
+/// # Examples
+///
+/// ```
+/// let url = pushgateway_push_url("http://pushgateway:9091/", "rust-worker").unwrap();
+/// assert_eq!(url.as_str(), "http://pushgateway:9091/metrics/job/rust-worker");
+/// ```
+fn pushgateway_push_url(base: &str, job: &str) -> Option<reqwest::Url> {
+    let mut url = reqwest::Url::parse(base).ok()?;
+    url.path_segments_mut()
+        .ok()?
+        .pop_if_empty()
+        .extend(["metrics", "job", job]);
+    Some(url)
+}
+
+/// 起動からの経過時間 `elapsed` における、周期 `period_ms` の日周変動の波形（-1.0..=1.0 の正弦波）。
+fn diurnal_wave(elapsed: Duration, period_ms: i32) -> f64 {
+    let period = period_ms.max(1) as f64;
+    let phase = (elapsed.as_millis() as f64 % period) / period;
+    (phase * std::f64::consts::TAU).sin()
+}
+
+/// `health_cache_ms` ごとに `handle_health` 用のキャッシュを更新し続ける。
+async fn refresh_health_cache(state: Arc<AppState>) {
+    loop {
+        let next = state.refresh_health_cache();
+        sleep(next).await;
+    }
+}
+
+/// `DIURNAL_UPDATE_INTERVAL` ごとに日周変動の波形を更新し、`worker_diurnal_factor` に反映し続ける。
+async fn simulate_diurnal(state: Arc<AppState>) {
+    let started = Instant::now();
+    let mut ticker = tokio::time::interval(DIURNAL_UPDATE_INTERVAL);
+    loop {
+        ticker.tick().await;
+        let config = state.config.read().clone();
+        let wave = diurnal_wave(started.elapsed(), config.diurnal_period_ms);
+        state.diurnal_wave.store(wave.to_bits(), Ordering::Relaxed);
+        gauge!("worker_diurnal_factor", "worker" => state.worker_name.clone())
+            .set(1.0 + config.diurnal_amplitude * wave);
+    }
+}
+
+/// `OUTAGE_CHECK_INTERVAL` ごとに擬似障害の抽選を行い続ける。
+async fn simulate_outages(state: Arc<AppState>) {
+    let mut ticker = tokio::time::interval(OUTAGE_CHECK_INTERVAL);
+    loop {
+        ticker.tick().await;
+        let config = state.config.read().clone();
+        state.roll_outage(&config, Instant::now());
+    }
+}
+
+/// エラーレスポンスを必要に応じて `application/problem+json` に書き換えるミドルウェア。
+///
+/// `problem_json` が有効な場合、またはリクエストの `Accept` に `application/problem+json` が含まれる場合に、
+/// 4xx/5xx のレスポンスを `into_problem_details` で変換する。それ以外はそのまま返す。
+async fn problem_json_errors(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let wants_problem = state.config.read().problem_json
+        || request
+            .headers()
+            .get(header::ACCEPT)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v.contains("application/problem+json"));
+    let response = next.run(request).await;
+    if !wants_problem {
+        return response;
+    }
+    into_problem_details(&state.worker_name, response).await
+}
+
+/// 4xx/5xx のレスポンスを RFC 7807 の問題詳細に変換する。ステータスとヘッダーは維持する。
+///
+/// `ErrorResponse` の `error` を `detail` に、ステータスの理由句を `title` にする。
+/// 抽出器のエラーなど `ErrorResponse` でない本文は、その文字列をそのまま `detail` にする。
+async fn into_problem_details(worker_name: &str, response: Response) -> Response {
+    let status = response.status();
+    if !(status.is_client_error() || status.is_server_error()) {
+        return response;
+    }
+    let (mut parts, body) = response.into_parts();
+    let bytes = axum::body::to_bytes(body, usize::MAX)
+        .await
+        .unwrap_or_default();
+    let detail = match serde_json::from_slice::<ErrorResponse>(&bytes) {
+        Ok(error) => error.error,
+        Err(_) => String::from_utf8_lossy(&bytes).into_owned(),
+    };
+    let problem = ProblemDetails {
+        problem_type: "about:blank",
+        title: status.canonical_reason().unwrap_or("Error").to_string(),
+        status: status.as_u16(),
+        detail,
+        worker: worker_name.to_string(),
+    };
+
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts.headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/problem+json"),
+    );
+    Response::from_parts(
+        parts,
+        Body::from(serde_json::to_vec(&problem).unwrap_or_default()),
+    )
+}
+
+/// Ctrl+C またはプロセス終了シグナルを待機し、受信したら段階的なシャットダウンを始める。
+///
+/// UNIX プラットフォームでは terminate シグナルも監視し、`MAX_LIFETIME_REQUESTS` に達した場合もシグナルと同じ手順で終了する。
+/// シグナルを受けるとまず `/ready` を 503 に切り替え、
+/// ロードバランサーが気付くまで `pre_stop_delay` だけ待つ。その間もタスクは通常通り処理する。
+/// この関数が返った後は、axum のグレースフルシャットダウンが処理中のリクエストの完了を待ってから終了する。
+///
+/// # Examples
+///
+/// ```ignore
+/// // シグナル受信後、readiness を落として 5 秒待ってからドレインに移る。
+/// axum::serve(listener, app)
+///     .with_graceful_shutdown(shutdown_signal(state, Duration::from_secs(5)))
+///     .await?;
+/// ```
+async fn shutdown_signal(state: Arc<AppState>, pre_stop_delay: Duration) {
+    let ctrl_c = async {
+        signal::ctrl_c()
+            .await
+            .expect("failed to install Ctrl+C handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        signal::unix::signal(signal::unix::SignalKind::terminate())
+            .expect("failed to install signal handler")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    let reason = tokio::select! {
+        _ = ctrl_c => "Shutdown signal received",
+        _ = terminate => "Shutdown signal received",
+        _ = state.lifetime_exhausted.notified() => "Lifetime request limit reached",
+    };
+
+    tracing::info!("{}; marking worker not ready", reason);
+
+    state.begin_shutdown();
+    if !pre_stop_delay.is_zero() {
+        tracing::info!(
+            "Waiting {}ms for load balancers to stop routing traffic",
+            pre_stop_delay.as_millis()
+        );
+        sleep(pre_stop_delay).await;
+    }
+    tracing::info!("Draining in-flight requests");
+}
+
+/// `UNIX_SOCKET_PATH` に Unix ドメインソケットを作って待ち受ける。
+///
+/// 前回の異常終了で残ったソケットファイルがあれば消してから作り直す。ソケット以外のファイルがある場合は消さずにエラーにする。
+#[cfg(unix)]
+fn bind_unix_socket(path: &Path) -> io::Result<tokio::net::UnixListener> {
+    use std::os::unix::fs::FileTypeExt;
+
+    if fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+        fs::remove_file(path)?;
+    }
+    tokio::net::UnixListener::bind(path)
+}
+
+/// TCP と同じアプリケーションを Unix ドメインソケットでも提供し、`shutdown` の後に処理中のリクエストを終えたらソケットファイルを消す。
+///
+/// 同一ホストのサイドカーからの接続向けのため、`MAX_CONNECTIONS` による接続数の制限は適用しない。
+#[cfg(unix)]
+async fn serve_unix_socket(
+    listener: tokio::net::UnixListener,
+    path: PathBuf,
+    app: Router,
+    shutdown: impl std::future::Future<Output = ()> + Send + 'static,
+) {
+    if let Err(err) = axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown)
+        .await
+    {
+        tracing::error!("Unix socket server failed: {}", err);
+    }
+    if let Err(err) = fs::remove_file(&path) {
+        tracing::warn!("Failed to remove unix socket {}: {}", path.display(), err);
+    }
+}
+
+/// `HEAD /task` のハンドラ。タスクは処理せず、受け付けの余力をヘッダーで返す。
+///
+/// `X-Queue-Available` にキューの空き枠、`X-Concurrency-Available` に同時実行の空き枠を入れる。
+/// キューに空きがあり、ドレイン中でなければ 200、そうでなければ 503 を返す。実際に受け付けるかは
+/// `POST /task` の時点の状態で決まるため、クライアントが事前に安く確認するための目安として使う。
+async fn handle_task_head(State(state): State<Arc<AppState>>) -> Response {
+    let queue_available = {
+        let config = state.config.read();
+        (config.queue_size - state.queue_depth(&config)).max(0)
+    };
+    let concurrency_available = state.concurrency_semaphore.available_permits();
+    let accepting = queue_available > 0 && !state.draining.load(Ordering::SeqCst);
+    let code = if accepting {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (
+        code,
+        [
+            (QUEUE_AVAILABLE_HEADER, queue_available.to_string()),
+            (
+                CONCURRENCY_AVAILABLE_HEADER,
+                concurrency_available.to_string(),
+            ),
+        ],
+    )
+        .into_response()
+}
+
+/// 新しいトラフィックを受けてよいかを返すハンドラ。
+///
+/// ドレイン中、またはシャットダウンが始まっている場合は 503 を返す。`/health` と異なり負荷は考慮しない。
+async fn handle_ready(State(state): State<Arc<AppState>>) -> Response {
+    let ready =
+        !state.draining.load(Ordering::SeqCst) && !state.shutting_down.load(Ordering::SeqCst);
+    let code = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (code, Json(serde_json::json!({ "ready": ready }))).into_response()
+}
+
+/// SIGUSR1 でドレイン状態に入り、SIGUSR2 でドレイン状態を抜けるシグナル監視タスク。
+///
+/// HTTP を介さずにオペレーターがワーカーをソフトドレインできるようにする。
+/// SIGTERM や Ctrl+C は引き続き `shutdown_signal` による完全なシャットダウンとして扱われる。
+#[cfg(unix)]
+async fn drain_signals(state: Arc<AppState>) {
+    use signal::unix::{signal, SignalKind};
+
+    let mut enter = signal(SignalKind::user_defined1()).expect("failed to install SIGUSR1 handler");
+    let mut exit = signal(SignalKind::user_defined2()).expect("failed to install SIGUSR2 handler");
+    loop {
+        tokio::select! {
+            _ = enter.recv() => state.set_draining(true),
+            _ = exit.recv() => state.set_draining(false),
+        }
+    }
+}
+
+/// 同時に保持する TCP 接続数を上限で制限するリスナー。
+///
+/// 上限に達している間は新しい接続を accept せずに待たせ（カーネルの backlog に保留される）、
+/// 既存の接続が閉じて枠が空き次第受け付ける。リクエスト単位のセマフォとは独立に、
+/// 接続の大量発生によるファイルディスクリプタ枯渇を防ぐ。
+struct LimitedListener {
+    inner: TcpListener,
+    permits: Arc<Semaphore>,
+    max_connections: usize,
+    worker_name: String,
+}
+
+impl LimitedListener {
+    fn new(inner: TcpListener, max_connections: Option<usize>, worker_name: String) -> Self {
+        let max_connections = max_connections.unwrap_or(Semaphore::MAX_PERMITS);
+        Self {
+            inner,
+            permits: Arc::new(Semaphore::new(max_connections)),
+            max_connections,
+            worker_name,
+        }
+    }
+}
+
+impl axum::serve::Listener for LimitedListener {
+    type Io = LimitedConnection;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        let permit = Arc::clone(&self.permits)
+            .acquire_owned()
+            .await
+            .expect("connection semaphore is never closed");
+        let (stream, addr) = axum::serve::Listener::accept(&mut self.inner).await;
+        let connection = LimitedConnection {
+            stream,
+            permit: Some(permit),
+            permits: Arc::clone(&self.permits),
+            max_connections: self.max_connections,
+            worker_name: self.worker_name.clone(),
+        };
+        connection.record_active();
+        (connection, addr)
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        self.inner.local_addr()
+    }
+}
+
+/// `LimitedListener` が受け付けた接続。破棄されると接続枠を返却する。
+struct LimitedConnection {
+    stream: TcpStream,
+    permit: Option<OwnedSemaphorePermit>,
+    permits: Arc<Semaphore>,
+    max_connections: usize,
+    worker_name: String,
+}
+
+impl LimitedConnection {
+    fn record_active(&self) {
+        let active = self.max_connections - self.permits.available_permits();
+        gauge!("worker_active_connections", "worker" => self.worker_name.clone())
+            .set(active as f64);
+    }
+}
+
+impl Drop for LimitedConnection {
+    fn drop(&mut self) {
+        drop(self.permit.take());
+        self.record_active();
+    }
+}
+
+impl AsyncRead for LimitedConnection {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for LimitedConnection {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().stream).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.stream.is_write_vectored()
+    }
+}
+
+/// パスは存在するがメソッドが対応していないリクエストに、他のエラーと同じ形式の 405 を返すハンドラ。
+///
+/// 利用できるメソッドは axum が `Allow` ヘッダーとして付与する。
+async fn handle_method_not_allowed(State(state): State<Arc<AppState>>, method: Method) -> Response {
+    state.error_response(
+        StatusCode::METHOD_NOT_ALLOWED,
+        format!("Method {} not allowed", method),
+    )
+}
+
+/// 共有状態 `state` に対するルーターを組み立てる。
+///
+/// `DEBUG_ENDPOINTS` のルートは `state.debug_endpoints` が有効な場合のみ、`state.disabled_endpoints` のルートは登録しない。
+/// 登録済みのパスに対応していないメソッドで来たリクエストには `handle_method_not_allowed` が答える。
+/// CORS・パニック時の 500・RFC 7807 形式への変換・`RESPONSE_HEADERS` の付与もここで重ねる。
+fn build_router(state: Arc<AppState>) -> Router {
+    let cors = CorsLayer::new()
+        .allow_origin(cors::Any)
+        .allow_methods(cors::Any)
+        .allow_headers(cors::Any);
+
+    if state.debug_endpoints {
+        tracing::warn!(
+            "Debug endpoints enabled: {}",
+            DEBUG_ENDPOINT_ROUTES.join(", ")
+        );
+    }
+    let mut app = Router::new();
+    for (endpoint, handler) in endpoint_routes(state.debug_endpoints) {
+        // Disabled endpoints are never registered, so they 404 (or 405 beside other methods)
+        if state.disabled_endpoints.contains(&endpoint) {
+            continue;
+        }
+        let (_, path) = endpoint.split_once(' ').unwrap_or(("", endpoint));
+        app = app.route(path, handler);
+    }
+    let app = app.method_not_allowed_fallback(handle_method_not_allowed);
+    let panic_worker = state.worker_name.clone();
+    app.layer(cors)
+        .layer(CatchPanicLayer::custom(move |err| {
+            panic_response(&panic_worker, err)
+        }))
+        .layer(middleware::from_fn_with_state(
+            Arc::clone(&state),
+            problem_json_errors,
+        ))
+        .layer(middleware::from_fn_with_state(
+            Arc::clone(&state),
+            inject_response_headers,
+        ))
+        .with_state(state)
+}
+
+/// `build_router` が登録するエンドポイント名（`GET /health` のようなメソッドとパス）とハンドラーの一覧。
+///
+/// 名前は `GET /`・`ENDPOINTS`・（`debug_endpoints` が有効なら）`DEBUG_ENDPOINT_ROUTES` と一致させる。
+/// ルートを足したときに一覧の更新漏れがないことはテストで確かめている。
+fn endpoint_routes(debug_endpoints: bool) -> Vec<(&'static str, MethodRouter<Arc<AppState>>)> {
+    // Bodies go through TimedJson so MAX_PARSE_MS bounds the one parse each handler needs
+    let timed_config_update =
+        |state: State<Arc<AppState>>, TimedJson(update): TimedJson<ConfigUpdate>| {
+            handle_config_update(state, Json(update))
+        };
+    let mut routes: Vec<(&'static str, MethodRouter<Arc<AppState>>)> = vec![
+        ("GET /", get(handle_index)),
+        (
+            "POST /task",
+            post(
+                |state: State<Arc<AppState>>,
+                 query: Query<TaskQuery>,
+                 headers: HeaderMap,
+                 TimedJson(task): TimedJson<TaskRequest>| {
+                    handle_task(state, query, headers, Json(task))
+                },
+            ),
+        ),
+        ("HEAD /task", head(handle_task_head)),
+        ("POST /task/batch", post(handle_task_batch)),
+        ("GET /task/{id}/progress", get(handle_task_progress)),
+        ("GET /health", get(handle_health)),
+        ("GET /health/history", get(handle_health_history)),
+        ("GET /events", get(handle_events)),
+        ("GET /ready", get(handle_ready)),
+        ("GET /config", get(handle_config_get)),
+        ("POST /config", post(timed_config_update)),
+        (
+            "PUT /config",
+            put(
+                |state: State<Arc<AppState>>, TimedJson(body): TimedJson<serde_json::Value>| {
+                    handle_config_replace(state, Json(body))
+                },
+            ),
+        ),
+        ("PATCH /config", patch(timed_config_update)),
+        ("GET /config/history", get(handle_config_history)),
+        ("GET /errors/recent", get(handle_recent_errors)),
+        ("GET /metrics", get(handle_metrics)),
+        ("POST /reset", post(handle_reset)),
+        ("GET /report", get(handle_report)),
+        ("GET /selftest", get(handle_selftest)),
+        ("POST /flush", post(handle_flush)),
+        ("POST /pause", post(handle_pause)),
+        ("POST /resume", post(handle_resume)),
+    ];
+    if debug_endpoints {
+        routes.push(("POST /debug/force", post(handle_debug_force)));
+        routes.push(("GET /debug/env", get(handle_debug_env)));
+    }
+    routes
+}
+
+/// ソケットを開かずに、`config` と名前・色だけを与えたワーカーのアプリケーションを組み立てる。
+///
+/// 環境変数から読む起動オプションはすべて既定値になる。テストから `tower::ServiceExt::oneshot` で
+/// リクエストを送るためのもので、バックグラウンドタスクは起動しない。
+#[cfg(test)]
+fn build_app(config: Configuration, worker_name: &str, worker_color: &str) -> Router {
+    build_router(Arc::new(AppState::new(
+        config,
+        worker_name.to_string(),
+        worker_color.to_string(),
+    )))
+}
+
+/// アプリケーションのHTTPサーバーを初期化し、ルーティング・メトリクス・共有状態を構成して起動する。
+///
+/// 初期設定を環境変数から読み込み、Prometheus メトリクスをセットアップし、キュー用・同時実行用のセマフォを含む共有 AppState を作成します。CORS を有効にした Axum ルーターを構築し、/、/task、/health、/config、/metrics のエンドポイントを登録した後、指定ポートでリッスンしてグレースフルシャットダウンを待機します。
+///
+/// # Examples
+///
+/// ```no_run
+/// // 簡易的な起動例（環境変数で設定を与えてから実行）
+/// // WORKER_NAME=demo WORKER_COLOR="#000000" PORT=8080 cargo run
+/// ```
+#[tokio::main]
+async fn main() {
+    // Logs, including the sampled per-task lines, go to stderr and keep stdout free
+    tracing_subscriber::fmt().with_writer(io::stderr).init();
+
+    let mut config = load_config();
+    if get_env_bool("STRICT_CONFIG", false) {
+        let errors = strict_config_errors(&config);
+        if !errors.is_empty() {
+            for error in &errors {
+                tracing::error!("Invalid configuration: {}", error);
+            }
+            std::process::exit(1);
+        }
+    }
+    if config.per_id_metrics {
+        tracing::warn!("{}", PER_ID_METRICS_WARNING);
+    }
+    let delay_spread_pct = get_env_f64("DELAY_SPREAD_PCT", 0.0).clamp(0.0, 100.0);
+    if delay_spread_pct > 0.0 {
+        let offset = apply_delay_spread(&mut config, delay_spread_pct);
+        tracing::info!(
+            "Applied delay spread of {:+.1}% (range ±{}%): response_delay_ms={}",
+            offset,
+            delay_spread_pct,
+            config.response_delay_ms
+        );
+    }
+    let worker_name = env::var("WORKER_NAME").unwrap_or_else(|_| "rust-worker-1".to_string());
+    let worker_color = env::var("WORKER_COLOR").unwrap_or_else(|_| "#F97316".to_string());
+    let port = env::var("PORT").unwrap_or_else(|_| "8080".to_string());
+    let worker_version =
+        env::var("WORKER_VERSION").unwrap_or_else(|_| env!("CARGO_PKG_VERSION").to_string());
+    let canary_version =
+        env::var("CANARY_VERSION").unwrap_or_else(|_| format!("{}-canary", worker_version));
+
+    let config_change_webhook = env::var("CONFIG_CHANGE_WEBHOOK")
+        .ok()
+        .filter(|v| !v.is_empty());
+    let pushgateway_url = env::var("PUSHGATEWAY_URL")
+        .ok()
+        .filter(|v| !v.is_empty())
+        .map(|base| {
+            pushgateway_push_url(&base, &worker_name).unwrap_or_else(|| {
+                tracing::error!("Invalid PUSHGATEWAY_URL: {:?}", base);
+                std::process::exit(1);
+            })
+        });
+
+    let task_profiles = load_task_profiles();
+    let tenant_quotas = parse_tenant_quotas(&env::var("TENANT_QUOTAS").unwrap_or_default());
+    let default_tenant_quota = get_env_i32("TENANT_DEFAULT_QUOTA", 0).max(0) as usize;
+    let tenant_quotas =
+        (!tenant_quotas.is_empty() || default_tenant_quota > 0).then(|| TenantQuotas {
+            default_quota: default_tenant_quota,
+            quotas: tenant_quotas,
+            semaphores: Mutex::new(HashMap::new()),
+        });
+    let hardware_classes =
+        parse_hardware_classes(&env::var("HARDWARE_CLASSES").unwrap_or_default());
+    let debug_endpoints = get_env_bool("DEBUG_ENDPOINTS", false);
+    let (disabled_endpoints, unknown_endpoints) =
+        parse_disabled_endpoints(&env::var("DISABLED_ENDPOINTS").unwrap_or_default());
+    for name in &unknown_endpoints {
+        tracing::error!("Unknown endpoint in DISABLED_ENDPOINTS: {:?}", name);
+    }
+    if !unknown_endpoints.is_empty() {
+        std::process::exit(1);
+    }
+    if !disabled_endpoints.is_empty() {
+        tracing::warn!("Disabled endpoints: {}", disabled_endpoints.join(", "));
+    }
+    let log_sample_rate = get_env_f64("LOG_SAMPLE_RATE", 1.0).clamp(0.0, 1.0);
+    let chaos_transport = get_env_bool("CHAOS_TRANSPORT", false);
+    if chaos_transport {
+        tracing::warn!("CHAOS_TRANSPORT enabled; responses may be deliberately malformed");
+    }
+    let chaos_health = get_env_bool("CHAOS_HEALTH", false);
+    if chaos_health {
+        tracing::warn!("CHAOS_HEALTH enabled; /health may deliberately report a worse status");
+    }
+    let sticky_sessions = get_env_bool("STICKY_SESSIONS", false).then(|| StickySessions {
+        cookie_name: env::var("STICKY_COOKIE_NAME")
+            .ok()
+            .filter(|v| !v.is_empty())
+            .unwrap_or_else(|| "worker_affinity".to_string()),
+        ttl_secs: get_env_i32("STICKY_COOKIE_TTL_SECS", 3600).max(0) as u64,
+    });
+    if let Some(sticky) = &sticky_sessions {
+        tracing::info!(
+            "Sticky sessions enabled (cookie: {}, ttl: {}s)",
+            sticky.cookie_name,
+            sticky.ttl_secs
+        );
+    }
+    let simulate_leak = get_env_bool("SIMULATE_LEAK", false);
+    if simulate_leak {
+        tracing::warn!(
+            "SIMULATE_LEAK enabled; leak_bytes_per_request retains memory until /reset (cap {} bytes)",
+            LEAK_CAP_BYTES
+        );
+    }
+    let state_file = env::var("STATE_FILE")
+        .ok()
+        .filter(|v| !v.is_empty())
+        .map(PathBuf::from);
+    let max_parse = u64::try_from(get_env_i32("MAX_PARSE_MS", 0))
+        .ok()
+        .filter(|ms| *ms > 0)
+        .map(Duration::from_millis);
+    let response_headers = load_response_headers();
+    if !response_headers.is_empty() {
+        tracing::info!("Injecting response headers: {:?}", response_headers);
+    }
+    let downstream_pooling = get_env_bool("DOWNSTREAM_POOLING", true);
+    let mut downstream_client = reqwest::Client::builder();
+    if !downstream_pooling {
+        // Every downstream call pays for a fresh connection
+        downstream_client = downstream_client.pool_max_idle_per_host(0);
+    }
+    let downstream_client = downstream_client
+        .build()
+        .expect("failed to build downstream client");
+    let warmup_task_id = env::var("WARMUP_TASK_ID").ok().filter(|v| !v.is_empty());
+    let pre_stop_delay = Duration::from_millis(get_env_i32("PRE_STOP_DELAY_MS", 0).max(0) as u64);
+    let task_events = get_env_bool("RECORD_EVENTS", false).then(|| Mutex::new(VecDeque::new()));
+    let max_lifetime_requests = env::var("MAX_LIFETIME_REQUESTS")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .filter(|v| *v > 0);
+    let admin_token = env::var("ADMIN_TOKEN").ok().filter(|v| !v.is_empty());
+    let jwt_key = env::var("JWT_SECRET")
+        .ok()
+        .filter(|v| !v.is_empty())
+        .map(|secret| jsonwebtoken::DecodingKey::from_secret(secret.as_bytes()));
+    let jwt_tenant_claim = env::var("JWT_TENANT_CLAIM").unwrap_or_else(|_| "tenant".to_string());
+    if jwt_key.is_some() {
+        tracing::info!(
+            "JWT authentication enabled for /task (tenant claim: {})",
+            jwt_tenant_claim
+        );
+    }
+    let max_connections = usize::try_from(get_env_i32("MAX_CONNECTIONS", 0))
+        .ok()
+        .filter(|v| *v > 0);
+    #[cfg(unix)]
+    let unix_socket_path = env::var("UNIX_SOCKET_PATH")
+        .ok()
+        .filter(|v| !v.is_empty())
+        .map(PathBuf::from);
+
+    let metric_prefix =
+        env::var("METRIC_PREFIX").unwrap_or_else(|_| DEFAULT_METRIC_PREFIX.to_string());
+    if !valid_metric_prefix(&metric_prefix) {
+        tracing::error!("Invalid METRIC_PREFIX: {:?}", metric_prefix);
+        std::process::exit(1);
+    }
+    let prometheus_handle = setup_metrics(&metric_prefix);
+
+    let state = Arc::new(AppState {
+        worker_version: worker_version.clone(),
+        canary_version: canary_version.clone(),
+        prometheus_handle,
+        downstream_client,
+        config_change_webhook: config_change_webhook.clone(),
+        pushgateway_url,
+        task_profiles,
+        tenant_quotas,
+        hardware_classes,
+        debug_endpoints,
+        disabled_endpoints,
+        admin_token,
+        jwt_key,
+        jwt_tenant_claim,
+        simulate_leak,
+        state_file: state_file.clone(),
+        log_sample_rate,
+        chaos_transport,
+        chaos_health,
+        sticky_sessions,
+        max_parse,
+        warmup_task_id: warmup_task_id.clone(),
+        response_headers,
+        max_lifetime_requests,
+        task_events,
+        ..AppState::new(config.clone(), worker_name.clone(), worker_color.clone())
+    });
+    state.set_accept_id_pattern(&state.config.read().accept_id_pattern);
+    state.record_config_history(&config);
+    if let Some(path) = &state_file {
+        let persisted = PersistedState::load(path);
+        tracing::info!(
+            "Restored {} task responses from {}",
+            persisted.task_responses.values().sum::<u64>(),
+            path.display()
+        );
+        state.restore_task_responses(persisted);
+        tokio::spawn(checkpoint_state(Arc::clone(&state)));
+    }
+
+    #[cfg(unix)]
+    tokio::spawn(drain_signals(Arc::clone(&state)));
+    tokio::spawn(report_permit_utilization(Arc::clone(&state)));
+    tokio::spawn(simulate_outages(Arc::clone(&state)));
+    tokio::spawn(simulate_diurnal(Arc::clone(&state)));
+    tokio::spawn(refresh_health_cache(Arc::clone(&state)));
+    if let Some(url) = &state.pushgateway_url {
+        tracing::info!("Pushing metrics to {}", url);
+        tokio::spawn(push_metrics_periodically(Arc::clone(&state)));
+    }
+
+    let app = build_router(Arc::clone(&state));
+
+    let addr: SocketAddr = format!("0.0.0.0:{}", port).parse().unwrap();
+    tracing::info!(
+        "Starting {} on port {} (color: {}, version: {})",
+        worker_name,
+        port,
+        worker_color,
+        worker_version
+    );
+    tracing::info!(
+        "Config: max_concurrent={}, delay={}ms, failure_rate={:.2}, queue_size={}, canary_fraction={:.2} ({})",
+        config.max_concurrent_requests,
+        config.response_delay_ms,
+        config.failure_rate,
+        config.queue_size,
+        config.canary_fraction,
+        canary_version
+    );
+
+    for (name, profile) in &state.task_profiles {
+        tracing::info!(
+            "Task profile {}: max_concurrent={}",
+            name,
+            profile.max_concurrent
+        );
+    }
+    if let Some(id) = &warmup_task_id {
+        tracing::info!("Warmup task id {:?} bypasses the queue", id);
+    }
+    if let Some(max) = max_lifetime_requests {
+        tracing::info!("Shutting down after {} requests", max);
+    }
+    if let Some(url) = &config_change_webhook {
+        tracing::info!("Config changes will be posted to {}", url);
+    }
+
+    match max_connections {
+        Some(max) => tracing::info!("Limiting simultaneous connections to {}", max),
+        None => tracing::info!("Simultaneous connections are not limited"),
+    }
+
+    let listener = TcpListener::bind(addr).await.unwrap();
+    let listener = LimitedListener::new(listener, max_connections, worker_name.clone());
+    // Both listeners start draining on the same signal
+    let shutdown = shutdown_signal(Arc::clone(&state), pre_stop_delay).shared();
+    #[cfg(unix)]
+    let unix_server = unix_socket_path.map(|path| {
+        let listener = bind_unix_socket(&path).unwrap_or_else(|err| {
+            tracing::error!("Failed to bind unix socket {}: {}", path.display(), err);
+            std::process::exit(1);
+        });
+        tracing::info!("Also listening on unix socket {}", path.display());
+        tokio::spawn(serve_unix_socket(
+            listener,
+            path,
+            app.clone(),
+            shutdown.clone(),
+        ))
+    });
+    axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown)
+        .await
+        .unwrap();
+    #[cfg(unix)]
+    if let Some(server) = unix_server {
+        let _ = server.await;
+    }
+    state.checkpoint();
+    // Final push so the last requests are not lost when the instance goes away
+    state.push_metrics().await;
+    tracing::info!("In-flight requests drained; exiting");
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 設定項目の環境変数を書き換えるテストを直列化する。
+    static ENV_LOCK: Mutex<()> = Mutex::new(());
+
+    fn test_config() -> Configuration {
+        Configuration {
+            max_concurrent_requests: 5,
+            response_delay_ms: 200,
+            failure_rate: 0.0,
+            queue_size: 10,
+            canary_fraction: 0.0,
+            base_jitter_ms: 0,
+            echo_config: false,
+            min_inter_response_ms: 0,
+            shadow_enabled: false,
+            shadow_response_delay_ms: 200,
+            shadow_failure_rate: 0.0,
+            rate_limit_rps: 0.0,
+            rate_limit_burst: 0,
+            latency_budget_ms: 0,
+            trickle_bytes_per_sec: 0,
+            stale_timestamp_rate: 0.0,
+            accept_id_pattern: String::new(),
+            admission_strategy: "default".to_string(),
+            problem_json: false,
+            downstream_url: String::new(),
+            downstream_connect_delay_ms: 0,
+            success_color: String::new(),
+            degraded_color: String::new(),
+            failure_color: String::new(),
+            outage_probability: 0.0,
+            outage_duration_ms: 0,
+            failure_scales_with_weight: false,
+            unfair_prefix: String::new(),
+            internal_retries: 0,
+            broken_content_length_rate: 0.0,
+            leak_rate_rps: 0.0,
+            health_load_weight: 1.0,
+            health_queue_weight: 1.0,
+            health_score_mode: "max".to_string(),
+            replica_lag_ms: 0,
+            replica_lag_jitter_ms: 0,
+            per_id_metrics: false,
+            failure_takes_precedence: false,
+            success_status_code: 200,
+            accepted_location: false,
+            leak_bytes_per_request: 0,
+            accept_timeout_ms: 0,
+            dependency_timeout_ms: 5000,
+            truncate_response_rate: 0.0,
+            diurnal_period_ms: 600_000,
+            diurnal_amplitude: 0.0,
+            include_queue_position: false,
+            single_flight: false,
+            latency_table: Vec::new(),
+            task_steps: false,
+            processing_timeout_ms: 0,
+            stream_trailers: false,
+            auto_id: false,
+            health_flap_rate: 0.0,
+            trace_task_ids: Vec::new(),
+            payload_size_bytes: 0,
+            response_size_alignment: 0,
+            config_read_delay_ms: 0,
+            downstream_targets: Vec::new(),
+            downstream_fanout: 1,
+            fanout_quorum: 0,
+            health_cache_ms: 0,
+            schema_drift_rate: 0.0,
+            max_batch_size: 1000,
+        }
+    }
+
+    fn test_state(config: Configuration) -> Arc<AppState> {
+        Arc::new(AppState {
+            worker_version: "1.0.0".to_string(),
+            canary_version: "1.0.0-canary".to_string(),
+            debug_endpoints: true,
+            stale_timestamp: "2000-01-01T00:00:00.000000000Z".to_string(),
+            ..AppState::new(config, "test-worker".to_string(), "#000000".to_string())
+        })
+    }
+
+    fn task(id: &str) -> TaskRequest {
+        TaskRequest {
+            id: id.to_string(),
+            weight: None,
+            profile: None,
+            force_error: None,
+            depends_on: None,
+            steps: None,
+        }
+    }
+
+    async fn send_task(state: &Arc<AppState>, id: &str) -> StatusCode {
+        handle_task(
+            State(Arc::clone(state)),
+            Query(TaskQuery::default()),
+            HeaderMap::new(),
+            Json(task(id)),
+        )
+        .await
+        .into_response()
+        .status()
+    }
+
+    async fn body_json(response: Response) -> serde_json::Value {
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    fn snapshot(state: &AppState) -> (i32, i32) {
+        let config = state.config.read();
+        (state.current_load(&config), state.queue_depth(&config))
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn derived_load_matches_in_flight_requests() {
+        let state = test_state(test_config());
+
+        let handles: Vec<_> = (0..5)
+            .map(|i| {
+                let state = Arc::clone(&state);
+                tokio::spawn(async move { send_task(&state, &format!("task-{i}")).await })
+            })
+            .collect();
+        sleep(Duration::from_millis(50)).await;
+        assert_eq!(snapshot(&state), (5, 5));
+
+        // Requests beyond the concurrency limit are rejected without leaking permits
+        for i in 0..3 {
+            let status = send_task(&state, &format!("extra-{i}")).await;
+            assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        }
+        assert_eq!(snapshot(&state), (5, 5));
+
+        for handle in handles {
+            assert_eq!(handle.await.unwrap(), StatusCode::OK);
+        }
+        assert_eq!(snapshot(&state), (0, 0));
+        assert_eq!(state.queue_depth_max.load(Ordering::SeqCst), 6);
+        assert_eq!(state.concurrency_semaphore.available_permits(), 5);
+        assert_eq!(state.queue_semaphore.available_permits(), 10);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn shrinking_concurrency_reclaims_held_permits() {
+        let state = test_state(test_config());
+
+        let handles: Vec<_> = (0..4)
+            .map(|i| {
+                let state = Arc::clone(&state);
+                tokio::spawn(async move { send_task(&state, &format!("task-{i}")).await })
+            })
+            .collect();
+        sleep(Duration::from_millis(50)).await;
+
+        let update = ConfigUpdate {
+            max_concurrent_requests: Some(2),
+            ..Default::default()
+        };
+        handle_config_update(State(Arc::clone(&state)), Json(update)).await;
+
+        for handle in handles {
+            assert_eq!(handle.await.unwrap(), StatusCode::OK);
+        }
+        sleep(Duration::from_millis(20)).await;
+        assert_eq!(state.concurrency_semaphore.available_permits(), 2);
+        assert_eq!(snapshot(&state), (0, 0));
+    }
+
+    #[tokio::test]
+    async fn index_negotiates_html_and_json() {
+        let state = test_state(test_config());
+
+        let json = handle_index(State(Arc::clone(&state)), HeaderMap::new())
+            .await
+            .into_response();
+        assert_eq!(json.headers()[header::CONTENT_TYPE], "application/json");
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ACCEPT, "text/html,*/*".parse().unwrap());
+        let html = handle_index(State(state), headers).await.into_response();
+        assert!(html.headers()[header::CONTENT_TYPE]
+            .to_str()
+            .unwrap()
+            .starts_with("text/html"));
+    }
+
+    #[test]
+    fn escape_html_escapes_markup() {
+        assert_eq!(
+            escape_html("<a href=\"x\">&</a>"),
+            "&lt;a href=&quot;x&quot;&gt;&amp;&lt;/a&gt;"
+        );
+    }
+
+    #[tokio::test]
+    async fn config_update_posts_to_webhook() {
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel::<serde_json::Value>();
+        let receiver = Router::new().route(
+            "/hook",
+            post(move |Json(body): Json<serde_json::Value>| async move {
+                tx.send(body).unwrap();
+                StatusCode::OK
+            }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, receiver).await.unwrap() });
+
+        let mut state = test_state(test_config());
+        Arc::get_mut(&mut state).unwrap().config_change_webhook =
+            Some(format!("http://{addr}/hook"));
+        let update = ConfigUpdate {
+            failure_rate: Some(0.5),
+            ..Default::default()
+        };
+        handle_config_update(State(state), Json(update)).await;
+
+        let body = tokio::time::timeout(Duration::from_secs(2), rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(body["failure_rate"], 0.5);
+    }
+
+    #[tokio::test]
+    async fn draining_rejects_tasks_and_fails_health() {
+        let state = test_state(test_config());
+        state.set_draining(true);
+
+        assert_eq!(
+            send_task(&state, "task-1").await,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        let health = handle_health(State(Arc::clone(&state)))
+            .await
+            .into_response();
+        assert_eq!(health.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        state.set_draining(false);
+        let health = handle_health(State(state)).await.into_response();
+        assert_eq!(health.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn parse_task_profiles_skips_invalid_entries() {
+        let profiles = parse_task_profiles("heavy:2, light:8, broken, zero:0, :3");
+        assert_eq!(profiles.len(), 2);
+        assert_eq!(profiles["heavy"].max_concurrent, 2);
+        assert_eq!(profiles["light"].max_concurrent, 8);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn profile_limit_is_enforced_independently() {
+        let mut state = test_state(test_config());
+        Arc::get_mut(&mut state).unwrap().task_profiles = parse_task_profiles("heavy:1");
+
+        let send = |id: &'static str, profile: Option<&'static str>| {
+            let state = Arc::clone(&state);
+            tokio::spawn(async move {
+                let task = TaskRequest {
+                    id: id.to_string(),
+                    weight: None,
+                    profile: profile.map(str::to_string),
+                    force_error: None,
+                    depends_on: None,
+                    steps: None,
+                };
+                handle_task(
+                    State(state),
+                    Query(TaskQuery::default()),
+                    HeaderMap::new(),
+                    Json(task),
+                )
+                .await
+                .into_response()
+                .status()
+            })
+        };
+
+        let first = send("heavy-1", Some("heavy"));
+        sleep(Duration::from_millis(50)).await;
+        assert_eq!(
+            send("heavy-2", Some("heavy")).await.unwrap(),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        assert_eq!(send("light-1", None).await.unwrap(), StatusCode::OK);
+        assert_eq!(first.await.unwrap(), StatusCode::OK);
+        assert_eq!(state.task_profiles["heavy"].in_flight(), 0);
+    }
+
+    #[tokio::test]
+    async fn forced_outcomes_apply_to_next_requests_only() {
+        let mut config = test_config();
+        config.response_delay_ms = 0;
+        let state = test_state(config);
+
+        let force = ForceRequest {
+            outcome: ForcedOutcome::Fail,
+            count: 2,
+        };
+        handle_debug_force(State(Arc::clone(&state)), HeaderMap::new(), Json(force)).await;
+        assert_eq!(
+            send_task(&state, "a").await,
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+        assert_eq!(
+            send_task(&state, "b").await,
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+        assert_eq!(send_task(&state, "c").await, StatusCode::OK);
+
+        let force = ForceRequest {
+            outcome: ForcedOutcome::Overload,
+            count: 1,
+        };
+        handle_debug_force(State(Arc::clone(&state)), HeaderMap::new(), Json(force)).await;
+        assert_eq!(
+            send_task(&state, "d").await,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        assert_eq!(send_task(&state, "e").await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn admin_token_is_required_when_configured() {
+        let mut state = test_state(test_config());
+        Arc::get_mut(&mut state).unwrap().admin_token = Some("secret".to_string());
+
+        let force = || ForceRequest {
+            outcome: ForcedOutcome::Success,
+            count: 1,
+        };
+        let resp =
+            handle_debug_force(State(Arc::clone(&state)), HeaderMap::new(), Json(force())).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::AUTHORIZATION, "Bearer secret".parse().unwrap());
+        let resp = handle_debug_force(State(state), headers, Json(force())).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn limited_listener_holds_connections_over_the_cap() {
+        let inner = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = inner.local_addr().unwrap();
+        let mut listener = LimitedListener::new(inner, Some(1), "test-worker".to_string());
+
+        let _first_client = TcpStream::connect(addr).await.unwrap();
+        let (first, _) = axum::serve::Listener::accept(&mut listener).await;
+        assert_eq!(listener.permits.available_permits(), 0);
+
+        let _second_client = TcpStream::connect(addr).await.unwrap();
+        let held = tokio::time::timeout(
+            Duration::from_millis(100),
+            axum::serve::Listener::accept(&mut listener),
+        )
+        .await;
+        assert!(
+            held.is_err(),
+            "second connection should wait for a free slot"
+        );
+
+        drop(first);
+        let accepted = tokio::time::timeout(
+            Duration::from_millis(500),
+            axum::serve::Listener::accept(&mut listener),
+        )
+        .await;
+        assert!(accepted.is_ok());
+    }
+
+    #[tokio::test]
+    async fn echo_config_includes_snapshot_in_response() {
+        let mut config = test_config();
+        config.response_delay_ms = 0;
+        let state = test_state(config);
+
+        let plain = handle_task(
+            State(Arc::clone(&state)),
+            Query(TaskQuery::default()),
+            HeaderMap::new(),
+            Json(task("a")),
+        )
+        .await
+        .into_response();
+        assert!(body_json(plain).await.get("config").is_none());
+
+        let query = TaskQuery {
+            echo_config: Some(true),
+            ..Default::default()
+        };
+        let echoed = handle_task(
+            State(state),
+            Query(query),
+            HeaderMap::new(),
+            Json(task("b")),
+        )
+        .await
+        .into_response();
+        assert_eq!(body_json(echoed).await["config"]["queue_size"], 10);
+    }
+
+    #[test]
+    fn get_env_buckets_falls_back_on_invalid_input() {
+        env::set_var("TEST_BUCKETS_VALID", "4, 1,2,2");
+        assert_eq!(
+            get_env_buckets("TEST_BUCKETS_VALID", &[10.0]),
+            vec![1.0, 2.0, 4.0]
+        );
+        env::set_var("TEST_BUCKETS_INVALID", "1,x");
+        assert_eq!(get_env_buckets("TEST_BUCKETS_INVALID", &[10.0]), vec![10.0]);
+        assert_eq!(get_env_buckets("TEST_BUCKETS_UNSET", &[10.0]), vec![10.0]);
+    }
+
+    #[test]
+    fn response_slots_are_spaced_by_the_gap() {
+        let state = test_state(test_config());
+        let gap = Duration::from_millis(100);
+        let first = state.reserve_response_slot(gap);
+        let second = state.reserve_response_slot(gap);
+        let third = state.reserve_response_slot(gap);
+        assert_eq!(second - first, gap);
+        assert_eq!(third - second, gap);
+    }
+
+    #[test]
+    fn ridiculous_queue_size_is_clamped() {
+        let _env = ENV_LOCK.lock();
+        env::set_var("QUEUE_SIZE", "2000000000");
+        let config = load_config();
+        env::remove_var("QUEUE_SIZE");
+        assert_eq!(config.queue_size, MAX_QUEUE_SIZE);
+        assert_eq!(clamp_queue_size(50), 50);
+    }
+
+    #[tokio::test]
+    async fn config_update_clamps_queue_size() {
+        let state = test_state(test_config());
+        let update = ConfigUpdate {
+            queue_size: Some(i32::MAX),
+            ..Default::default()
+        };
+        handle_config_update(State(Arc::clone(&state)), Json(update)).await;
+        assert_eq!(state.config.read().queue_size, MAX_QUEUE_SIZE);
+        assert_eq!(
+            state.queue_semaphore.available_permits(),
+            MAX_QUEUE_SIZE as usize
+        );
+    }
+
+    #[tokio::test]
+    async fn query_overrides_require_debug_endpoints() {
+        let mut state = test_state(test_config());
+        let query = || TaskQuery {
+            force: Some(ForcedOutcome::Fail),
+            delay: Some(0),
+            ..Default::default()
+        };
+
+        let resp = handle_task(
+            State(Arc::clone(&state)),
+            Query(query()),
+            HeaderMap::new(),
+            Json(task("a")),
+        )
+        .await
+        .into_response();
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        Arc::get_mut(&mut state).unwrap().debug_endpoints = false;
+        let start = Instant::now();
+        let resp = handle_task(
+            State(state),
+            Query(query()),
+            HeaderMap::new(),
+            Json(task("b")),
+        )
+        .await
+        .into_response();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(start.elapsed() >= Duration::from_millis(200));
+    }
+
+    #[tokio::test]
+    async fn reset_clears_queue_high_water_mark() {
+        let state = test_state(test_config());
+        state.queue_depth_max.store(7, Ordering::SeqCst);
+        let resp = handle_reset(State(Arc::clone(&state)), HeaderMap::new()).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        assert_eq!(state.queue_depth_max.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn config_history_is_bounded() {
+        let state = test_state(test_config());
+        for delay in 0..(CONFIG_HISTORY_LIMIT as i32 + 5) {
+            let update = ConfigUpdate {
+                response_delay_ms: Some(delay),
+                ..Default::default()
+            };
+            handle_config_update(State(Arc::clone(&state)), Json(update)).await;
+        }
+
+        let resp = handle_config_history(State(state), HeaderMap::new()).await;
+        let history = body_json(resp).await;
+        let history = history.as_array().unwrap();
+        assert_eq!(history.len(), CONFIG_HISTORY_LIMIT);
+        assert_eq!(history[0]["config"]["response_delay_ms"], 5);
+        assert_eq!(
+            history.last().unwrap()["config"]["response_delay_ms"],
+            CONFIG_HISTORY_LIMIT as i32 + 4
+        );
+    }
+
+    #[test]
+    fn delay_spread_stays_within_range() {
+        for _ in 0..100 {
+            let mut config = test_config();
+            let offset = apply_delay_spread(&mut config, 25.0);
+            assert!((-25.0..=25.0).contains(&offset));
+            assert!((150..=250).contains(&config.response_delay_ms));
+        }
+    }
+
+    #[test]
+    fn token_bucket_refills_over_time() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new();
+        assert_eq!(bucket.take(2.0, 2.0, start).0, Some(1.0));
+        assert_eq!(bucket.take(2.0, 2.0, start).0, Some(0.0));
+        let (remaining, reset) = bucket.take(2.0, 2.0, start);
+        assert_eq!(remaining, None);
+        assert_eq!(reset, Duration::from_secs(1));
+        assert!(bucket
+            .take(2.0, 2.0, start + Duration::from_millis(500))
+            .0
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn rate_limited_requests_get_429_with_headers() {
+        let mut config = test_config();
+        config.response_delay_ms = 0;
+        config.rate_limit_rps = 1.0;
+        let state = test_state(config);
+
+        assert_eq!(send_task(&state, "a").await, StatusCode::OK);
+        let resp = handle_task(
+            State(state),
+            Query(TaskQuery::default()),
+            HeaderMap::new(),
+            Json(task("b")),
+        )
+        .await
+        .into_response();
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(resp.headers()["ratelimit-limit"], "1");
+        assert_eq!(resp.headers()["ratelimit-remaining"], "0");
+        assert_eq!(resp.headers()["ratelimit-reset"], "1");
+    }
+
+    #[tokio::test]
+    async fn exceeding_latency_budget_returns_504() {
+        let mut config = test_config();
+        config.response_delay_ms = 50;
+        config.latency_budget_ms = 20;
+        let state = test_state(config);
+        assert_eq!(send_task(&state, "slow").await, StatusCode::GATEWAY_TIMEOUT);
+
+        state.config.write().latency_budget_ms = 1_000;
+        assert_eq!(send_task(&state, "fast").await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn warmup_task_bypasses_drain_and_queue() {
+        let mut state = test_state(test_config());
+        Arc::get_mut(&mut state).unwrap().warmup_task_id = Some("warmup".to_string());
+        Arc::get_mut(&mut state).unwrap().tenant_quotas = Some(TenantQuotas {
+            default_quota: 0,
+            quotas: parse_tenant_quotas("acme:1"),
+            semaphores: Mutex::new(HashMap::new()),
+        });
+        state.set_draining(true);
+
+        assert_eq!(send_task(&state, "warmup").await, StatusCode::OK);
+        assert_eq!(
+            send_task(&state, "real").await,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+
+        // A tenant at its quota still gets its warmup probe answered
+        let mut headers = HeaderMap::new();
+        headers.insert(TENANT_HEADER, HeaderValue::from_static("acme"));
+        let _held = state.acquire_tenant_slot(&headers).unwrap();
+        let response = handle_task(
+            State(Arc::clone(&state)),
+            Query(TaskQuery::default()),
+            headers,
+            Json(task("warmup")),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // Only the real request counts as traffic
+        assert_eq!(state.lifetime_requests.load(Ordering::SeqCst), 1);
+        assert_eq!(state.capacity_report().requests, 1);
+    }
+
+    #[tokio::test]
+    async fn panic_becomes_json_500() {
+        let response = panic_response("rust-test", Box::new("boom"));
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let body = body_json(response).await;
+        assert_eq!(body["error"], "Internal server error");
+        assert_eq!(body["worker"], "rust-test");
+    }
+
+    #[tokio::test]
+    async fn trickle_streams_full_body() {
+        let mut config = test_config();
+        config.trickle_bytes_per_sec = 1_000;
+        let state = test_state(config);
+
+        let started = Instant::now();
+        let response = process_task(&state, TaskQuery::default(), task("slow")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = body_json(response).await;
+        assert_eq!(body["id"], "slow");
+        // The JSON body is well over 100 bytes, so streaming at 1000 B/s spans several chunks
+        assert!(started.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[tokio::test]
+    async fn stale_timestamp_returns_cached_time() {
+        let mut config = test_config();
+        config.stale_timestamp_rate = 1.0;
+        let state = test_state(config);
+
+        let response = process_task(&state, TaskQuery::default(), task("stale")).await;
+        let body = body_json(response).await;
+        assert_eq!(body["timestamp"], "2000-01-01T00:00:00.000000000Z");
+    }
+
+    #[tokio::test]
+    async fn put_config_requires_every_field() {
+        let state = test_state(test_config());
+        let body = serde_json::json!({ "failure_rate": 0.5 });
+
+        let response = handle_config_replace(State(Arc::clone(&state)), Json(body)).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let error = body_json(response).await["error"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        assert!(error.contains("max_concurrent_requests"));
+        assert!(error.contains("PATCH"));
+        assert_eq!(state.config.read().failure_rate, test_config().failure_rate);
+    }
+
+    #[tokio::test]
+    async fn put_config_rejects_invalid_values_and_replaces_valid_ones() {
+        let state = test_state(test_config());
+
+        let mut invalid = serde_json::to_value(test_config()).unwrap();
+        invalid["failure_rate"] = serde_json::json!(1.5);
+        let response = handle_config_replace(State(Arc::clone(&state)), Json(invalid)).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(body_json(response).await["error"]
+            .as_str()
+            .unwrap()
+            .contains("failure_rate"));
+
+        let mut valid = serde_json::to_value(test_config()).unwrap();
+        valid["failure_rate"] = serde_json::json!(0.25);
+        valid["max_concurrent_requests"] = serde_json::json!(3);
+        let response = handle_config_replace(State(Arc::clone(&state)), Json(valid)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(state.config.read().failure_rate, 0.25);
+        assert_eq!(state.concurrency_semaphore.available_permits(), 3);
+    }
+
+    #[tokio::test]
+    async fn accept_id_pattern_rejects_other_shards() {
+        let state = test_state(test_config());
+        let update = ConfigUpdate {
+            accept_id_pattern: Some("^shard-a-".to_string()),
+            ..Default::default()
+        };
+        handle_config_update(State(Arc::clone(&state)), Json(update)).await;
+
+        assert_eq!(send_task(&state, "shard-a-1").await, StatusCode::OK);
+        assert_eq!(send_task(&state, "shard-b-1").await, StatusCode::NOT_FOUND);
+        assert_eq!(
+            state.queue_semaphore.available_permits(),
+            test_config().queue_size as usize
+        );
+
+        state.set_accept_id_pattern("(unclosed");
+        assert_eq!(send_task(&state, "shard-b-1").await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn permit_utilization_tracks_held_time_and_resets() {
+        let mut config = test_config();
+        config.max_concurrent_requests = 1;
+        config.response_delay_ms = 50;
+        let state = test_state(config);
+        state.reset_permit_utilization();
+
+        assert_eq!(send_task(&state, "busy").await, StatusCode::OK);
+        let utilization = state.permit_utilization(1);
+        assert!(utilization > 0.5 && utilization <= 1.0, "{utilization}");
+
+        let response = handle_reset(State(Arc::clone(&state)), HeaderMap::new()).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(state.permit_held_micros.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn strict_config_reports_unparseable_and_out_of_range_values() {
+        let _env = ENV_LOCK.lock();
+        env::set_var("FAILURE_RATE", "1.5");
+        env::set_var("BASE_JITTER_MS", "lots");
+        env::set_var("RESPONSE_DELAY_MS", "25");
+        let config = load_config();
+        let errors = strict_config_errors(&config);
+        env::remove_var("FAILURE_RATE");
+        env::remove_var("BASE_JITTER_MS");
+        env::remove_var("RESPONSE_DELAY_MS");
+
+        assert_eq!(errors.len(), 2, "{errors:?}");
+        assert!(errors
+            .iter()
+            .any(|e| e.starts_with("FAILURE_RATE=") && e.contains("out of range")));
+        assert!(errors
+            .iter()
+            .any(|e| e.starts_with("BASE_JITTER_MS=") && e.contains("parsed")));
+    }
+
+    #[test]
+    fn strict_config_accepts_list_fields() {
+        let _env = ENV_LOCK.lock();
+        env::set_var("TRACE_TASK_IDS", "a, b");
+        let config = load_config();
+        let errors = strict_config_errors(&config);
+        env::remove_var("TRACE_TASK_IDS");
+
+        assert!(errors.is_empty(), "{errors:?}");
+        assert_eq!(config.trace_task_ids, ["a", "b"]);
+    }
+
+    #[tokio::test]
+    async fn default_admission_holds_permits_until_dropped() {
+        let mut config = test_config();
+        config.max_concurrent_requests = 1;
+        let state = test_state(config.clone());
+        let controller = admission_controller("default");
+
+        let admission = controller
+            .admit(&state, &config, &task("first"), None)
+            .unwrap();
+        assert_eq!(snapshot(&state), (1, 1));
+        match controller.admit(&state, &config, &task("second"), None) {
+            Err(Rejection::Overloaded(message)) => assert!(message.contains("(2/1)")),
+            other => panic!("unexpected admission: {:?}", other.err()),
+        }
+        assert_eq!(snapshot(&state), (1, 1));
+
+        drop(admission);
+        assert_eq!(snapshot(&state), (0, 0));
+    }
+
+    #[tokio::test]
+    async fn default_admission_rejects_full_queue_and_rate_limit() {
+        let mut config = test_config();
+        config.queue_size = 1;
+        let state = test_state(config.clone());
+        let controller = admission_controller("default");
+
+        let _held = controller
+            .admit(&state, &config, &task("first"), None)
+            .unwrap();
+        assert!(matches!(
+            controller.admit(&state, &config, &task("second"), None),
+            Err(Rejection::QueueFull)
+        ));
+
+        config.rate_limit_rps = 1.0;
+        config.rate_limit_burst = 1;
+        let state = test_state(config.clone());
+        let _held = controller
+            .admit(&state, &config, &task("first"), None)
+            .unwrap();
+        assert!(matches!(
+            controller.admit(&state, &config, &task("second"), None),
+            Err(Rejection::RateLimited { limit: 1, .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn default_admission_keeps_a_carried_queue_permit() {
+        let mut config = test_config();
+        config.queue_size = 1;
+        let state = test_state(config.clone());
+        let controller = admission_controller("default");
+
+        // A parked task already holds the only queue slot, so admission must reuse it
+        let parked = state.queue_semaphore.try_acquire().unwrap();
+        let admission = controller
+            .admit(&state, &config, &task("parked"), Some(parked))
+            .unwrap();
+        assert_eq!(snapshot(&state), (1, 1));
+        assert!(matches!(
+            controller.admit(&state, &config, &task("newcomer"), None),
+            Err(Rejection::QueueFull)
+        ));
+
+        drop(admission);
+        assert_eq!(snapshot(&state), (0, 0));
+    }
+
+    #[tokio::test]
+    async fn unknown_admission_strategy_is_ignored() {
+        let state = test_state(test_config());
+        let update = ConfigUpdate {
+            admission_strategy: Some("bogus".to_string()),
+            ..Default::default()
+        };
+        handle_config_update(State(Arc::clone(&state)), Json(update)).await;
+        assert_eq!(state.config.read().admission_strategy, "default");
+    }
+
+    #[tokio::test]
+    async fn errors_render_as_problem_details() {
+        let state = test_state(test_config());
+        let response = state.error_response(StatusCode::SERVICE_UNAVAILABLE, "Queue full");
+
+        let response = into_problem_details(&state.worker_name, response).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "application/problem+json"
+        );
+        let body = body_json(response).await;
+        assert_eq!(body["type"], "about:blank");
+        assert_eq!(body["title"], "Service Unavailable");
+        assert_eq!(body["status"], 503);
+        assert_eq!(body["detail"], "Queue full");
+        assert_eq!(body["worker"], state.worker_name);
+
+        let ok = into_problem_details(&state.worker_name, StatusCode::OK.into_response()).await;
+        assert!(ok.headers().get(header::CONTENT_TYPE).is_none());
+    }
+
+    #[tokio::test]
+    async fn batch_processes_each_element() {
+        let batch = |state: &Arc<AppState>, body: serde_json::Value| {
+            handle_task_batch(
+                State(Arc::clone(state)),
+                HeaderMap::new(),
+                Body::from(body.to_string()),
+            )
+        };
+        let body = serde_json::json!([{"id": "a"}, {"id": "b"}, {"weight": 1.0}, {"id": "c"}]);
+        let mut config = test_config();
+        config.response_delay_ms = 0;
+        let state = test_state(config);
+        let response = batch(&state, body.clone()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let result = body_json(response).await;
+        assert_eq!(result["total"], 4);
+        assert_eq!(result["invalid"], 1);
+        assert_eq!(result["statuses"]["200"], 3);
+
+        state.config.write().max_batch_size = 2;
+        let response = batch(&state, body).await;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let response = batch(&state, serde_json::json!({})).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn progress_stream_serves_byte_ranges() {
+        let state = test_state(test_config());
+        let get = |range: Option<&'static str>| {
+            let state = Arc::clone(&state);
+            async move {
+                let mut headers = HeaderMap::new();
+                if let Some(range) = range {
+                    headers.insert(header::RANGE, HeaderValue::from_static(range));
+                }
+                let response =
+                    handle_task_progress(State(state), UrlPath("job-1".to_string()), headers).await;
+                let status = response.status();
+                let headers = response.headers().clone();
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                (status, headers, body)
+            }
+        };
+
+        let (status, headers, full) = get(None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers[header::ACCEPT_RANGES], "bytes");
+        assert_eq!(headers[header::CONTENT_LENGTH], full.len().to_string());
+        let lines: Vec<serde_json::Value> = full
+            .split(|b| *b == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| serde_json::from_slice(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), PROGRESS_STEPS as usize + 1);
+        assert_eq!(lines[0]["percent"], 0);
+        assert_eq!(lines[PROGRESS_STEPS as usize]["percent"], 100);
+        assert_eq!(lines[0]["id"], "job-1");
+
+        // A range spanning a line boundary is stitched from both lines
+        let len = full.len();
+        let (status, headers, body) = get(Some("bytes=40-99")).await;
+        assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+        assert_eq!(headers[header::CONTENT_RANGE], format!("bytes 40-99/{len}"));
+        assert_eq!(body, full.slice(40..100));
+
+        let (status, headers, body) = get(Some("bytes=-5")).await;
+        assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+        assert_eq!(
+            headers[header::CONTENT_RANGE],
+            format!("bytes {}-{}/{len}", len - 5, len - 1)
+        );
+        assert_eq!(body, full.slice(len - 5..));
+
+        for invalid in ["bytes=90-10", "bytes=999999-", "items=0-1", "bytes=0-1,4-5"] {
+            let (status, headers, _) = get(Some(invalid)).await;
+            assert_eq!(status, StatusCode::RANGE_NOT_SATISFIABLE, "{invalid}");
+            assert_eq!(headers[header::CONTENT_RANGE], format!("bytes */{len}"));
+        }
+    }
+
+    #[test]
+    fn byte_ranges_are_clamped_to_the_body() {
+        assert_eq!(parse_byte_range("bytes=0-9", 100), Some((0, 9)));
+        assert_eq!(parse_byte_range("bytes=90-", 100), Some((90, 99)));
+        assert_eq!(parse_byte_range("bytes=50-500", 100), Some((50, 99)));
+        assert_eq!(parse_byte_range("bytes=-500", 100), Some((0, 99)));
+        assert_eq!(parse_byte_range("bytes=-0", 100), None);
+        assert_eq!(parse_byte_range("bytes=100-", 100), None);
+        assert_eq!(parse_byte_range("bytes=-", 100), None);
+    }
+
+    #[test]
+    fn array_splitter_keeps_one_element_buffered() {
+        let mut splitter = JsonArraySplitter::default();
+        let mut elements = Vec::new();
+        let mut count = 0;
+        splitter.feed(b"[", &mut elements).unwrap();
+        // 200k elements arrive in 4 KiB chunks; none of them are ever held together
+        let mut pending = Vec::new();
+        for i in 0..200_000 {
+            if i > 0 {
+                pending.push(b',');
+            }
+            pending.extend_from_slice(format!(r#"{{"id":"t{i}","weight":1.0}}"#).as_bytes());
+            if pending.len() >= 4096 {
+                splitter.feed(&pending, &mut elements).unwrap();
+                count += elements.len();
+                elements.clear();
+                pending.clear();
+            }
+        }
+        pending.push(b']');
+        splitter.feed(&pending, &mut elements).unwrap();
+        count += elements.len();
+        splitter.finish().unwrap();
+        assert_eq!(count, 200_000);
+        assert!(splitter.peak_buffered <= r#"{"id":"t199999","weight":1.0}"#.len());
+
+        let mut splitter = JsonArraySplitter::default();
+        let mut elements = Vec::new();
+        splitter
+            .feed(br#" [ {"id": "a],\"b"} , [1, [2]], 3 ] "#, &mut elements)
+            .unwrap();
+        splitter.finish().unwrap();
+        assert_eq!(
+            elements,
+            [
+                br#"{"id": "a],\"b"} "#.to_vec(),
+                b"[1, [2]]".to_vec(),
+                b"3 ".to_vec()
+            ]
+        );
+
+        for invalid in [&b"{}"[..], b"[1,]", b"[,1]", b"[1] 2"] {
+            let mut splitter = JsonArraySplitter::default();
+            assert!(splitter.feed(invalid, &mut Vec::new()).is_err());
+        }
+        let mut splitter = JsonArraySplitter::default();
+        splitter.feed(b"[1,2", &mut Vec::new()).unwrap();
+        assert!(splitter.finish().is_err());
+    }
+
+    /// テスト用の下流サーバを起動し、その URL を返す。`/ok` は 200、それ以外は 404 を返す。
+    async fn spawn_downstream() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = Router::new().route("/ok", post(|| async { "ok" }));
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        format!("http://{}", addr)
+    }
+
+    #[tokio::test]
+    async fn downstream_call_includes_connect_delay() {
+        let base = spawn_downstream().await;
+        let mut config = test_config();
+        config.downstream_url = format!("{}/ok", base);
+        config.downstream_connect_delay_ms = 40;
+        let state = test_state(config);
+
+        let response = process_task(&state, TaskQuery::default(), task("hop")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = body_json(response).await;
+        assert!(body["processingTimeMs"].as_i64().unwrap() >= 40);
+    }
+
+    #[tokio::test]
+    async fn downstream_failure_returns_bad_gateway() {
+        let base = spawn_downstream().await;
+        let mut config = test_config();
+        config.downstream_url = format!("{}/missing", base);
+        let state = test_state(config);
+
+        assert_eq!(send_task(&state, "hop").await, StatusCode::BAD_GATEWAY);
+        assert_eq!(snapshot(&state), (0, 0));
+    }
+
+    #[tokio::test]
+    async fn flush_requires_admin_and_publishes_gauges() {
+        let mut state = test_state(test_config());
+        Arc::get_mut(&mut state).unwrap().admin_token = Some("secret".to_string());
+
+        let response = handle_flush(State(Arc::clone(&state)), HeaderMap::new()).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::AUTHORIZATION, "Bearer secret".parse().unwrap());
+        let response = handle_flush(State(Arc::clone(&state)), headers).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn outcome_colors_reflect_result() {
+        let mut config = test_config();
+        config.max_concurrent_requests = 1;
+        config.success_color = "green".to_string();
+        config.degraded_color = "yellow".to_string();
+        config.failure_color = "red".to_string();
+        let state = test_state(config);
+
+        // A single-slot worker is at full load while serving its only request
+        let response = process_task(&state, TaskQuery::default(), task("busy")).await;
+        assert_eq!(body_json(response).await["color"], "yellow");
+
+        state.config.write().max_concurrent_requests = 10;
+        state.concurrency_semaphore.add_permits(9);
+        let response = process_task(&state, TaskQuery::default(), task("calm")).await;
+        assert_eq!(body_json(response).await["color"], "green");
+
+        state.config.write().failure_rate = 1.0;
+        let response = process_task(&state, TaskQuery::default(), task("broken")).await;
+        assert_eq!(body_json(response).await["color"], "red");
+    }
+
+    #[tokio::test]
+    async fn paused_tasks_wait_in_queue_until_resumed() {
+        let mut config = test_config();
+        config.queue_size = 2;
+        let state = test_state(config);
+        handle_pause(State(Arc::clone(&state)), HeaderMap::new()).await;
+
+        let handles: Vec<_> = (0..2)
+            .map(|i| {
+                let state = Arc::clone(&state);
+                tokio::spawn(async move { send_task(&state, &format!("held-{i}")).await })
+            })
+            .collect();
+        sleep(Duration::from_millis(50)).await;
+        assert_eq!(snapshot(&state), (0, 2));
+        assert_eq!(
+            send_task(&state, "overflow").await,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+
+        handle_resume(State(Arc::clone(&state)), HeaderMap::new()).await;
+        for handle in handles {
+            assert_eq!(handle.await.unwrap(), StatusCode::OK);
+        }
+        assert_eq!(snapshot(&state), (0, 0));
+    }
+
+    #[test]
+    fn parse_response_headers_skips_invalid_entries() {
+        let headers = parse_response_headers(
+            "Cache-Control: no-store, max-age=0\n bad header: x\nStrict-Transport-Security: max-age=60; includeSubDomains\nnocolon",
+        );
+        assert_eq!(headers.len(), 2);
+        assert_eq!(headers[header::CACHE_CONTROL], "no-store, max-age=0");
+        assert_eq!(
+            headers[header::STRICT_TRANSPORT_SECURITY],
+            "max-age=60; includeSubDomains"
+        );
+    }
+
+    #[tokio::test]
+    async fn outage_rejects_tasks_until_it_ends() {
+        let mut config = test_config();
+        config.outage_probability = 1.0;
+        config.outage_duration_ms = 50;
+        let state = test_state(config.clone());
+
+        assert!(state.roll_outage(&config, Instant::now()));
+        assert_eq!(
+            send_task(&state, "blip").await,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        // Still inside the window, so no second outage is started
+        assert!(!state.roll_outage(&config, Instant::now()));
+
+        sleep(Duration::from_millis(60)).await;
+        config.outage_probability = 0.0;
+        assert!(!state.roll_outage(&config, Instant::now()));
+        assert_eq!(send_task(&state, "after").await, StatusCode::OK);
+    }
+
+    #[test]
+    fn percentile_uses_nearest_rank() {
+        let values: Vec<f64> = (1..=100).map(f64::from).collect();
+        assert_eq!(percentile(&values, 50.0), Some(50.0));
+        assert_eq!(percentile(&values, 99.0), Some(99.0));
+        assert_eq!(percentile(&[7.0], 99.0), Some(7.0));
+        assert_eq!(percentile(&[], 50.0), None);
+    }
+
+    #[tokio::test]
+    async fn report_summarizes_recent_tasks() {
+        let state = test_state(test_config());
+        state.record_request_sample(StatusCode::OK, Duration::from_millis(10));
+        state.record_request_sample(StatusCode::OK, Duration::from_millis(30));
+        state.record_request_sample(StatusCode::SERVICE_UNAVAILABLE, Duration::from_millis(1));
+        state.record_request_sample(StatusCode::TOO_MANY_REQUESTS, Duration::from_millis(1));
+
+        let response = handle_report(State(Arc::clone(&state)), HeaderMap::new()).await;
+        let body = body_json(response).await;
+        assert_eq!(body["requests"], 4);
+        assert_eq!(body["maxThroughputRps"], 4.0);
+        assert_eq!(body["rejectionRate"], 0.5);
+        assert_eq!(body["latencyP50Ms"], 10.0);
+        assert_eq!(body["latencyP99Ms"], 30.0);
+        assert_eq!(body["successLatency"]["p90Ms"], 30.0);
+        assert_eq!(body["failureLatency"]["p50Ms"], serde_json::Value::Null);
+
+        handle_reset(State(Arc::clone(&state)), HeaderMap::new()).await;
+        assert_eq!(state.capacity_report().requests, 0);
+    }
+
+    #[test]
+    fn failure_rate_scales_with_weight_when_enabled() {
+        let mut config = test_config();
+        config.failure_rate = 0.3;
+        assert_eq!(config.effective_failure_rate(2.0), 0.3);
+
+        config.failure_scales_with_weight = true;
+        assert!((config.effective_failure_rate(2.0) - 0.6).abs() < 1e-9);
+        assert_eq!(config.effective_failure_rate(0.5), 0.15);
+        assert_eq!(config.effective_failure_rate(10.0), 1.0);
+    }
+
+    fn jwt(secret: &str, claims: serde_json::Value) -> HeaderMap {
+        let token = jsonwebtoken::encode(
+            &jsonwebtoken::Header::default(),
+            &claims,
+            &jsonwebtoken::EncodingKey::from_secret(secret.as_bytes()),
+        )
+        .unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::AUTHORIZATION,
+            format!("Bearer {}", token).parse().unwrap(),
+        );
+        headers
+    }
+
+    #[tokio::test]
+    async fn jwt_is_required_when_secret_is_set() {
+        let mut state = test_state(test_config());
+        Arc::get_mut(&mut state).unwrap().jwt_key =
+            Some(jsonwebtoken::DecodingKey::from_secret(b"shared"));
+        let exp = chrono::Utc::now().timestamp() + 60;
+
+        let valid = jwt(
+            "shared",
+            serde_json::json!({ "exp": exp, "tenant": "acme" }),
+        );
+        assert_eq!(
+            state.authenticate_task(&valid),
+            Ok(Some("acme".to_string()))
+        );
+        let resp = handle_task(
+            State(Arc::clone(&state)),
+            Query(TaskQuery::default()),
+            valid,
+            Json(task("a")),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        assert_eq!(
+            state.authenticate_task(&HeaderMap::new()),
+            Err("Missing bearer token")
+        );
+        let forged = jwt("other", serde_json::json!({ "exp": exp }));
+        assert_eq!(state.authenticate_task(&forged), Err("Invalid token"));
+        let expired = jwt("shared", serde_json::json!({ "exp": exp - 3600 }));
+        assert_eq!(state.authenticate_task(&expired), Err("Token expired"));
+        let resp = handle_task(
+            State(state),
+            Query(TaskQuery::default()),
+            expired,
+            Json(task("b")),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn batch_is_authenticated_before_decoding() {
+        let mut state = test_state(test_config());
+        Arc::get_mut(&mut state).unwrap().jwt_key =
+            Some(jsonwebtoken::DecodingKey::from_secret(b"shared"));
+        let exp = chrono::Utc::now().timestamp() + 60;
+
+        // A malformed body would be a 400, so a 401 shows nothing was read
+        let resp = handle_task_batch(
+            State(Arc::clone(&state)),
+            HeaderMap::new(),
+            Body::from("not json"),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(body_json(resp).await["error"], "Missing bearer token");
+
+        let valid = jwt("shared", serde_json::json!({ "exp": exp }));
+        let resp = handle_task_batch(
+            State(state),
+            valid,
+            Body::from(r#"[{"id": "a"}, {"id": "b"}]"#),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(body_json(resp).await["statuses"]["200"], 2);
+    }
+
+    #[tokio::test]
+    async fn unfair_prefix_reserves_capacity_for_favored_ids() {
+        let mut config = test_config();
+        config.max_concurrent_requests = 4;
+        config.unfair_prefix = "vip-".to_string();
+        let state = test_state(config.clone());
+        let controller = admission_controller("default");
+
+        let _a = controller
+            .admit(&state, &config, &task("other-1"), None)
+            .unwrap();
+        let _b = controller
+            .admit(&state, &config, &task("other-2"), None)
+            .unwrap();
+        match controller.admit(&state, &config, &task("other-3"), None) {
+            Err(Rejection::Overloaded(message)) => assert!(message.contains("reserved")),
+            other => panic!("unexpected admission: {:?}", other.err()),
+        }
+        let _c = controller
+            .admit(&state, &config, &task("vip-1"), None)
+            .unwrap();
+        let _d = controller
+            .admit(&state, &config, &task("vip-2"), None)
+            .unwrap();
+        assert_eq!(snapshot(&state).0, 4);
+    }
+
+    #[tokio::test]
+    async fn internal_retries_report_attempts() {
+        let mut config = test_config();
+        config.internal_retries = 2;
+        config.failure_rate = 1.0;
+        config.response_delay_ms = 10;
+        let state = test_state(config);
+
+        let started = Instant::now();
+        assert_eq!(
+            send_task(&state, "flaky").await,
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+        // One initial attempt plus two retries, each with its own delay
+        assert!(started.elapsed() >= Duration::from_millis(30));
+
+        state.config.write().failure_rate = 0.0;
+        let response = process_task(&state, TaskQuery::default(), task("steady")).await;
+        assert_eq!(body_json(response).await["attempts"], 1);
+    }
+
+    #[tokio::test]
+    async fn ready_flips_before_tasks_stop() {
+        let state = test_state(test_config());
+        let resp = handle_ready(State(Arc::clone(&state))).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        state.shutting_down.store(true, Ordering::SeqCst);
+        let resp = handle_ready(State(Arc::clone(&state))).await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body_json(resp).await["ready"], false);
+        // Traffic that still arrives during the pre-stop window is served
+        assert_eq!(send_task(&state, "late").await, StatusCode::OK);
+    }
+
+    #[test]
+    fn debug_env_reports_resolution_and_redacts_secrets() {
+        let _env = ENV_LOCK.lock();
+        env::set_var("CANARY_FRACTION", "2.0");
+        env::set_var("JWT_SECRET", "hunter2");
+        let resolutions = debug_env_resolutions();
+        env::remove_var("CANARY_FRACTION");
+        env::remove_var("JWT_SECRET");
+
+        let body = serde_json::to_value(resolutions).unwrap();
+        let find = |name: &str| {
+            body.as_array()
+                .unwrap()
+                .iter()
+                .find(|r| r["name"] == name)
+                .cloned()
+                .unwrap()
+        };
+        let canary = find("CANARY_FRACTION");
+        assert_eq!(canary["raw"], "2.0");
+        assert_eq!(canary["parsed"], true);
+        assert_eq!(canary["accepted"], false);
+        assert_eq!(canary["effective"], 1.0);
+        assert_eq!(find("JWT_SECRET")["raw"], "[redacted]");
+        assert_eq!(
+            find("MAX_CONCURRENT_REQUESTS")["raw"],
+            serde_json::Value::Null
+        );
+    }
+
+    #[tokio::test]
+    async fn broken_content_length_requires_chaos_transport() {
+        let mut config = test_config();
+        config.broken_content_length_rate = 1.0;
+        let mut state = test_state(config);
+
+        // Without the transport flag the server frames the body from its real length
+        let response = process_task(&state, TaskQuery::default(), task("safe")).await;
+        assert!(response.headers().get(header::CONTENT_LENGTH).is_none());
+
+        Arc::get_mut(&mut state).unwrap().chaos_transport = true;
+        let response = process_task(&state, TaskQuery::default(), task("broken")).await;
+        let declared: usize = response.headers()[header::CONTENT_LENGTH]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        let actual = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap()
+            .len();
+        assert_eq!(declared, actual + MISFRAMED_EXTRA_BYTES);
+    }
+
+    #[tokio::test]
+    async fn config_updates_advance_the_version() {
+        let state = test_state(test_config());
+        for delay in [10, 20] {
+            let update = ConfigUpdate {
+                response_delay_ms: Some(delay),
+                ..Default::default()
+            };
+            handle_config_update(State(Arc::clone(&state)), Json(update)).await;
+        }
+        assert_eq!(state.config_version.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn force_error_requires_debug_endpoints() {
+        let mut state = test_state(test_config());
+        let forced = |id: &str| TaskRequest {
+            force_error: Some("disk on fire".to_string()),
+            ..task(id)
+        };
+
+        let resp = handle_task(
+            State(Arc::clone(&state)),
+            Query(TaskQuery::default()),
+            HeaderMap::new(),
+            Json(forced("a")),
+        )
+        .await
+        .into_response();
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(body_json(resp).await["error"], "disk on fire");
+
+        Arc::get_mut(&mut state).unwrap().debug_endpoints = false;
+        let resp = handle_task(
+            State(state),
+            Query(TaskQuery::default()),
+            HeaderMap::new(),
+            Json(forced("b")),
+        )
+        .await
+        .into_response();
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn leaky_bucket_spaces_admissions_evenly() {
+        let mut config = test_config();
+        config.response_delay_ms = 0;
+        config.leak_rate_rps = 20.0;
+        config.queue_size = 3;
+        let state = test_state(config);
+
+        let start = Instant::now();
+        let handles: Vec<_> = (0..4)
+            .map(|i| {
+                let state = Arc::clone(&state);
+                tokio::spawn(async move { send_task(&state, &format!("t{i}")).await })
+            })
+            .collect();
+        let mut statuses = Vec::new();
+        for handle in handles {
+            statuses.push(handle.await.unwrap());
+        }
+        statuses.sort();
+        assert_eq!(
+            statuses,
+            [
+                StatusCode::OK,
+                StatusCode::OK,
+                StatusCode::OK,
+                StatusCode::SERVICE_UNAVAILABLE
+            ]
+        );
+        // Three admissions at 50ms spacing take at least 100ms in total
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert_eq!(state.leak_buffered.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn sticky_sessions_pin_clients_with_a_cookie() {
+        let mut state = test_state(test_config());
+        Arc::get_mut(&mut state).unwrap().sticky_sessions = Some(StickySessions {
+            cookie_name: "affinity".to_string(),
+            ttl_secs: 60,
+        });
+        let send = |cookie: Option<&'static str>| {
+            let state = Arc::clone(&state);
+            async move {
+                let mut headers = HeaderMap::new();
+                if let Some(cookie) = cookie {
+                    headers.insert(header::COOKIE, HeaderValue::from_static(cookie));
+                }
+                handle_task(
+                    State(state),
+                    Query(TaskQuery::default()),
+                    headers,
+                    Json(task("a")),
+                )
+                .await
+            }
+        };
+
+        let resp = send(None).await;
+        assert_eq!(resp.headers()["x-worker-affinity"], "miss");
+        assert_eq!(
+            resp.headers()[header::SET_COOKIE],
+            "affinity=test-worker; Max-Age=60; Path=/; HttpOnly"
+        );
+
+        let resp = send(Some("other=1; affinity=test-worker")).await;
+        assert_eq!(resp.headers()["x-worker-affinity"], "hit");
+
+        let resp = send(Some("affinity=another-worker")).await;
+        assert_eq!(resp.headers()["x-worker-affinity"], "miss");
+    }
+
+    #[tokio::test]
+    async fn parse_guard_times_out_slow_bodies() {
+        let parsed = parse_json_within::<TaskRequest>(
+            Bytes::from_static(br#"{"id":"a"}"#),
+            Duration::from_secs(5),
+        )
+        .await;
+        assert_eq!(parsed.unwrap().id, "a");
+        let invalid =
+            parse_json_within::<TaskRequest>(Bytes::from_static(b"{"), Duration::from_secs(5))
+                .await;
+        assert_eq!(invalid.unwrap_err(), ParseFailure::Invalid);
+
+        let huge = format!("[{}0]", "0,".repeat(500_000));
+        let parsed =
+            parse_json_within::<serde_json::Value>(Bytes::from(huge), Duration::from_nanos(1))
+                .await;
+        assert_eq!(parsed.unwrap_err(), ParseFailure::TimedOut);
+    }
+
+    #[tokio::test]
+    async fn recent_errors_record_task_ids() {
+        let mut config = test_config();
+        config.failure_rate = 1.0;
+        config.response_delay_ms = 0;
+        let state = test_state(config);
+
+        assert_eq!(
+            send_task(&state, "doomed").await,
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+        let resp = handle_recent_errors(State(Arc::clone(&state)), HeaderMap::new()).await;
+        let body = body_json(resp).await;
+        assert_eq!(body[0]["id"], "doomed");
+        assert_eq!(body[0]["status"], 500);
+        assert_eq!(body[0]["error"], "Simulated failure");
+    }
+
+    #[test]
+    fn health_score_weights_queue_and_load() {
+        let mut config = test_config();
+        assert_eq!(config.health_score(0.5, 0.8), 0.8);
+
+        config.health_queue_weight = 0.5;
+        assert_eq!(config.health_score(0.5, 0.8), 0.5);
+
+        config.health_score_mode = "sum".to_string();
+        assert_eq!(config.health_score(0.5, 0.8), 0.9);
+
+        let mut config = test_config();
+        merge_config_update(
+            &mut config,
+            &ConfigUpdate {
+                health_load_weight: Some(-1.0),
+                health_score_mode: Some("avg".to_string()),
+                ..Default::default()
+            },
+        );
+        assert_eq!(config.health_load_weight, 1.0);
+        assert_eq!(config.health_score_mode, "max");
+    }
+
+    #[tokio::test]
+    async fn replica_lag_is_reported_only_when_configured() {
+        let mut config = test_config();
+        config.response_delay_ms = 0;
+        let state = test_state(config);
+        let body = body_json(process_task(&state, TaskQuery::default(), task("a")).await).await;
+        assert!(body.get("replicaLagMs").is_none());
+
+        state.config.write().replica_lag_ms = 250;
+        let body = body_json(process_task(&state, TaskQuery::default(), task("b")).await).await;
+        assert_eq!(body["replicaLagMs"], 250);
+    }
+
+    #[test]
+    fn duration_millis_caps_instead_of_truncating() {
+        assert_eq!(duration_millis(Duration::from_millis(1500)), 1500);
+        assert_eq!(duration_millis(Duration::MAX), i64::MAX);
+    }
+
+    #[test]
+    fn per_id_metric_labels_are_capped() {
+        let state = test_state(test_config());
+        for i in 0..PER_ID_METRICS_LIMIT {
+            assert_eq!(state.metric_id_label(&format!("id-{i}")), format!("id-{i}"));
+        }
+        assert_eq!(state.metric_id_label("late"), "other");
+        assert_eq!(state.metric_id_label("id-0"), "id-0");
+    }
+
+    #[tokio::test]
+    async fn failure_can_take_precedence_over_rejection() {
+        let mut config = test_config();
+        config.failure_rate = 1.0;
+        config.queue_size = 0;
+        let state = test_state(config);
+        assert_eq!(
+            send_task(&state, "a").await,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+
+        state.config.write().failure_takes_precedence = true;
+        assert_eq!(
+            send_task(&state, "b").await,
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+
+    #[tokio::test]
+    async fn selftest_reports_processing_failures() {
+        let mut config = test_config();
+        config.response_delay_ms = 0;
+        let state = test_state(config);
+        let resp = handle_selftest(State(Arc::clone(&state)), HeaderMap::new()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(body_json(resp).await["ok"], true);
+
+        state.config.write().failure_rate = 1.0;
+        let resp = handle_selftest(State(Arc::clone(&state)), HeaderMap::new()).await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body = body_json(resp).await;
+        assert_eq!(body["status"], 500);
+        assert_eq!(body["error"], "Simulated failure");
+    }
+
+    #[tokio::test]
+    async fn task_counts_survive_a_checkpoint() {
+        let path = env::temp_dir().join(format!("worker-state-{}.json", std::process::id()));
+        let mut state = test_state(test_config());
+        Arc::get_mut(&mut state).unwrap().state_file = Some(path.clone());
+        state.restore_task_responses(PersistedState {
+            task_responses: BTreeMap::from([(200, 41)]),
+        });
+        state.count_task_response(StatusCode::OK);
+        state.count_task_response(StatusCode::SERVICE_UNAVAILABLE);
+        state.checkpoint();
+
+        let restored = PersistedState::load(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(
+            restored.task_responses,
+            BTreeMap::from([(200, 42), (503, 1)])
+        );
+        assert!(PersistedState::load(&path).task_responses.is_empty());
+    }
+
+    #[tokio::test]
+    async fn success_status_code_can_signal_accepted() {
+        let mut config = test_config();
+        config.response_delay_ms = 0;
+        config.success_status_code = 202;
+        let state = test_state(config);
+        let response = process_task(&state, TaskQuery::default(), task("job-1")).await;
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        assert!(response.headers().get(header::LOCATION).is_none());
+
+        state.config.write().accepted_location = true;
+        let response = process_task(&state, TaskQuery::default(), task("job-2")).await;
+        assert_eq!(response.headers()[header::LOCATION], "/task/job-2/status");
+
+        let mut config = test_config();
+        merge_config_update(
+            &mut config,
+            &ConfigUpdate {
+                success_status_code: Some(204),
+                ..Default::default()
+            },
+        );
+        assert_eq!(config.success_status_code, 200);
+    }
+
+    #[tokio::test]
+    async fn leaked_memory_is_capped_and_released_on_reset() {
+        let mut state = test_state(test_config());
+        state.leak_memory(1024);
+        assert_eq!(state.leaked_bytes.load(Ordering::SeqCst), 0);
+
+        Arc::get_mut(&mut state).unwrap().simulate_leak = true;
+        state.leak_memory(1024);
+        state.leak_memory(1024);
+        assert_eq!(state.leaked_bytes.load(Ordering::SeqCst), 2048);
+        state.leak_memory(LEAK_CAP_BYTES as i32);
+        assert_eq!(state.leaked_bytes.load(Ordering::SeqCst), 2048);
+
+        handle_reset(State(Arc::clone(&state)), HeaderMap::new()).await;
+        assert_eq!(state.leaked_bytes.load(Ordering::SeqCst), 0);
+        assert!(state.leaked.lock().is_empty());
+    }
+
+    #[tokio::test]
+    async fn admission_wait_beyond_accept_timeout_is_408() {
+        let mut config = test_config();
+        config.accept_timeout_ms = 50;
+        let state = test_state(config);
+        state.set_paused(true);
+
+        let start = Instant::now();
+        assert_eq!(
+            send_task(&state, "parked").await,
+            StatusCode::REQUEST_TIMEOUT
+        );
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert_eq!(snapshot(&state), (0, 0));
+    }
+
+    #[test]
+    fn metrics_can_be_filtered_by_prefix() {
+        let rendered = "# TYPE worker_requests_total counter\n\
+            worker_requests_total{status=\"success\"} 3\n\
+            \n\
+            # HELP worker_current_load load\n\
+            # TYPE worker_current_load gauge\n\
+            worker_current_load 1\n";
+        assert_eq!(
+            filter_metrics(rendered, "worker_requests"),
+            "# TYPE worker_requests_total counter\nworker_requests_total{status=\"success\"} 3\n"
+        );
+        assert_eq!(filter_metrics(rendered, "nope"), "");
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_queue_size_increases_keep_permits_in_sync() {
+        let state = test_state(test_config());
+        let updates: Vec<_> = (0..64)
+            .map(|i| {
+                let state = Arc::clone(&state);
+                tokio::spawn(async move {
+                    let update = ConfigUpdate {
+                        queue_size: Some(11 + (i * 37) % 64),
+                        ..Default::default()
+                    };
+                    handle_config_update(State(state), Json(update)).await;
+                })
+            })
+            .collect();
+        for update in updates {
+            update.await.unwrap();
+        }
+
+        let queue_size = state.config.read().queue_size;
+        assert_eq!(queue_size, 74);
+        assert_eq!(
+            state.queue_semaphore.available_permits(),
+            queue_size as usize
+        );
+    }
+
+    #[tokio::test]
+    async fn dependent_tasks_wait_for_their_dependency() {
+        let mut config = test_config();
+        config.response_delay_ms = 50;
+        config.dependency_timeout_ms = 1000;
+        let state = test_state(config);
+        let send = |id: &'static str, depends_on: Option<&'static str>| {
+            let state = Arc::clone(&state);
+            tokio::spawn(async move {
+                let task = TaskRequest {
+                    depends_on: depends_on.map(str::to_string),
+                    ..task(id)
+                };
+                let resp = handle_task(
+                    State(state),
+                    Query(TaskQuery::default()),
+                    HeaderMap::new(),
+                    Json(task),
+                )
+                .await;
+                (resp.status(), Instant::now())
+            })
+        };
+
+        let child = send("child", Some("parent"));
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let parent = send("parent", None);
+        let (parent_status, parent_done) = parent.await.unwrap();
+        let (child_status, child_done) = child.await.unwrap();
+        assert_eq!(parent_status, StatusCode::OK);
+        assert_eq!(child_status, StatusCode::OK);
+        assert!(child_done > parent_done);
+
+        state.config.write().dependency_timeout_ms = 30;
+        let (status, _) = send("orphan", Some("missing")).await.unwrap();
+        assert_eq!(status, StatusCode::FAILED_DEPENDENCY);
+    }
+
+    #[tokio::test]
+    async fn truncated_responses_are_invalid_json() {
+        let mut config = test_config();
+        config.response_delay_ms = 0;
+        config.truncate_response_rate = 1.0;
+        let mut state = test_state(config);
+        let response = process_task(&state, TaskQuery::default(), task("whole")).await;
+        body_json(response).await;
+
+        Arc::get_mut(&mut state).unwrap().chaos_transport = true;
+        let response = process_task(&state, TaskQuery::default(), task("clipped")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert!(!body.is_empty());
+        assert!(serde_json::from_slice::<serde_json::Value>(&body).is_err());
+    }
+
+    #[test]
+    fn diurnal_model_scales_delay_and_failure_rate() {
+        assert!(diurnal_wave(Duration::ZERO, 1000).abs() < 1e-9);
+        assert!((diurnal_wave(Duration::from_millis(250), 1000) - 1.0).abs() < 1e-9);
+        assert!((diurnal_wave(Duration::from_millis(1750), 1000) + 1.0).abs() < 1e-9);
+
+        let mut config = test_config();
+        config.failure_rate = 0.8;
+        config.apply_diurnal(1.0);
+        assert_eq!(config.response_delay_ms, 200);
+
+        config.diurnal_amplitude = 0.5;
+        config.apply_diurnal(1.0);
+        assert_eq!(config.response_delay_ms, 300);
+        assert_eq!(config.failure_rate, 1.0);
+        config.apply_diurnal(-1.0);
+        assert_eq!(config.response_delay_ms, 150);
+    }
+
+    #[test]
+    fn disabled_endpoints_are_validated() {
+        let (disabled, unknown) =
+            parse_disabled_endpoints("GET /metrics, put  /config,,POST /debug/force,GET /nope");
+        assert_eq!(
+            disabled,
+            ["GET /metrics", "PUT /config", "POST /debug/force"]
+        );
+        assert_eq!(unknown, ["GET /nope"]);
+
+        let mut state = test_state(test_config());
+        Arc::get_mut(&mut state).unwrap().disabled_endpoints = disabled;
+        let endpoints = state.endpoints();
+        assert!(!endpoints.contains(&"GET /metrics"));
+        assert!(endpoints.contains(&"PATCH /config"));
+    }
+
+    #[test]
+    fn endpoint_lists_match_the_registered_routes() {
+        let names = |debug| {
+            endpoint_routes(debug)
+                .into_iter()
+                .map(|(endpoint, _)| endpoint)
+                .collect::<Vec<_>>()
+        };
+        let listed: Vec<_> = std::iter::once("GET /")
+            .chain(ENDPOINTS.iter().copied())
+            .collect();
+        assert_eq!(names(false), listed);
+        assert_eq!(
+            names(true),
+            [listed, DEBUG_ENDPOINT_ROUTES.to_vec()].concat()
+        );
+    }
+
+    #[test]
+    fn report_separates_failure_latency() {
+        let state = test_state(test_config());
+        state.record_request_sample(StatusCode::OK, Duration::from_millis(5));
+        for ms in [100, 200, 300] {
+            state.record_request_sample(StatusCode::GATEWAY_TIMEOUT, Duration::from_millis(ms));
+        }
+        state.record_request_sample(StatusCode::SERVICE_UNAVAILABLE, Duration::from_millis(1));
+
+        let report = state.capacity_report();
+        assert_eq!(report.success_latency.p99_ms, Some(5.0));
+        assert_eq!(report.failure_latency.p50_ms, Some(200.0));
+        assert_eq!(report.failure_latency.p90_ms, Some(300.0));
+    }
+
+    #[tokio::test]
+    async fn queue_position_reflects_requests_ahead() {
+        let mut config = test_config();
+        config.response_delay_ms = 0;
+        config.include_queue_position = true;
+        let state = test_state(config);
+        state.set_paused(true);
+
+        let parked: Vec<_> = (0..2)
+            .map(|i| {
+                let state = Arc::clone(&state);
+                tokio::spawn(async move {
+                    let resp =
+                        process_task(&state, TaskQuery::default(), task(&format!("p{i}"))).await;
+                    body_json(resp).await["queuePosition"].clone()
+                })
+            })
+            .collect();
+        while snapshot(&state).1 < 2 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        let late = {
+            let state = Arc::clone(&state);
+            tokio::spawn(async move {
+                body_json(process_task(&state, TaskQuery::default(), task("late")).await).await
+                    ["queuePosition"]
+                    .clone()
+            })
+        };
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        state.set_paused(false);
+
+        let mut positions = Vec::new();
+        for handle in parked {
+            positions.push(handle.await.unwrap());
+        }
+        positions.sort_by_key(|p| p.as_i64());
+        assert_eq!(positions, [0, 1]);
+        assert_eq!(late.await.unwrap(), 2);
+    }
+
+    #[test]
+    fn parse_hardware_classes_skips_invalid_entries() {
+        let classes =
+            parse_hardware_classes("fast:3:0.5, slow:1:2, broken, zero:0:1, neg:1:-1, :1:1");
+        let parsed: Vec<_> = classes
+            .iter()
+            .map(|c| (c.color.as_str(), c.weight, c.latency_multiplier))
+            .collect();
+        assert_eq!(parsed, [("fast", 3.0, 0.5), ("slow", 1.0, 2.0)]);
+    }
+
+    #[tokio::test]
+    async fn hardware_class_sets_color_and_scales_delay() {
+        let mut config = test_config();
+        config.response_delay_ms = 100;
+        let mut state = test_state(config);
+        Arc::get_mut(&mut state).unwrap().hardware_classes = parse_hardware_classes("#111111:1:0");
+
+        let started = Instant::now();
+        let response = process_task(&state, TaskQuery::default(), task("hw")).await;
+        assert!(started.elapsed() < Duration::from_millis(100));
+        assert_eq!(body_json(response).await["color"], "#111111");
+    }
+
+    #[tokio::test]
+    async fn metrics_are_rendered_off_the_runtime() {
+        let state = test_state(test_config());
+        let response = handle_metrics(State(state), Query(MetricsQuery::default())).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "text/plain; charset=utf-8"
+        );
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert!(std::str::from_utf8(&body).is_ok());
+    }
+
+    #[tokio::test]
+    async fn single_flight_coalesces_concurrent_tasks() {
+        let mut config = test_config();
+        config.response_delay_ms = 100;
+        config.single_flight = true;
+        let state = test_state(config);
+
+        let requests: Vec<_> = (0..3)
+            .map(|_| {
+                let state = Arc::clone(&state);
+                tokio::spawn(async move {
+                    let response = handle_task(
+                        State(state),
+                        Query(TaskQuery::default()),
+                        HeaderMap::new(),
+                        Json(task("same")),
+                    )
+                    .await;
+                    body_json(response).await
+                })
+            })
+            .collect();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(snapshot(&state).0, 1);
+
+        let mut bodies = Vec::new();
+        for request in requests {
+            bodies.push(request.await.unwrap());
+        }
+        assert!(bodies.iter().all(|b| *b == bodies[0]));
+        assert!(state.in_flight_tasks.lock().is_empty());
+    }
+
+    mod app {
+        use super::*;
+        use tower::ServiceExt;
+
+        async fn send(
+            app: Router,
+            method: &str,
+            uri: &str,
+            body: Option<serde_json::Value>,
+        ) -> Response {
+            let request = Request::builder().method(method).uri(uri);
+            let request = match body {
+                Some(body) => request
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(body.to_string())),
+                None => request.body(Body::empty()),
+            };
+            app.oneshot(request.unwrap()).await.unwrap()
+        }
+
+        fn app() -> Router {
+            let mut config = test_config();
+            config.response_delay_ms = 0;
+            build_app(config, "app-worker", "#123456")
+        }
+
+        #[tokio::test]
+        async fn head_task_reports_capacity_without_processing() {
+            let state = test_state(test_config());
+            let app = build_router(Arc::clone(&state));
+            let response = send(app.clone(), "HEAD", "/task", None).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(response.headers()[QUEUE_AVAILABLE_HEADER], "10");
+            assert_eq!(response.headers()[CONCURRENCY_AVAILABLE_HEADER], "5");
+            assert_eq!(state.lifetime_requests.load(Ordering::SeqCst), 0);
+
+            state.set_draining(true);
+            let response = send(app, "HEAD", "/task", None).await;
+            assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        }
+
+        #[tokio::test]
+        async fn slow_bodies_are_rejected_before_the_handler() {
+            let mut state = test_state(test_config());
+            Arc::get_mut(&mut state).unwrap().max_parse = Some(Duration::from_nanos(1));
+            let app = build_router(Arc::clone(&state));
+
+            let response = send(
+                app.clone(),
+                "POST",
+                "/task",
+                Some(serde_json::json!({"id": "slow"})),
+            )
+            .await;
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+            let body = body_json(response).await;
+            assert!(
+                body["error"].as_str().unwrap().contains("to parse"),
+                "{body}"
+            );
+            assert_eq!(state.lifetime_requests.load(Ordering::SeqCst), 0);
+
+            // Malformed bodies keep the usual extractor rejection
+            let request = Request::builder()
+                .method("POST")
+                .uri("/config")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from("{"))
+                .unwrap();
+            let response = app.oneshot(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        }
+
+        #[tokio::test]
+        async fn task_is_served_through_the_router() {
+            let response = send(
+                app(),
+                "POST",
+                "/task",
+                Some(serde_json::json!({"id": "t1"})),
+            )
+            .await;
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = body_json(response).await;
+            assert_eq!(body["id"], "t1");
+            assert_eq!(body["worker"], "app-worker");
+        }
+
+        #[tokio::test]
+        async fn health_is_served_through_the_router() {
+            let response = send(app(), "GET", "/health", None).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(body_json(response).await["status"], "healthy");
+        }
+
+        #[tokio::test]
+        async fn wrong_method_gets_json_error_with_allow_header() {
+            let response = send(app(), "GET", "/task", None).await;
+            assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+            let allow = response.headers()[header::ALLOW]
+                .to_str()
+                .unwrap()
+                .to_string();
+            assert!(["POST", "HEAD"].iter().all(|m| allow.contains(m)));
+            assert_eq!(body_json(response).await["error"], "Method GET not allowed");
+
+            let response = send(app(), "DELETE", "/config", None).await;
+            let allow = response.headers()[header::ALLOW]
+                .to_str()
+                .unwrap()
+                .to_string();
+            assert!(["GET", "POST", "PUT", "PATCH"]
+                .iter()
+                .all(|m| allow.contains(m)));
+        }
+
+        #[cfg(unix)]
+        #[tokio::test]
+        async fn health_is_served_over_a_unix_socket() {
+            use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+            let path = env::temp_dir().join(format!("worker-uds-{}.sock", std::process::id()));
+            fs::write(&path, b"").unwrap();
+            assert!(
+                bind_unix_socket(&path).is_err(),
+                "regular files are not replaced"
+            );
+            fs::remove_file(&path).unwrap();
+
+            let listener = bind_unix_socket(&path).unwrap();
+            let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+            let server = tokio::spawn(serve_unix_socket(listener, path.clone(), app(), async {
+                let _ = stopped.await;
+            }));
+
+            let mut stream = tokio::net::UnixStream::connect(&path).await.unwrap();
+            stream
+                .write_all(b"GET /health HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+                .await
+                .unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+            assert!(response.contains("\"status\":\"healthy\""));
+
+            stop.send(()).unwrap();
+            server.await.unwrap();
+            assert!(!path.exists());
+        }
+
+        #[tokio::test]
+        async fn config_updates_through_the_router() {
+            let app = app();
+            let response = send(
+                app.clone(),
+                "PATCH",
+                "/config",
+                Some(serde_json::json!({"failure_rate": 0.5})),
+            )
+            .await;
+            assert_eq!(response.status(), StatusCode::OK);
+            let response = send(app, "GET", "/config", None).await;
+            assert_eq!(body_json(response).await["failure_rate"], 0.5);
+        }
+    }
+
+    #[test]
+    fn metric_prefix_replaces_worker_prefix() {
+        assert!(valid_metric_prefix("svc_a1"));
+        assert!(!valid_metric_prefix(""));
+        assert!(!valid_metric_prefix("1svc"));
+        assert!(!valid_metric_prefix("svc-a"));
+
+        let inner = PrometheusBuilder::new().build_recorder();
+        let handle = inner.handle();
+        let recorder = PrefixedRecorder::new("svc", inner);
+        metrics::with_local_recorder(&recorder, || {
+            counter!("worker_requests_total", "worker" => "w").increment(1);
+        });
+        let rendered = handle.render();
+        assert!(rendered.contains("svc_requests_total{worker=\"w\"} 1"));
+        assert!(!rendered.contains("worker_requests_total"));
+    }
+
+    #[test]
+    fn latency_table_is_validated_and_sampled() {
+        assert_eq!(
+            parse_latency_table("10:80, 100:15,1000:5"),
+            Some(vec![
+                LatencyEntry {
+                    delay_ms: 10,
+                    weight: 80.0
+                },
+                LatencyEntry {
+                    delay_ms: 100,
+                    weight: 15.0
+                },
+                LatencyEntry {
+                    delay_ms: 1000,
+                    weight: 5.0
+                },
+            ])
+        );
+        assert_eq!(parse_latency_table("10:80,slow"), None);
+        assert!(!valid_latency_table(
+            &parse_latency_table("10:0,20:0").unwrap()
+        ));
+        assert!(!valid_latency_table(&parse_latency_table("-1:1").unwrap()));
+
+        let mut config = test_config();
+        merge_config_update(
+            &mut config,
+            &ConfigUpdate {
+                latency_table: parse_latency_table("10:0,20:0"),
+                ..Default::default()
+            },
+        );
+        assert!(config.latency_table.is_empty());
+        config.latency_table = parse_latency_table("7:0,42:1").unwrap();
+        for _ in 0..20 {
+            let mut sampled = config.clone();
+            sampled.apply_latency_table();
+            assert_eq!(sampled.response_delay_ms, 42);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn health_stays_responsive_during_config_updates() {
+        let state = test_state(test_config());
+        let updates: Vec<_> = (0..4)
+            .map(|i| {
+                let state = Arc::clone(&state);
+                tokio::spawn(async move {
+                    for j in 0..200 {
+                        let update = ConfigUpdate {
+                            max_concurrent_requests: Some(5 + (i + j) % 3),
+                            queue_size: Some(10 + j),
+                            ..Default::default()
+                        };
+                        handle_config_update(State(Arc::clone(&state)), Json(update)).await;
+                    }
+                })
+            })
+            .collect();
+
+        let mut slowest = Duration::ZERO;
+        for _ in 0..200 {
+            let started = Instant::now();
+            let response = handle_health(State(Arc::clone(&state)))
+                .await
+                .into_response();
+            slowest = slowest.max(started.elapsed());
+            assert_ne!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+            tokio::task::yield_now().await;
+        }
+        for update in updates {
+            update.await.unwrap();
+        }
+        assert!(
+            slowest < Duration::from_millis(50),
+            "slowest /health took {slowest:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn task_steps_run_in_order_when_enabled() {
+        let mut config = test_config();
+        config.response_delay_ms = 500;
+        config.task_steps = true;
+        let state = test_state(config);
+
+        let mut request = task("recipe");
+        request.steps = Some(serde_json::json!([{"sleep": 20}, {"cpu": 10}, {"fail": 0.0}]));
+        let started = Instant::now();
+        let response = process_task(&state, TaskQuery::default(), request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(started.elapsed() >= Duration::from_millis(30));
+        assert!(started.elapsed() < Duration::from_millis(500));
+
+        let mut request = task("doomed");
+        request.steps = Some(serde_json::json!([{"fail": 1.0}, {"sleep": 500}]));
+        let started = Instant::now();
+        let response = process_task(&state, TaskQuery::default(), request).await;
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(started.elapsed() < Duration::from_millis(500));
+
+        let too_many = vec![serde_json::json!({"sleep": 0}); MAX_TASK_STEPS + 1];
+        for invalid in [
+            serde_json::json!([{"fail": 1.5}]),
+            serde_json::json!([{"nap": 5}]),
+            serde_json::json!([{"sleep": MAX_TASK_STEP_MS + 1}]),
+            serde_json::json!({"sleep": 5}),
+            serde_json::Value::Array(too_many),
+        ] {
+            let mut request = task("invalid");
+            request.steps = Some(invalid);
+            let response = process_task(&state, TaskQuery::default(), request).await;
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        }
+    }
+
+    #[tokio::test]
+    async fn malformed_steps_are_ignored_when_disabled() {
+        let mut config = test_config();
+        config.response_delay_ms = 0;
+        let state = test_state(config);
+
+        let mut request = task("plain");
+        request.steps = Some(serde_json::json!([{"nap": "soon"}]));
+        let response = process_task(&state, TaskQuery::default(), request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn abandoned_cpu_steps_stop_spinning() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .max_blocking_threads(1)
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let steps = [TaskStep::Cpu(MAX_TASK_STEP_MS)];
+            let abandoned =
+                tokio::time::timeout(Duration::from_millis(20), run_task_steps(&steps, None)).await;
+            assert!(abandoned.is_err());
+            // The only blocking thread is free again once the spin notices the cancellation
+            let freed =
+                tokio::time::timeout(Duration::from_secs(2), tokio::task::spawn_blocking(|| ()))
+                    .await;
+            assert!(freed.is_ok());
+        });
+    }
+
+    #[test]
+    fn rejections_record_queue_ratio() {
+        let state = test_state(test_config());
+        let _held = state.queue_semaphore.try_acquire_many(3).unwrap();
+        let recorder = PrometheusBuilder::new()
+            .set_buckets_for_metric(
+                Matcher::Full("worker_rejection_queue_ratio".to_string()),
+                &[0.25, 0.5],
+            )
+            .unwrap()
+            .build_recorder();
+        let handle = recorder.handle();
+        metrics::with_local_recorder(&recorder, || {
+            state.rejection_response(Rejection::Overloaded("busy".to_string()), "1.0.0");
+        });
+        let rendered = handle.render();
+        assert!(rendered.contains(
+            "worker_rejection_queue_ratio_bucket{worker=\"test-worker\",reason=\"overloaded\",le=\"0.25\"} 0"
+        ));
+        assert!(rendered.contains(
+            "worker_rejection_queue_ratio_bucket{worker=\"test-worker\",reason=\"overloaded\",le=\"0.5\"} 1"
+        ));
+    }
+
+    #[tokio::test]
+    async fn processing_timeout_caps_the_simulated_work() {
+        let mut config = test_config();
+        config.response_delay_ms = 100;
+        config.processing_timeout_ms = 150;
+        let state = test_state(config);
+
+        assert_eq!(send_task(&state, "light").await, StatusCode::OK);
+
+        let mut heavy = task("heavy");
+        heavy.weight = Some(5.0);
+        let started = Instant::now();
+        let response = process_task(&state, TaskQuery::default(), heavy).await;
+        assert!(started.elapsed() < Duration::from_millis(400));
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body_json(response).await["error"], "Processing timeout");
+        assert_eq!(snapshot(&state), (0, 0));
+    }
+
+    #[tokio::test]
+    async fn health_history_records_transitions() {
+        let state = test_state(test_config());
+        handle_health(State(Arc::clone(&state))).await;
+        handle_health(State(Arc::clone(&state))).await;
+        let held = state.concurrency_semaphore.try_acquire_many(4).unwrap();
+        handle_health(State(Arc::clone(&state))).await;
+        drop(held);
+        handle_health(State(Arc::clone(&state))).await;
+
+        let resp = handle_health_history(State(Arc::clone(&state)), HeaderMap::new()).await;
+        let statuses: Vec<_> = body_json(resp)
+            .await
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|t| t["status"].as_str().unwrap().to_string())
+            .collect();
+        assert_eq!(statuses, ["healthy", "degraded", "healthy"]);
+    }
+
+    #[tokio::test]
+    async fn tenant_quotas_limit_each_tenant() {
+        let mut config = test_config();
+        config.response_delay_ms = 100;
+        let mut state = test_state(config);
+        Arc::get_mut(&mut state).unwrap().tenant_quotas = Some(TenantQuotas {
+            default_quota: 2,
+            quotas: parse_tenant_quotas("acme:1, broken, zero:0"),
+            semaphores: Mutex::new(HashMap::new()),
+        });
+        let send = |tenant: &'static str, id: &'static str| {
+            let state = Arc::clone(&state);
+            tokio::spawn(async move {
+                let mut headers = HeaderMap::new();
+                headers.insert(TENANT_HEADER, HeaderValue::from_static(tenant));
+                handle_task(
+                    State(state),
+                    Query(TaskQuery::default()),
+                    headers,
+                    Json(task(id)),
+                )
+                .await
+                .status()
+            })
+        };
+
+        let first = send("acme", "a1");
+        let others = [send("globex", "g1"), send("globex", "g2")];
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        assert_eq!(
+            send("acme", "a2").await.unwrap(),
+            StatusCode::TOO_MANY_REQUESTS
+        );
+        assert_eq!(
+            send("globex", "g3").await.unwrap(),
+            StatusCode::TOO_MANY_REQUESTS
+        );
+        // Unlisted tenants share one bucket, so made-up names cannot mint new ones
+        assert_eq!(
+            send("initech", "i1").await.unwrap(),
+            StatusCode::TOO_MANY_REQUESTS
+        );
+        let mut buckets: Vec<_> = state
+            .tenant_quotas
+            .as_ref()
+            .unwrap()
+            .semaphores
+            .lock()
+            .keys()
+            .cloned()
+            .collect();
+        buckets.sort();
+        assert_eq!(buckets, ["acme", "other"]);
+
+        assert_eq!(first.await.unwrap(), StatusCode::OK);
+        for other in others {
+            assert_eq!(other.await.unwrap(), StatusCode::OK);
+        }
+        assert_eq!(send("acme", "a3").await.unwrap(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn streamed_responses_end_with_trailers() {
+        use http_body_util::BodyExt;
+
+        let mut config = test_config();
+        config.response_delay_ms = 0;
+        config.trickle_bytes_per_sec = 100_000;
+        config.stream_trailers = true;
+        let state = test_state(config);
+
+        let response = process_task(&state, TaskQuery::default(), task("trailed")).await;
+        assert_eq!(
+            response.headers()[header::TRAILER],
+            "x-processing-time-ms, x-status"
+        );
+        let collected = response.into_body().collect().await.unwrap();
+        let trailers = collected.trailers().cloned().unwrap();
+        assert_eq!(trailers[STATUS_TRAILER], "200");
+        assert!(trailers[PROCESSING_TIME_TRAILER]
+            .to_str()
+            .unwrap()
+            .parse::<i64>()
+            .is_ok());
+        let body: serde_json::Value = serde_json::from_slice(&collected.to_bytes()).unwrap();
+        assert_eq!(body["id"], "trailed");
+    }
+
+    #[tokio::test]
+    async fn blank_task_ids_are_rejected_or_generated() {
+        let state = test_state(test_config());
+        assert_eq!(send_task(&state, "  ").await, StatusCode::BAD_REQUEST);
+        assert_eq!(snapshot(&state), (0, 0));
+
+        state.config.write().auto_id = true;
+        state.config.write().response_delay_ms = 0;
+        let response = handle_task(
+            State(Arc::clone(&state)),
+            Query(TaskQuery::default()),
+            HeaderMap::new(),
+            Json(task("")),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let id = body_json(response).await["id"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        let uuid =
+            Regex::new("^[0-9a-f]{8}-[0-9a-f]{4}-4[0-9a-f]{3}-[89ab][0-9a-f]{3}-[0-9a-f]{12}$")
+                .unwrap();
+        assert!(uuid.is_match(&id), "{id}");
+    }
+
+    #[test]
+    fn pushgateway_push_url_escapes_job() {
+        let url = pushgateway_push_url("http://gw:9091/prefix/", "a b/c").unwrap();
+        assert_eq!(url.as_str(), "http://gw:9091/prefix/metrics/job/a%20b%2Fc");
+        assert!(pushgateway_push_url("not a url", "worker").is_none());
+    }
+
+    #[tokio::test]
+    async fn push_metrics_puts_rendered_metrics() {
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel::<(Method, String, String)>();
+        let receiver = Router::new().fallback(
+            move |method: Method, uri: axum::http::Uri, body: String| async move {
+                tx.send((method, uri.path().to_string(), body)).unwrap();
+                StatusCode::OK
+            },
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, receiver).await.unwrap() });
+
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let mut state = test_state(test_config());
+        let shared = Arc::get_mut(&mut state).unwrap();
+        shared.prometheus_handle = recorder.handle();
+        shared.pushgateway_url = pushgateway_push_url(&format!("http://{addr}"), "test-worker");
+        metrics::with_local_recorder(&recorder, || {
+            counter!("worker_requests_total", "status" => "success").increment(1);
+        });
+        state.push_metrics().await;
+
+        let (method, path, body) = tokio::time::timeout(Duration::from_secs(2), rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(method, Method::PUT);
+        assert_eq!(path, "/metrics/job/test-worker");
+        assert!(body.contains("worker_requests_total"));
+    }
+
+    #[test]
+    fn shadow_results_stay_out_of_primary_metrics() {
+        let mut config = test_config();
+        config.response_delay_ms = 0;
+        config.shadow_enabled = true;
+        config.shadow_response_delay_ms = 0;
+        let state = test_state(config);
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let handle = recorder.handle();
+        // Spawned shadow tasks only see the local recorder when they run on this thread
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        metrics::with_local_recorder(&recorder, || {
+            runtime.block_on(async {
+                assert_eq!(send_task(&state, "shadowed").await, StatusCode::OK);
+                sleep(Duration::from_millis(20)).await;
+            })
+        });
+        let rendered = handle.render();
+        let successes: Vec<_> = rendered
+            .lines()
+            .filter(|line| {
+                line.starts_with("worker_requests_total{") && line.contains("status=\"success\"")
+            })
+            .collect();
+        assert_eq!(successes.len(), 1, "{rendered}");
+        assert!(successes[0].ends_with(" 1"));
+        assert!(rendered
+            .contains("worker_shadow_requests_total{worker=\"test-worker\",status=\"success\"} 1"));
+    }
+
+    #[tokio::test]
+    async fn health_flap_requires_chaos_health() {
+        let mut config = test_config();
+        config.health_flap_rate = 1.0;
+        let mut state = test_state(config);
+
+        let health = handle_health(State(Arc::clone(&state)))
+            .await
+            .into_response();
+        assert_eq!(body_json(health).await["status"], "healthy");
+
+        Arc::get_mut(&mut state).unwrap().chaos_health = true;
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let handle = recorder.handle();
+        let health = metrics::with_local_recorder(&recorder, || {
+            futures_util::FutureExt::now_or_never(handle_health(State(Arc::clone(&state))))
+                .unwrap()
+                .into_response()
+        });
+        assert_eq!(body_json(health).await["status"], "degraded");
+        assert!(handle.render().contains(
+            "worker_health_flaps_injected_total{worker=\"test-worker\",actual=\"healthy\",reported=\"degraded\"} 1"
+        ));
+        // The injected status is noise and is not recorded as a transition
+        assert_eq!(state.health_history.lock().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn lifetime_limit_triggers_shutdown() {
+        let mut state = test_state(test_config());
+        Arc::get_mut(&mut state).unwrap().max_lifetime_requests = Some(2);
+
+        send_task(&state, "task-1").await;
+        let early = tokio::time::timeout(
+            Duration::from_millis(50),
+            state.lifetime_exhausted.notified(),
+        );
+        assert!(early.await.is_err());
+
+        send_task(&state, "task-2").await;
+        let reached =
+            tokio::time::timeout(Duration::from_secs(1), state.lifetime_exhausted.notified());
+        assert!(reached.await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn lifetime_limit_counts_batch_elements_but_not_warmup() {
+        let mut config = test_config();
+        config.response_delay_ms = 0;
+        let mut state = test_state(config);
+        Arc::get_mut(&mut state).unwrap().max_lifetime_requests = Some(3);
+        Arc::get_mut(&mut state).unwrap().warmup_task_id = Some("warmup".to_string());
+
+        let batch = r#"[{"id": "a"}, {"id": "warmup"}, {"id": "b"}]"#;
+        let response = handle_task_batch(
+            State(Arc::clone(&state)),
+            HeaderMap::new(),
+            Body::from(batch),
+        )
+        .await;
+        assert_eq!(body_json(response).await["statuses"]["200"], 3);
+        assert_eq!(send_task(&state, "warmup").await, StatusCode::OK);
+        assert_eq!(state.lifetime_requests.load(Ordering::SeqCst), 2);
+        let early = tokio::time::timeout(
+            Duration::from_millis(50),
+            state.lifetime_exhausted.notified(),
+        );
+        assert!(early.await.is_err());
+
+        send_task(&state, "c").await;
+        let reached =
+            tokio::time::timeout(Duration::from_secs(1), state.lifetime_exhausted.notified());
+        assert!(reached.await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn traced_task_returns_the_same_response() {
+        let mut config = test_config();
+        config.trace_task_ids = vec!["traced".to_string()];
+        let state = test_state(config);
+
+        let response = handle_task(
+            State(Arc::clone(&state)),
+            Query(TaskQuery::default()),
+            HeaderMap::new(),
+            Json(task("traced")),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = body_json(response).await;
+        assert_eq!(body["id"], "traced");
+        assert_eq!(body["worker"], "test-worker");
+    }
+
+    #[tokio::test]
+    async fn traced_task_keeps_trailers() {
+        use http_body_util::BodyExt;
+
+        let mut config = test_config();
+        config.response_delay_ms = 0;
+        config.trickle_bytes_per_sec = 100_000;
+        config.stream_trailers = true;
+        config.trace_task_ids = vec!["traced".to_string()];
+        let state = test_state(config);
+
+        let response = handle_task(
+            State(Arc::clone(&state)),
+            Query(TaskQuery::default()),
+            HeaderMap::new(),
+            Json(task("traced")),
+        )
+        .await;
+        let collected = response.into_body().collect().await.unwrap();
+        assert_eq!(collected.trailers().unwrap()[STATUS_TRAILER], "200");
+        let body: serde_json::Value = serde_json::from_slice(&collected.to_bytes()).unwrap();
+        assert_eq!(body["id"], "traced");
+    }
+
+    #[test]
+    fn pad_response_body_applies_floor_and_alignment() {
+        let body = br#"{"id":"task-1"}"#.to_vec();
+        assert_eq!(pad_response_body(body.clone(), 0, 0), body);
+        assert_eq!(pad_response_body(body.clone(), 10, 0), body);
+
+        let floored = pad_response_body(body.clone(), 100, 0);
+        assert_eq!(floored.len(), 100);
+        let parsed: serde_json::Value = serde_json::from_slice(&floored).unwrap();
+        assert_eq!(parsed["id"], "task-1");
+
+        assert_eq!(pad_response_body(body.clone(), 100, 64).len(), 128);
+        // A single spare byte cannot hold the padding field, so the next boundary is used
+        assert_eq!(pad_response_body(body.clone(), 16, 8).len(), 32);
+        assert_eq!(pad_response_body(body.clone(), 16, 0).len(), 28);
+    }
+
+    #[tokio::test]
+    async fn config_read_delay_requires_debug_endpoints() {
+        let mut config = test_config();
+        config.config_read_delay_ms = 200;
+        let mut state = test_state(config);
+
+        let start = Instant::now();
+        handle_config_get(State(Arc::clone(&state))).await;
+        assert!(start.elapsed() >= Duration::from_millis(200));
+
+        Arc::get_mut(&mut state).unwrap().debug_endpoints = false;
+        let start = Instant::now();
+        handle_config_get(State(state)).await;
+        assert!(start.elapsed() < Duration::from_millis(200));
+    }
+
+    #[tokio::test]
+    async fn downstream_targets_are_picked_by_weight() {
+        assert_eq!(
+            parse_downstream_targets("http://a/x?k=v=3, http://b=1"),
+            Some(vec![
+                DownstreamTarget {
+                    url: "http://a/x?k=v".to_string(),
+                    weight: 3.0
+                },
+                DownstreamTarget {
+                    url: "http://b".to_string(),
+                    weight: 1.0
+                },
+            ])
+        );
+        assert_eq!(parse_downstream_targets("http://a"), None);
+        assert!(!valid_downstream_targets(
+            &parse_downstream_targets("http://a=0").unwrap()
+        ));
+        assert!(!valid_downstream_targets(
+            &parse_downstream_targets("=1").unwrap()
+        ));
+
+        let base = spawn_downstream().await;
+        let mut config = test_config();
+        config.downstream_url = format!("{}/missing", base);
+        config.downstream_targets =
+            parse_downstream_targets(&format!("{base}/ok=1,{base}/missing=0")).unwrap();
+        let state = test_state(config);
+        for id in ["a", "b", "c"] {
+            assert_eq!(send_task(&state, id).await, StatusCode::OK);
+        }
+
+        state.config.write().downstream_targets.clear();
+        assert_eq!(send_task(&state, "d").await, StatusCode::BAD_GATEWAY);
+    }
+
+    /// 呼び出しの順に成功と失敗を交互に返す下流サーバを起動し、その URL を返す。
+    async fn spawn_alternating_downstream() -> String {
+        let hits = Arc::new(AtomicU64::new(0));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = Router::new().route(
+            "/flaky",
+            post(move || {
+                let n = hits.fetch_add(1, Ordering::SeqCst);
+                async move {
+                    if n.is_multiple_of(2) {
+                        StatusCode::OK
+                    } else {
+                        StatusCode::INTERNAL_SERVER_ERROR
+                    }
+                }
+            }),
+        );
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        format!("http://{}/flaky", addr)
+    }
+
+    #[tokio::test]
+    async fn downstream_fanout_waits_for_quorum() {
+        let mut config = test_config();
+        config.downstream_fanout = 4;
+        config.fanout_quorum = 2;
+        config.downstream_url = spawn_alternating_downstream().await;
+        let state = test_state(config);
+        assert_eq!(send_task(&state, "half").await, StatusCode::OK);
+
+        let mut config = test_config();
+        config.downstream_fanout = 4;
+        config.downstream_url = spawn_alternating_downstream().await;
+        let state = test_state(config);
+        assert_eq!(send_task(&state, "all").await, StatusCode::BAD_GATEWAY);
+    }
+
+    #[tokio::test]
+    async fn events_record_task_timeline() {
+        let mut config = test_config();
+        config.response_delay_ms = 0;
+        config.queue_size = 1;
+        let mut state = test_state(config);
+        let events = |state: &Arc<AppState>, since: Option<String>| {
+            let state = Arc::clone(state);
+            async move {
+                let query = EventsQuery {
+                    since,
+                    ..EventsQuery::default()
+                };
+                let response = handle_events(State(state), HeaderMap::new(), Query(query)).await;
+                (response.status(), body_json(response).await)
+            }
+        };
+        assert_eq!(events(&state, None).await.0, StatusCode::NOT_FOUND);
+
+        Arc::get_mut(&mut state).unwrap().task_events = Some(Mutex::new(VecDeque::new()));
+        send_task(&state, "first").await;
+        let (status, body) = events(&state, None).await;
+        assert_eq!(status, StatusCode::OK);
+        let kinds: Vec<_> = body
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|e| e["event"].clone())
+            .collect();
+        assert_eq!(kinds, ["admitted", "completed"]);
+        assert_eq!(body[1]["status"], 200);
+
+        let last = body[1]["timestamp"].as_str().unwrap().to_string();
+        let held = state.queue_semaphore.try_acquire().unwrap();
+        send_task(&state, "second").await;
+        drop(held);
+        let (_, body) = events(&state, Some(last)).await;
+        assert_eq!(body.as_array().unwrap().len(), 2);
+        assert_eq!(body[0]["event"], "rejected");
+        assert_eq!(body[0]["id"], "second");
+        assert_eq!(body[0]["reason"], "rejected");
+
+        let (status, _) = events(&state, Some("yesterday".to_string())).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn events_cover_early_rejections_by_seq() {
+        let mut config = test_config();
+        config.response_delay_ms = 0;
+        let mut state = test_state(config);
+        Arc::get_mut(&mut state).unwrap().task_events = Some(Mutex::new(VecDeque::new()));
+        let events_after = |since_seq: u64| {
+            let state = Arc::clone(&state);
+            async move {
+                let query = EventsQuery {
+                    since_seq: Some(since_seq),
+                    ..EventsQuery::default()
+                };
+                body_json(handle_events(State(state), HeaderMap::new(), Query(query)).await).await
+            }
+        };
+
+        send_task(&state, "ok").await;
+        let body = events_after(0).await;
+        assert_eq!(body[0]["seq"], 1);
+        assert_eq!(body[1]["seq"], 2);
+        assert_eq!(events_after(2).await.as_array().unwrap().len(), 0);
+
+        state.set_draining(true);
+        assert_eq!(
+            send_task(&state, "drained").await,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        let body = events_after(2).await;
+        assert_eq!(body.as_array().unwrap().len(), 2);
+        assert_eq!(body[0]["event"], "rejected");
+        assert_eq!(body[0]["id"], "drained");
+        assert_eq!(body[0]["reason"], "draining");
+        assert_eq!(body[1]["event"], "completed");
+        assert_eq!(body[1]["status"], 503);
+    }
+
+    #[tokio::test]
+    async fn health_cache_serves_snapshot_until_draining() {
+        let mut config = test_config();
+        config.health_cache_ms = 60_000;
+        let state = test_state(config);
+        assert_eq!(state.refresh_health_cache(), Duration::from_secs(60));
+
+        let _busy = state.concurrency_semaphore.try_acquire_many(5).unwrap();
+        let health = handle_health(State(Arc::clone(&state)))
+            .await
+            .into_response();
+        let body = body_json(health).await;
+        assert_eq!(body["status"], "healthy");
+        assert_eq!(body["currentLoad"], 0);
+
+        state.set_draining(true);
+        let health = handle_health(State(Arc::clone(&state)))
+            .await
+            .into_response();
+        assert_eq!(health.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        state.config.write().health_cache_ms = 0;
+        assert_eq!(state.refresh_health_cache(), HEALTH_CACHE_IDLE_INTERVAL);
+        assert!(state.cached_health().is_none());
+    }
+
+    #[tokio::test]
+    async fn schema_drift_requires_chaos_transport() {
+        let mut config = test_config();
+        config.response_delay_ms = 0;
+        config.schema_drift_rate = 1.0;
+        let mut state = test_state(config);
+
+        let response = process_task(&state, TaskQuery::default(), task("drift")).await;
+        assert_eq!(body_json(response).await["id"], "drift");
+
+        Arc::get_mut(&mut state).unwrap().chaos_transport = true;
+        let response = process_task(&state, TaskQuery::default(), task("drift")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = body_json(response).await;
+        assert_eq!(body["taskId"], "drift");
+        assert_eq!(body["schemaVersion"], DRIFTED_SCHEMA_VERSION);
+        assert!(body.get("id").is_none());
+        assert!(body.get("timestamp").is_none());
+    }
+
+    #[tokio::test]
+    async fn drain_and_shutdown_release_paused_tasks() {
+        let state = test_state(test_config());
+        state.set_paused(true);
+
+        let parked = tokio::spawn({
+            let state = Arc::clone(&state);
+            async move { send_task(&state, "parked-drain").await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!parked.is_finished());
+        state.set_draining(true);
+        let status = tokio::time::timeout(Duration::from_secs(2), parked)
+            .await
+            .expect("drain must not hang on a paused task")
+            .unwrap();
+        assert_eq!(status, StatusCode::OK);
+
+        state.set_draining(false);
+        let parked = tokio::spawn({
+            let state = Arc::clone(&state);
+            async move { send_task(&state, "parked-shutdown").await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!parked.is_finished());
+        state.begin_shutdown();
+        let status = tokio::time::timeout(Duration::from_secs(2), parked)
+            .await
+            .expect("shutdown must not hang on a paused task")
+            .unwrap();
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn panicking_leader_releases_its_in_flight_entry() {
+        let state = test_state(test_config());
+        let flight: InFlightTask = std::future::pending().boxed().shared();
+        state
+            .in_flight_tasks
+            .lock()
+            .insert("doomed".to_string(), flight);
+
+        let entry = InFlightEntry {
+            state: Arc::clone(&state),
+            id: "doomed".to_string(),
+        };
+        let leader = tokio::spawn(async move {
+            let _entry = entry;
+            panic!("leader failed");
+        });
+        assert!(leader.await.unwrap_err().is_panic());
+        assert!(state.in_flight_tasks.lock().is_empty());
+    }
+
+    #[tokio::test]
+    async fn leaky_bucket_survives_tiny_rates_and_cancellation() {
+        let mut config = test_config();
+        config.leak_rate_rps = 1e-320;
+        let state = test_state(config.clone());
+
+        let first = state.enter_leak_buffer(&config);
+        let second = state.enter_leak_buffer(&config);
+        assert_eq!(second.slot - first.slot, MAX_LEAK_INTERVAL);
+        drop((first, second));
+
+        // A client that disconnects while buffered must not leave the depth behind
+        let cancelled =
+            tokio::time::timeout(Duration::from_millis(50), send_task(&state, "gone")).await;
+        assert!(cancelled.is_err());
+        assert_eq!(state.leak_buffered.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn leaky_bucket_gives_back_slots_of_timed_out_waiters() {
+        let mut config = test_config();
+        config.response_delay_ms = 0;
+        config.leak_rate_rps = 5.0;
+        config.accept_timeout_ms = 50;
+        let state = test_state(config);
+
+        let start = Instant::now();
+        assert_eq!(send_task(&state, "first").await, StatusCode::OK);
+        // The second slot is 200ms away, so this waiter gives up after 50ms
+        assert_ne!(send_task(&state, "gone").await, StatusCode::OK);
+        state.config.write().accept_timeout_ms = 0;
+        // The next task takes the abandoned slot instead of queueing behind it
+        assert_eq!(send_task(&state, "next").await, StatusCode::OK);
+        assert!(start.elapsed() < Duration::from_millis(350));
+        assert_eq!(state.leak_buffered.load(Ordering::SeqCst), 0);
+    }
+}